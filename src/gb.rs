@@ -1,7 +1,36 @@
+mod apu;
+mod boot;
+mod cart;
+mod joypad;
+mod link;
 mod mem;
+mod movie;
 mod ppu;
-
+mod printer;
+mod rewind;
+mod serial;
+mod sgb;
+mod state;
+#[cfg(test)]
+mod testing;
+
+use crate::gb::boot::CGB_COMPAT_REGISTERS;
 use crate::gb::mem::Memory;
+use crate::gb::ppu::{palettes_for_buttons, palettes_for_header};
+use crate::gb::state::{StateReader, StateWriter};
+
+pub use crate::gb::apu::{DEFAULT_SAMPLE_RATE, MAX_SAMPLE_RATE};
+pub use crate::gb::boot::{BootRomError, Model};
+pub use crate::gb::cart::header::{CartridgeHeader, HeaderError};
+pub use crate::gb::joypad::{Button, ButtonState, MAX_PLAYERS};
+pub use crate::gb::link::{cable, LocalLink, LocalPeer, NetworkLink};
+pub use crate::gb::printer::Printer;
+pub use crate::gb::movie::{Movie, MovieError, MovieStart};
+pub use crate::gb::ppu::{RenderMode, SCREEN_HEIGHT, SCREEN_WIDTH};
+pub use crate::gb::rewind::{Rewind, RewindStats, DEFAULT_REWIND_BYTES};
+pub use crate::gb::serial::{Disconnected, SerialDevice, StdoutCapture};
+pub use crate::gb::sgb::{SGB_SCREEN_HEIGHT, SGB_SCREEN_WIDTH};
+pub use crate::gb::state::{StateError, STATE_VERSION};
 
 // Offsets for shifting to the corresponding bits
 const Z_FLAG: u8 = 7;
@@ -13,6 +42,9 @@ const C_FLAG: u8 = 4;
 const IF_ADDR: u16 = 0xFF0F;
 const IE_ADDR: u16 = 0xFFFF;
 
+// M-cycles the CPU is paused for after STOP switches speed
+const SPEED_SWITCH_CYCLES: u16 = 2050;
+
 pub struct Gameboy {
     pc: u16,
     sp: u16,
@@ -29,27 +61,77 @@ pub struct Gameboy {
     ime_delay: bool,
     halt: bool,
     halt_bug: bool,
+    // STOP mode, left when a selected joypad line goes low
+    stopped: bool,
+    // M-cycles the CPU stays paused for while a CGB speed switch settles
+    speed_switch_cycles: u16,
+    model: Model,
+}
+
+impl Default for Gameboy {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Gameboy {
     pub fn new() -> Self {
+        Self::with_model(Model::Dmg)
+    }
+
+    // Starts at 0x0100 with the registers the model's boot ROM leaves behind
+    pub fn with_model(model: Model) -> Self {
+        let [a, f, b, c, d, e, h, l] = model.post_boot_registers();
         Self {
             pc: 0x100,
             sp: 0xFFFE,
-            a_reg: 1,
-            b_reg: 0,
-            c_reg: 0x13,
-            d_reg: 0,
-            e_reg: 0xD8,
-            f_reg: 0b1011_0000,
-            h_reg: 1,
-            l_reg: 0x4D,
-            mem: Memory::new(),
+            a_reg: a,
+            b_reg: b,
+            c_reg: c,
+            d_reg: d,
+            e_reg: e,
+            f_reg: f,
+            h_reg: h,
+            l_reg: l,
+            mem: Memory::new(model),
             ime: false,
             ime_delay: false,
             halt: false,
             halt_bug: false,
+            stopped: false,
+            speed_switch_cycles: 0,
+            model,
+        }
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    // Whether a CGB game has switched the CPU to double speed
+    pub fn double_speed(&self) -> bool {
+        self.mem.double_speed()
+    }
+
+    // Runs the real boot sequence from a dump of the boot ROM instead of skipping it.
+    // The CPU and hardware start from their power-on state, so call this before running any frames
+    pub fn load_boot_rom(&mut self, data: &[u8]) -> Result<(), BootRomError> {
+        let expected = self.model.boot_rom_size();
+        if data.len() != expected {
+            return Err(BootRomError::InvalidSize { size: data.len(), expected });
         }
+        self.pc = 0;
+        self.sp = 0;
+        self.a_reg = 0;
+        self.b_reg = 0;
+        self.c_reg = 0;
+        self.d_reg = 0;
+        self.e_reg = 0;
+        self.f_reg = 0;
+        self.h_reg = 0;
+        self.l_reg = 0;
+        self.mem.map_boot_rom(data);
+        Ok(())
     }
 
     fn get_af(&self) -> u16 {
@@ -99,15 +181,211 @@ impl Gameboy {
         }
     }
 
-    pub fn load_rom(&mut self, data: &[u8]) {
-        self.mem.load_rom(data);
+    // Validates the cartridge header and inserts the cartridge.
+    // A CGB runs cartridges that declare CGB support in CGB mode and everything else in DMG compatibility mode
+    pub fn load_rom(&mut self, data: &[u8]) -> Result<(), HeaderError> {
+        self.mem.load_rom(data)?;
+        let supports_cgb = self.cartridge_header().is_some_and(|header| header.supports_cgb());
+        let cgb_mode = self.model == Model::Cgb && supports_cgb;
+        self.mem.set_cgb_mode(cgb_mode);
+        if self.model == Model::Cgb && !cgb_mode {
+            if !self.mem.boot_rom_mapped() {
+                [self.d_reg, self.e_reg, self.h_reg, self.l_reg] = CGB_COMPAT_REGISTERS;
+            }
+            if let Some(palettes) = self.cartridge_header().map(palettes_for_header) {
+                self.mem.ppu_mut().set_compat_palettes(palettes);
+            }
+        }
+        Ok(())
+    }
+
+    // Overrides the palettes of a DMG game on a CGB like holding a direction, optionally with A or B, during boot does.
+    // Returns false if the buttons don't form one of the 12 combinations or the game isn't running in compatibility mode
+    pub fn select_compat_palette(&mut self, held: ButtonState) -> bool {
+        if self.model != Model::Cgb || self.mem.cgb_mode() || self.cartridge_header().is_none() {
+            return false;
+        }
+        match palettes_for_buttons(held) {
+            Some(palettes) => {
+                self.mem.ppu_mut().set_compat_palettes(palettes);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Header of the inserted cartridge, None if no ROM has been loaded
+    pub fn cartridge_header(&self) -> Option<&CartridgeHeader> {
+        self.mem.cart().header()
+    }
+
+    pub fn set_render_mode(&mut self, mode: RenderMode) {
+        self.mem.ppu_mut().set_render_mode(mode);
+    }
+
+    // Makes rgb_frame mimic the colours of the CGB's LCD instead of showing the raw RGB555 values
+    pub fn set_color_correction(&mut self, enabled: bool) {
+        self.mem.ppu_mut().set_color_correction(enabled);
+    }
+
+    // Drives the MBC3 clock from the host's wall-clock instead of emulated time
+    pub fn set_rtc_host_sync(&mut self, enabled: bool) {
+        self.mem.cart_mut().set_rtc_host_sync(enabled);
+    }
+
+    // Battery backed cartridge state: external RAM plus the RTC footer when present
+    pub fn save_data(&mut self) -> Vec<u8> {
+        self.mem.cart_mut().save_data()
+    }
+
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        self.mem.cart_mut().load_save_data(data);
+    }
+
+    // Whether the cartridge header declares a battery, i.e. save data should be persisted
+    pub fn has_battery(&self) -> bool {
+        self.mem.cart().has_battery()
+    }
+
+    // Snapshots the whole machine. Host settings (render mode, sample rate, serial device, RTC host sync) are not included
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new(self.cartridge_header());
+        w.u16(self.pc);
+        w.u16(self.sp);
+        for register in [self.a_reg, self.b_reg, self.c_reg, self.d_reg, self.e_reg, self.f_reg, self.h_reg, self.l_reg] {
+            w.u8(register);
+        }
+        w.bool(self.ime);
+        w.bool(self.ime_delay);
+        w.bool(self.halt);
+        w.bool(self.halt_bug);
+        w.bool(self.stopped);
+        w.u16(self.speed_switch_cycles);
+        self.mem.save_state(&mut w);
+        w.finish()
+    }
+
+    // Restores a snapshot taken with the same ROM. On error the machine is left as it was
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let backup = self.save_state();
+        let result = self.read_state(data);
+        if result.is_err() {
+            self.read_state(&backup).expect("Unable to restore state after a failed load");
+        }
+        result
+    }
+
+    fn read_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut r = StateReader::new(data, self.cartridge_header())?;
+        self.pc = r.u16()?;
+        self.sp = r.u16()?;
+        for register in [
+            &mut self.a_reg, &mut self.b_reg, &mut self.c_reg, &mut self.d_reg,
+            &mut self.e_reg, &mut self.f_reg, &mut self.h_reg, &mut self.l_reg,
+        ] {
+            *register = r.u8()?;
+        }
+        self.f_reg &= 0xF0;
+        self.ime = r.bool()?;
+        self.ime_delay = r.bool()?;
+        self.halt = r.bool()?;
+        self.halt_bug = r.bool()?;
+        self.stopped = r.bool()?;
+        self.speed_switch_cycles = r.u16()?.min(SPEED_SWITCH_CYCLES);
+        self.mem.load_state(&mut r)?;
+        r.finish()
+    }
+
+    // Returns true if save data was modified since the last call
+    pub fn take_save_dirty(&mut self) -> bool {
+        self.mem.cart_mut().take_save_dirty()
+    }
+
+    // Current digital output (0-15) of the four sound channels
+    pub fn audio_channel_outputs(&self) -> [u8; 4] {
+        self.mem.apu().channel_outputs()
+    }
+
+    // Plugs a device into the link port, replacing whatever was connected
+    pub fn connect_serial(&mut self, device: Box<dyn SerialDevice>) {
+        self.mem.connect_serial(device);
+    }
+
+    // Sets the host audio rate samples are resampled to, e.g. 44100 or 48000, from 1 to MAX_SAMPLE_RATE
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.mem.apu_mut().set_sample_rate(sample_rate);
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.mem.apu().sample_rate()
+    }
+
+    // Moves buffered audio into out as interleaved left/right samples in -1.0..1.0
+    pub fn drain_samples(&mut self, out: &mut Vec<f32>) {
+        self.mem.apu_mut().drain_samples(out);
+    }
+
+    // Presses or releases a single button
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        let mut state = self.mem.joypad().state(0);
+        state.set(button, pressed);
+        self.set_input(state);
+    }
+
+    // Replaces the state of every button at once, e.g. with the host input polled once per frame
+    pub fn set_input(&mut self, state: ButtonState) {
+        self.mem.set_input(0, state);
+    }
+
+    // Buttons of SGB controllers 2 to MAX_PLAYERS, read once a game enables multiplayer. Player 0 is the same as set_input.
+    // Indexes from MAX_PLAYERS on have no controller port and are ignored
+    pub fn set_player_input(&mut self, player: usize, state: ButtonState) {
+        if player < MAX_PLAYERS {
+            self.mem.set_input(player, state);
+        }
+    }
+
+    // Runs instructions until the PPU finishes drawing a frame
+    pub fn run_frame(&mut self) {
+        while !self.step() {}
+    }
+
+    // Runs a single instruction, returns true if the PPU finished a frame during it
+    pub fn step(&mut self) -> bool {
+        self.tick();
+        let frame = self.mem.ppu_mut().take_frame();
+        if frame {
+            self.mem.end_frame();
+        }
+        frame
+    }
+
+    // Shades (0-3) of the last drawn frame, SCREEN_WIDTH x SCREEN_HEIGHT. In CGB mode these are the colour numbers before the palettes are applied
+    pub fn frame(&self) -> &[u8] {
+        self.mem.ppu().frame()
+    }
+
+    // Last drawn frame as packed RGB888, frame_size pixels. The SGB adds its palettes and border
+    pub fn rgb_frame(&self) -> Vec<u8> {
+        match self.mem.sgb() {
+            Some(sgb) => sgb.rgb_frame(),
+            None => self.mem.ppu().rgb_frame(),
+        }
+    }
+
+    // Width and height of rgb_frame
+    pub fn frame_size(&self) -> (usize, usize) {
+        match self.model {
+            Model::Sgb => (SGB_SCREEN_WIDTH, SGB_SCREEN_HEIGHT),
+            _ => (SCREEN_WIDTH, SCREEN_HEIGHT),
+        }
     }
 
     fn m_tick(&mut self) {
         // Checks if TIMA overflowed before moving on to the next M-Cycle
         self.mem.check_overflow();
 
-        for _x in 0..4 {
+        for _x in 0..self.mem.dots_per_m_cycle() {
             self.mem.tick_ppu();
         }
         self.mem.inc_clk();
@@ -157,7 +435,28 @@ impl Gameboy {
     }
 
     pub fn tick(&mut self) {
+        if self.speed_switch_cycles > 0 {
+            // The CPU and timers are paused while the new clock settles
+            self.speed_switch_cycles -= 1;
+            self.mem.tick_stopped();
+            return;
+        }
+        if self.mem.hdma_stalled() {
+            // The CPU waits for VRAM DMA blocks, the rest of the system keeps running
+            self.m_tick();
+            return;
+        }
+        if self.stopped {
+            if !self.mem.joypad().any_selected_pressed() {
+                // The CPU and timers are halted, the screen keeps producing frames so the frontend can poll input
+                self.mem.tick_stopped();
+                return;
+            }
+            self.stopped = false;
+        }
+
         self.handle_interrupts();
+        self.mem.set_cpu_halted(self.halt);
 
         let op: u16 = self.fetch();
         if self.ime_delay {
@@ -246,7 +545,7 @@ impl Gameboy {
                         
                         // SWAP
                         (1, 1, 0) => {
-                            result = (initial << 4) | (initial >> 4);
+                            result = initial.rotate_left(4);
                             self.set_flag(Z_FLAG, result == 0);
                             self.set_flag(N_FLAG, false);
                             self.set_flag(H_FLAG, false);
@@ -300,8 +599,18 @@ impl Gameboy {
                 // NOP
                 (0b00, (0, 0, 0), 0b000) => {},
                 
-                // STOP (Should not be called on DMG, UPDATE IF UPGRADING TO CGB)
-                (0b00, (0, 1, 0), 0b000) => unimplemented!("STOP should not be called"),
+                // STOP
+                (0b00, (0, 1, 0), 0b000) => {
+                    // STOP is followed by a padding byte that gets skipped
+                    self.pc = self.pc.wrapping_add(1);
+                    self.mem.write(0xFF04, 0);
+                    // With KEY1 armed on CGB, STOP switches CPU speed instead of stopping
+                    if self.mem.switch_speed() {
+                        self.speed_switch_cycles = SPEED_SWITCH_CYCLES;
+                    } else {
+                        self.stopped = true;
+                    }
+                }
 
                 // LD (u16), SP
                 (0b00, (0, 0, 1), 0b000) => {
@@ -1254,4 +1563,182 @@ impl Gameboy {
 
         higher << 8 | lower
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gb::testing::{fix_checksums, rom};
+
+    // Counts up in WRAM and scrolls the screen with the count forever
+    const COUNTER: [u8; 9] = [0x21, 0x00, 0xC0, 0x34, 0x7E, 0xE0, 0x42, 0x18, 0xFA];
+
+    fn gameboy(code: &[u8]) -> Gameboy {
+        let mut gameboy = Gameboy::new();
+        gameboy.load_rom(&rom(code)).unwrap();
+        gameboy
+    }
+
+    // CGB only cartridge on a CGB
+    fn cgb_gameboy(code: &[u8]) -> Gameboy {
+        let mut data = rom(code);
+        data[0x143] = 0xC0;
+        fix_checksums(&mut data);
+        let mut gameboy = Gameboy::with_model(Model::Cgb);
+        gameboy.load_rom(&data).unwrap();
+        gameboy
+    }
+
+    #[test]
+    fn stop_waits_for_a_selected_button() {
+        // Select the buttons, STOP, then LD B, 0x42
+        let mut gameboy = gameboy(&[0x3E, 0x10, 0xE0, 0x00, 0x10, 0x00, 0x06, 0x42, 0x18, 0xFE]);
+        gameboy.run_frame();
+        assert!(gameboy.stopped);
+
+        // The d-pad is not selected
+        gameboy.set_button(Button::Up, true);
+        gameboy.run_frame();
+        assert!(gameboy.stopped);
+        assert_ne!(gameboy.b_reg, 0x42);
+
+        gameboy.set_button(Button::A, true);
+        assert_eq!(gameboy.mem.read(IF_ADDR) & 0x10, 0x10);
+        gameboy.run_frame();
+        assert!(!gameboy.stopped);
+        assert_eq!(gameboy.b_reg, 0x42);
+    }
+
+    // Adds up the time the link port reports to its device
+    struct Clocked(std::rc::Rc<std::cell::Cell<u32>>);
+
+    impl SerialDevice for Clocked {
+        fn transfer(&mut self, _outgoing: u8) -> u8 {
+            0xFF
+        }
+
+        fn clock(&mut self, cycles: u32) {
+            self.0.set(self.0.get() + cycles);
+        }
+    }
+
+    #[test]
+    fn keeps_the_link_clock_running_while_stopped() {
+        let mut gameboy = gameboy(&[0x3E, 0x10, 0xE0, 0x00, 0x10, 0x00, 0x18, 0xFE]);
+        let cycles = std::rc::Rc::new(std::cell::Cell::new(0));
+        gameboy.connect_serial(Box::new(Clocked(cycles.clone())));
+        gameboy.run_frame();
+        assert!(gameboy.stopped);
+        let before = cycles.get();
+        gameboy.run_frame();
+        assert!(gameboy.stopped);
+        assert!(cycles.get() - before >= 70_000);
+    }
+
+    #[test]
+    fn ignores_input_for_players_without_a_controller_port() {
+        let mut gameboy = gameboy(&[0x18, 0xFE]);
+        gameboy.set_player_input(MAX_PLAYERS, ButtonState { a: true, ..Default::default() });
+        gameboy.set_player_input(1, ButtonState { a: true, ..Default::default() });
+    }
+
+    #[test]
+    fn states_round_trip_on_every_model() {
+        for model in [Model::Dmg, Model::Cgb, Model::Sgb] {
+            let mut gb = Gameboy::with_model(model);
+            gb.load_rom(&rom(&COUNTER)).unwrap();
+            for _ in 0..3 {
+                gb.run_frame();
+            }
+            let state = gb.save_state();
+            for _ in 0..2 {
+                gb.run_frame();
+            }
+            let expected = (gb.save_state(), gb.frame().to_vec());
+
+            gb.load_state(&state).unwrap();
+            for _ in 0..2 {
+                gb.run_frame();
+            }
+            assert!((gb.save_state(), gb.frame().to_vec()) == expected, "{:?} diverged after loading", model);
+        }
+    }
+
+    #[test]
+    fn rejects_states_that_do_not_fit() {
+        let mut gb = gameboy(&COUNTER);
+        gb.run_frame();
+        let state = gb.save_state();
+        gb.run_frame();
+        let before = gb.save_state();
+
+        let mut other = rom(&COUNTER);
+        other[0x134..0x139].copy_from_slice(b"OTHER");
+        fix_checksums(&mut other);
+        let mut other_gb = Gameboy::new();
+        other_gb.load_rom(&other).unwrap();
+        assert!(matches!(other_gb.load_state(&state), Err(StateError::RomMismatch { .. })));
+
+        assert!(matches!(gb.load_state(&state[..state.len() - 1]), Err(StateError::Truncated)));
+        assert!(matches!(gb.load_state(b"GBS"), Err(StateError::NotAState)));
+        let mut newer = state.clone();
+        newer[4..6].copy_from_slice(&(STATE_VERSION + 1).to_le_bytes());
+        assert!(matches!(gb.load_state(&newer), Err(StateError::UnsupportedVersion(_))));
+        let mut longer = state;
+        longer.push(0);
+        assert!(matches!(gb.load_state(&longer), Err(StateError::Invalid(_))));
+        // Failed loads leave the machine running where it was
+        assert!(gb.save_state() == before);
+    }
+
+    #[test]
+    fn unmaps_the_boot_rom_on_a_write_to_ff50() {
+        // Unmaps itself straight away and runs into the cartridge's entry point
+        let mut boot = vec![0; 0x100];
+        boot[..4].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]);
+        let mut gb = gameboy(&[0x06, 0x42, 0x18, 0xFE]);
+        assert!(matches!(gb.load_boot_rom(&boot[..0xFF]), Err(BootRomError::InvalidSize { size: 0xFF, expected: 0x100 })));
+        gb.load_boot_rom(&boot).unwrap();
+        assert_eq!(gb.mem.read(0x0000), 0x3E);
+
+        for _ in 0..400 {
+            gb.step();
+        }
+        assert_eq!(gb.mem.read(0x0000), 0x00);
+        assert_eq!(gb.mem.read(0xFF50), 0xFF);
+        assert_eq!(gb.b_reg, 0x42);
+    }
+
+    #[test]
+    fn switches_speed_on_stop_with_key1_armed() {
+        // Arm KEY1, STOP, then LD B, 0x42
+        let code = [0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00, 0x06, 0x42, 0x18, 0xFE];
+        let mut gb = cgb_gameboy(&code);
+        // Past the entry point and the KEY1 write
+        for _ in 0..4 {
+            gb.step();
+        }
+        assert_eq!(gb.mem.read(0xFF4D), 0x7F);
+        gb.step();
+        assert!(gb.double_speed());
+        assert_eq!(gb.mem.read(0xFF4D), 0xFE);
+
+        // The CPU is paused while the clock settles
+        for _ in 0..SPEED_SWITCH_CYCLES - 1 {
+            gb.step();
+        }
+        assert_ne!(gb.b_reg, 0x42);
+        for _ in 0..2 {
+            gb.step();
+        }
+        assert_eq!(gb.b_reg, 0x42);
+
+        // KEY1 does not exist on DMG, so STOP stops
+        let mut gb = gameboy(&code);
+        for _ in 0..5 {
+            gb.step();
+        }
+        assert_eq!(gb.mem.read(0xFF4D), 0xFF);
+        assert!(!gb.double_speed());
+        assert!(gb.stopped);
+    }
+}
@@ -0,0 +1,372 @@
+mod channels;
+mod output;
+
+use crate::gb::apu::channels::{Noise, Square, Wave};
+use crate::gb::apu::output::SampleOutput;
+use crate::gb::state::{StateError, StateReader, StateWriter};
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+// The resampler can't produce more than one sample per T-cycle
+pub const MAX_SAMPLE_RATE: u32 = 4_194_304;
+
+pub struct Audio {
+    square1: Square,
+    square2: Square,
+    wave: Wave,
+    noise: Noise,
+    // NR50 master volume and VIN panning
+    nr50: u8,
+    // NR51 channel panning
+    nr51: u8,
+    powered: bool,
+    // Step 0-7 of the 512 Hz frame sequencer
+    frame_step: u8,
+    output: SampleOutput,
+}
+
+impl Audio {
+    pub fn new() -> Self {
+        let mut audio = Self {
+            square1: Square::new(true),
+            square2: Square::new(false),
+            wave: Wave::new(),
+            noise: Noise::new(),
+            nr50: 0x77,
+            nr51: 0xF3,
+            powered: true,
+            frame_step: 0,
+            output: SampleOutput::new(DEFAULT_SAMPLE_RATE),
+        };
+        // Post-boot register values
+        audio.square1.write_sweep(0x80);
+        audio.square1.write_duty(0xBF);
+        audio.square1.envelope.write(0xF3);
+        audio.square1.enabled = true;
+        audio.square2.write_duty(0x3F);
+        audio
+    }
+
+    // Advances the channel timers by a number of T-cycles
+    pub fn tick(&mut self, cycles: u32) {
+        if self.powered {
+            self.square1.step(cycles);
+            self.square2.step(cycles);
+            self.wave.step(cycles);
+            self.noise.step(cycles);
+        }
+
+        let (left, right) = self.mix();
+        self.output.push(left, right, cycles);
+    }
+
+    // Converts each channel through its DAC and applies NR51 panning and NR50 master volume
+    fn mix(&self) -> (f32, f32) {
+        let digital = self.channel_outputs();
+        let dac_enabled = [
+            self.square1.envelope.dac_enabled(),
+            self.square2.envelope.dac_enabled(),
+            self.wave.dac_enabled,
+            self.noise.envelope.dac_enabled(),
+        ];
+
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for channel in 0..4 {
+            if !dac_enabled[channel] {
+                continue;
+            }
+            // DACs map 0-15 linearly onto 1.0 to -1.0
+            let analog = 1.0 - digital[channel] as f32 / 7.5;
+            if self.nr51 & (0x10 << channel) != 0 {
+                left += analog;
+            }
+            if self.nr51 & (0x01 << channel) != 0 {
+                right += analog;
+            }
+        }
+
+        // Master volume multiplies by 1-8, scaled so 4 channels at full volume reach 1.0
+        let left_volume = (((self.nr50 >> 4) & 0b111) + 1) as f32;
+        let right_volume = ((self.nr50 & 0b111) + 1) as f32;
+        (left * left_volume / 32.0, right * right_volume / 32.0)
+    }
+
+    // Restarts the output pipeline at a new host sample rate
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.output = SampleOutput::new(sample_rate);
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.output.sample_rate()
+    }
+
+    // Moves the queued interleaved stereo samples into out
+    pub fn drain_samples(&mut self, out: &mut Vec<f32>) {
+        self.output.drain(out);
+    }
+
+    // Clocked on the falling edge of DIV bit 4 (512 Hz)
+    pub fn step_frame_sequencer(&mut self) {
+        if !self.powered {
+            return;
+        }
+        // Length at 256 Hz
+        if self.frame_step.is_multiple_of(2) {
+            self.square1.clock_length();
+            self.square2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        // Sweep at 128 Hz
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.square1.clock_sweep();
+        }
+        // Envelope at 64 Hz
+        if self.frame_step == 7 {
+            self.square1.envelope.clock();
+            self.square2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+        self.frame_step = (self.frame_step + 1) % 8;
+    }
+
+    // Digital output (0-15) of each channel, before panning and master volume
+    pub fn channel_outputs(&self) -> [u8; 4] {
+        [self.square1.output(), self.square2.output(), self.wave.output(), self.noise.output()]
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            // Channel 1
+            0xFF10 => self.square1.read_sweep() | 0x80,
+            0xFF11 => self.square1.read_duty() | 0x3F,
+            0xFF12 => self.square1.envelope.read(),
+            0xFF14 => Self::read_control(self.square1.length.enabled),
+            // Channel 2
+            0xFF16 => self.square2.read_duty() | 0x3F,
+            0xFF17 => self.square2.envelope.read(),
+            0xFF19 => Self::read_control(self.square2.length.enabled),
+            // Channel 3
+            0xFF1A => if self.wave.dac_enabled { 0xFF } else { 0x7F },
+            0xFF1C => (self.wave.volume << 5) | 0x9F,
+            0xFF1E => Self::read_control(self.wave.length.enabled),
+            // Channel 4
+            0xFF21 => self.noise.envelope.read(),
+            0xFF22 => self.noise.polynomial,
+            0xFF23 => Self::read_control(self.noise.length.enabled),
+            // Control
+            0xFF24 => self.nr50,
+            0xFF25 => self.nr51,
+            0xFF26 => {
+                let status = self.square1.enabled as u8
+                    | (self.square2.enabled as u8) << 1
+                    | (self.wave.enabled as u8) << 2
+                    | (self.noise.enabled as u8) << 3;
+                if self.powered { 0xF0 | status } else { 0x70 }
+            }
+            // Wave RAM
+            0xFF30..=0xFF3F => self.wave.ram[(addr - 0xFF30) as usize],
+            // Write-only and unused registers
+            _ => 0xFF,
+        }
+    }
+
+    // Only the length enable bit of NRx4 is readable
+    fn read_control(length_enabled: bool) -> u8 {
+        if length_enabled { 0xFF } else { 0xBF }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        // Wave RAM and NR52 are always writable
+        if (0xFF30..=0xFF3F).contains(&addr) {
+            self.wave.ram[(addr - 0xFF30) as usize] = data;
+            return;
+        }
+        if addr == 0xFF26 {
+            self.set_power(data & 0x80 != 0);
+            return;
+        }
+        // While powered off only the length counters can be written on DMG
+        if !self.powered {
+            match addr {
+                0xFF11 => self.square1.length.load(data & 0x3F),
+                0xFF16 => self.square2.length.load(data & 0x3F),
+                0xFF1B => self.wave.length.load(data),
+                0xFF20 => self.noise.length.load(data & 0x3F),
+                _ => {}
+            }
+            return;
+        }
+
+        match addr {
+            // Channel 1
+            0xFF10 => self.square1.write_sweep(data),
+            0xFF11 => self.square1.write_duty(data),
+            0xFF12 => {
+                self.square1.envelope.write(data);
+                if !self.square1.envelope.dac_enabled() {
+                    self.square1.enabled = false;
+                }
+            }
+            0xFF13 => self.square1.frequency = (self.square1.frequency & 0x700) | data as u16,
+            0xFF14 => {
+                self.square1.frequency = (self.square1.frequency & 0xFF) | ((data as u16 & 0b111) << 8);
+                self.square1.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    self.square1.trigger();
+                }
+            }
+            // Channel 2
+            0xFF16 => self.square2.write_duty(data),
+            0xFF17 => {
+                self.square2.envelope.write(data);
+                if !self.square2.envelope.dac_enabled() {
+                    self.square2.enabled = false;
+                }
+            }
+            0xFF18 => self.square2.frequency = (self.square2.frequency & 0x700) | data as u16,
+            0xFF19 => {
+                self.square2.frequency = (self.square2.frequency & 0xFF) | ((data as u16 & 0b111) << 8);
+                self.square2.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    self.square2.trigger();
+                }
+            }
+            // Channel 3
+            0xFF1A => {
+                self.wave.dac_enabled = data & 0x80 != 0;
+                if !self.wave.dac_enabled {
+                    self.wave.enabled = false;
+                }
+            }
+            0xFF1B => self.wave.length.load(data),
+            0xFF1C => self.wave.volume = (data >> 5) & 0b11,
+            0xFF1D => self.wave.frequency = (self.wave.frequency & 0x700) | data as u16,
+            0xFF1E => {
+                self.wave.frequency = (self.wave.frequency & 0xFF) | ((data as u16 & 0b111) << 8);
+                self.wave.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    self.wave.trigger();
+                }
+            }
+            // Channel 4
+            0xFF20 => self.noise.length.load(data & 0x3F),
+            0xFF21 => {
+                self.noise.envelope.write(data);
+                if !self.noise.envelope.dac_enabled() {
+                    self.noise.enabled = false;
+                }
+            }
+            0xFF22 => self.noise.polynomial = data,
+            0xFF23 => {
+                self.noise.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    self.noise.trigger();
+                }
+            }
+            // Control
+            0xFF24 => self.nr50 = data,
+            0xFF25 => self.nr51 = data,
+            _ => {}
+        }
+    }
+
+    // Powering off clears every register except wave RAM
+    fn set_power(&mut self, on: bool) {
+        if on && !self.powered {
+            self.frame_step = 0;
+        }
+        if !on && self.powered {
+            let wave_ram = self.wave.ram;
+            self.square1 = Square::new(true);
+            self.square2 = Square::new(false);
+            self.wave = Wave::new();
+            self.wave.ram = wave_ram;
+            self.noise = Noise::new();
+            self.nr50 = 0;
+            self.nr51 = 0;
+        }
+        self.powered = on;
+    }
+
+    // The resampler and queued samples belong to the host side and are left alone
+    pub fn save_state(&self, w: &mut StateWriter) {
+        self.square1.save_state(w);
+        self.square2.save_state(w);
+        self.wave.save_state(w);
+        self.noise.save_state(w);
+        w.u8(self.nr50);
+        w.u8(self.nr51);
+        w.bool(self.powered);
+        w.u8(self.frame_step);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.square1.load_state(r)?;
+        self.square2.load_state(r)?;
+        self.wave.load_state(r)?;
+        self.noise.load_state(r)?;
+        self.nr50 = r.u8()?;
+        self.nr51 = r.u8()?;
+        self.powered = r.bool()?;
+        self.frame_step = r.u8()? % 8;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel_on(audio: &Audio, channel: u8) -> bool {
+        audio.read(0xFF26) & (1 << channel) != 0
+    }
+
+    #[test]
+    fn clocks_length_on_even_frame_sequencer_steps() {
+        let mut audio = Audio::new();
+        audio.write(0xFF17, 0xF0);
+        audio.write(0xFF16, 62);
+        audio.write(0xFF19, 0xC0);
+        assert!(channel_on(&audio, 1));
+        audio.step_frame_sequencer();
+        audio.step_frame_sequencer();
+        assert!(channel_on(&audio, 1));
+        audio.step_frame_sequencer();
+        assert!(!channel_on(&audio, 1));
+    }
+
+    #[test]
+    fn clocks_envelopes_on_step_7_and_sweep_on_steps_2_and_6() {
+        let mut audio = Audio::new();
+        audio.write(0xFF17, 0xF1);
+        audio.write(0xFF19, 0x80);
+        // Period 1 sweep increasing the frequency by half
+        audio.write(0xFF10, 0x11);
+        audio.write(0xFF13, 0x00);
+        audio.write(0xFF14, 0x81);
+        for step in 0..8 {
+            assert_eq!(audio.square2.envelope.volume, 15, "step {}", step);
+            let frequency = audio.square1.frequency;
+            audio.step_frame_sequencer();
+            assert_eq!(audio.square1.frequency != frequency, step == 2 || step == 6, "step {}", step);
+        }
+        assert_eq!(audio.square2.envelope.volume, 14);
+    }
+
+    #[test]
+    fn powering_off_clears_registers_but_keeps_wave_ram() {
+        let mut audio = Audio::new();
+        audio.write(0xFF30, 0x12);
+        audio.write(0xFF26, 0x00);
+        assert_eq!(audio.read(0xFF24), 0x00);
+        assert_eq!(audio.read(0xFF26), 0x70);
+        // Registers other than the length counters ignore writes until powered back on
+        audio.write(0xFF24, 0x77);
+        assert_eq!(audio.read(0xFF24), 0x00);
+        assert_eq!(audio.read(0xFF30), 0x12);
+        audio.write(0xFF26, 0x80);
+        audio.write(0xFF24, 0x77);
+        assert_eq!(audio.read(0xFF24), 0x77);
+    }
+}
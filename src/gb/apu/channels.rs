@@ -0,0 +1,461 @@
+use crate::gb::state::{StateError, StateReader, StateWriter};
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// Counts down to silence a channel after a set time, clocked at 256 Hz
+pub struct Length {
+    counter: u16,
+    max: u16,
+    pub enabled: bool,
+}
+
+impl Length {
+    pub fn new(max: u16) -> Self {
+        Self {
+            counter: 0,
+            max,
+            enabled: false,
+        }
+    }
+
+    // NRx1 stores max - counter
+    pub fn load(&mut self, value: u8) {
+        self.counter = self.max - value as u16;
+    }
+
+    // Returns false once the channel should be disabled
+    pub fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter > 0;
+        }
+        true
+    }
+
+    pub fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.counter);
+        w.bool(self.enabled);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.counter = r.u16()?.min(self.max);
+        self.enabled = r.bool()?;
+        Ok(())
+    }
+}
+
+// Volume envelope shared by the square and noise channels, clocked at 64 Hz
+pub struct Envelope {
+    // Raw NRx2 value
+    register: u8,
+    pub volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Self {
+            register: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        self.register
+    }
+
+    pub fn write(&mut self, data: u8) {
+        self.register = data;
+    }
+
+    // The DAC is off when both the initial volume and direction are 0
+    pub fn dac_enabled(&self) -> bool {
+        self.register & 0xF8 != 0
+    }
+
+    pub fn trigger(&mut self) {
+        self.volume = self.register >> 4;
+        self.timer = self.register & 0b111;
+    }
+
+    pub fn clock(&mut self) {
+        let period = self.register & 0b111;
+        if period == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = period;
+            if self.register & 0x08 != 0 && self.volume < 15 {
+                self.volume += 1;
+            } else if self.register & 0x08 == 0 && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.register);
+        w.u8(self.volume);
+        w.u8(self.timer);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.register = r.u8()?;
+        self.volume = r.u8()? & 0xF;
+        self.timer = r.u8()?;
+        Ok(())
+    }
+}
+
+// Channel 1 and 2, channel 1 additionally has the frequency sweep
+pub struct Square {
+    pub enabled: bool,
+    pub length: Length,
+    pub envelope: Envelope,
+    duty: u8,
+    duty_step: usize,
+    pub frequency: u16,
+    timer: i32,
+    has_sweep: bool,
+    sweep_register: u8,
+    sweep_enabled: bool,
+    sweep_timer: u8,
+    shadow_frequency: u16,
+}
+
+impl Square {
+    pub fn new(has_sweep: bool) -> Self {
+        Self {
+            enabled: false,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            duty: 0,
+            duty_step: 0,
+            frequency: 0,
+            timer: 0,
+            has_sweep,
+            sweep_register: 0,
+            sweep_enabled: false,
+            sweep_timer: 0,
+            shadow_frequency: 0,
+        }
+    }
+
+    pub fn read_sweep(&self) -> u8 {
+        self.sweep_register
+    }
+
+    pub fn write_sweep(&mut self, data: u8) {
+        self.sweep_register = data & 0x7F;
+    }
+
+    pub fn read_duty(&self) -> u8 {
+        self.duty << 6
+    }
+
+    pub fn write_duty(&mut self, data: u8) {
+        self.duty = data >> 6;
+        self.length.load(data & 0x3F);
+    }
+
+    pub fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = self.period();
+
+        if self.has_sweep {
+            let period = (self.sweep_register >> 4) & 0b111;
+            let shift = self.sweep_register & 0b111;
+            self.shadow_frequency = self.frequency;
+            self.sweep_timer = if period == 0 { 8 } else { period };
+            self.sweep_enabled = period != 0 || shift != 0;
+            // An overflowing first calculation disables the channel straight away
+            if shift != 0 && self.sweep_calculation() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 4
+    }
+
+    fn sweep_calculation(&self) -> u16 {
+        let delta = self.shadow_frequency >> (self.sweep_register & 0b111);
+        if self.sweep_register & 0x08 != 0 {
+            self.shadow_frequency - delta
+        } else {
+            self.shadow_frequency + delta
+        }
+    }
+
+    // Clocked at 128 Hz by the frame sequencer
+    pub fn clock_sweep(&mut self) {
+        if !self.has_sweep {
+            return;
+        }
+        if self.sweep_timer > 0 {
+            self.sweep_timer -= 1;
+        }
+        if self.sweep_timer != 0 {
+            return;
+        }
+
+        let period = (self.sweep_register >> 4) & 0b111;
+        self.sweep_timer = if period == 0 { 8 } else { period };
+        if !self.sweep_enabled || period == 0 {
+            return;
+        }
+
+        let new_frequency = self.sweep_calculation();
+        if new_frequency > 2047 {
+            self.enabled = false;
+        } else if self.sweep_register & 0b111 != 0 {
+            self.frequency = new_frequency;
+            self.shadow_frequency = new_frequency;
+            // The new frequency is checked for overflow again but not written back
+            if self.sweep_calculation() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        self.timer -= cycles as i32;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.duty_step = (self.duty_step + 1) % 8;
+        }
+    }
+
+    // Digital output 0-15
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        DUTY_TABLE[self.duty as usize][self.duty_step] * self.envelope.volume
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        self.length.save_state(w);
+        self.envelope.save_state(w);
+        w.u8(self.duty);
+        w.u8(self.duty_step as u8);
+        w.u16(self.frequency);
+        w.i32(self.timer);
+        w.u8(self.sweep_register);
+        w.bool(self.sweep_enabled);
+        w.u8(self.sweep_timer);
+        w.u16(self.shadow_frequency);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.bool()?;
+        self.length.load_state(r)?;
+        self.envelope.load_state(r)?;
+        self.duty = r.u8()? & 0b11;
+        self.duty_step = (r.u8()? & 0b111) as usize;
+        self.frequency = r.u16()? & 0x7FF;
+        self.timer = r.i32()?;
+        self.sweep_register = r.u8()?;
+        self.sweep_enabled = r.bool()?;
+        self.sweep_timer = r.u8()?;
+        self.shadow_frequency = r.u16()? & 0x7FF;
+        Ok(())
+    }
+}
+
+// Channel 3, plays back the 32 4-bit samples in wave RAM
+pub struct Wave {
+    pub enabled: bool,
+    pub dac_enabled: bool,
+    pub length: Length,
+    // NR32 output level
+    pub volume: u8,
+    pub frequency: u16,
+    timer: i32,
+    position: usize,
+    pub ram: [u8; 16],
+}
+
+impl Wave {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            dac_enabled: false,
+            length: Length::new(256),
+            volume: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            ram: [0; 16],
+        }
+    }
+
+    pub fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.timer = self.period();
+        self.position = 0;
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 2
+    }
+
+    pub fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        self.timer -= cycles as i32;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.position = (self.position + 1) % 32;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        let byte = self.ram[self.position / 2];
+        let sample = if self.position.is_multiple_of(2) { byte >> 4 } else { byte & 0xF };
+        // Output level 0 mutes, 1-3 shift right by 0-2
+        match self.volume {
+            0 => 0,
+            level => sample >> (level - 1),
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.bool(self.dac_enabled);
+        self.length.save_state(w);
+        w.u8(self.volume);
+        w.u16(self.frequency);
+        w.i32(self.timer);
+        w.u8(self.position as u8);
+        w.bytes(&self.ram);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.bool()?;
+        self.dac_enabled = r.bool()?;
+        self.length.load_state(r)?;
+        self.volume = r.u8()? & 0b11;
+        self.frequency = r.u16()? & 0x7FF;
+        self.timer = r.i32()?;
+        self.position = (r.u8()? % 32) as usize;
+        r.bytes(&mut self.ram)
+    }
+}
+
+// Channel 4, pseudo-random noise from a linear feedback shift register
+pub struct Noise {
+    pub enabled: bool,
+    pub length: Length,
+    pub envelope: Envelope,
+    // Raw NR43 value
+    pub polynomial: u8,
+    lfsr: u16,
+    timer: i32,
+}
+
+impl Noise {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            polynomial: 0,
+            lfsr: 0x7FFF,
+            timer: 0,
+        }
+    }
+
+    pub fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.lfsr = 0x7FFF;
+        self.timer = self.period();
+    }
+
+    fn period(&self) -> i32 {
+        (NOISE_DIVISORS[(self.polynomial & 0b111) as usize] << (self.polynomial >> 4)) as i32
+    }
+
+    pub fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        self.timer -= cycles as i32;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            let feedback = (self.lfsr & 1) ^ ((self.lfsr >> 1) & 1);
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+            // 7-bit mode also feeds back into bit 6
+            if self.polynomial & 0x08 != 0 {
+                self.lfsr = (self.lfsr & !0x40) | (feedback << 6);
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        // Output is the inverse of bit 0
+        (!self.lfsr & 1) as u8 * self.envelope.volume
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        self.length.save_state(w);
+        self.envelope.save_state(w);
+        w.u8(self.polynomial);
+        w.u16(self.lfsr);
+        w.i32(self.timer);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.bool()?;
+        self.length.load_state(r)?;
+        self.envelope.load_state(r)?;
+        self.polynomial = r.u8()?;
+        self.lfsr = r.u16()?;
+        self.timer = r.i32()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+// T-cycles per second, the rate amplitudes are fed in at
+const CLOCK_RATE: f64 = 4_194_304.0;
+// Sub-sample positions the step kernel is precomputed for
+const PHASES: usize = 32;
+// Taps per kernel phase
+const KERNEL_WIDTH: usize = 16;
+// Samples are moved to the ring buffer in batches of at least this many
+const DRAIN_THRESHOLD: f64 = 32.0;
+// Charge retained by the output capacitor after one T-cycle on DMG
+const CAPACITOR_CHARGE: f64 = 0.999958;
+
+// Band-limited step synthesis: every amplitude change is added as a windowed sinc impulse into a delta buffer,
+// which is integrated when samples are read out at the host rate
+struct BandLimited {
+    // Output samples per T-cycle
+    step: f64,
+    // Current time in output samples, relative to the start of deltas
+    time: f64,
+    deltas: Vec<f32>,
+    integrator: f32,
+    amplitude: f32,
+}
+
+impl BandLimited {
+    fn new(sample_rate: u32) -> Self {
+        Self {
+            step: sample_rate as f64 / CLOCK_RATE,
+            time: 0.0,
+            deltas: vec![0.0; KERNEL_WIDTH],
+            integrator: 0.0,
+            amplitude: 0.0,
+        }
+    }
+
+    fn set_amplitude(&mut self, amplitude: f32, kernel: &[[f32; KERNEL_WIDTH]]) {
+        let delta = amplitude - self.amplitude;
+        if delta == 0.0 {
+            return;
+        }
+        self.amplitude = amplitude;
+
+        let whole = self.time as usize;
+        let phase = ((self.time - whole as f64) * PHASES as f64) as usize;
+        if self.deltas.len() < whole + KERNEL_WIDTH {
+            self.deltas.resize(whole + KERNEL_WIDTH, 0.0);
+        }
+        for (i, tap) in kernel[phase].iter().enumerate() {
+            self.deltas[whole + i] += delta * tap;
+        }
+    }
+
+    fn clock(&mut self, cycles: u32) {
+        self.time += self.step * cycles as f64;
+    }
+
+    // Integrates every sample that can no longer be affected by future amplitude changes
+    fn read(&mut self, out: &mut Vec<f32>) {
+        let ready = self.time as usize;
+        if self.deltas.len() < ready + KERNEL_WIDTH {
+            self.deltas.resize(ready + KERNEL_WIDTH, 0.0);
+        }
+        for delta in self.deltas.drain(..ready) {
+            self.integrator += delta;
+            out.push(self.integrator);
+        }
+        self.time -= ready as f64;
+    }
+}
+
+// Each phase holds a Blackman windowed sinc impulse offset by that fraction of a sample, normalised to sum to 1
+fn build_kernel() -> Vec<[f32; KERNEL_WIDTH]> {
+    // Cut off a little under Nyquist to leave room for the transition band
+    let cutoff = 0.45;
+    let center = KERNEL_WIDTH as f64 / 2.0;
+    (0..PHASES).map(|phase| {
+        let offset = phase as f64 / PHASES as f64;
+        let mut taps = [0.0; KERNEL_WIDTH];
+        let mut sum = 0.0;
+        for (i, tap) in taps.iter_mut().enumerate() {
+            let x = i as f64 - center + 1.0 - offset;
+            let sinc = if x == 0.0 { 1.0 } else { (2.0 * PI * cutoff * x).sin() / (2.0 * PI * cutoff * x) };
+            let n = (i as f64 + 1.0 - offset) / KERNEL_WIDTH as f64;
+            let window = 0.42 - 0.5 * (2.0 * PI * n).cos() + 0.08 * (4.0 * PI * n).cos();
+            *tap = sinc * window;
+            sum += *tap;
+        }
+        taps.map(|tap| (tap / sum) as f32)
+    }).collect()
+}
+
+// Resamples the mixed stereo signal to the host rate, removes DC like the DMG's output capacitor,
+// and queues interleaved stereo samples for the frontend
+pub struct SampleOutput {
+    sample_rate: u32,
+    kernel: Vec<[f32; KERNEL_WIDTH]>,
+    left: BandLimited,
+    right: BandLimited,
+    // Charge factor per output sample and the capacitor state of each side
+    charge: f32,
+    capacitor: [f32; 2],
+    // Interleaved left/right samples waiting for the frontend, oldest dropped when full
+    ring: VecDeque<f32>,
+    capacity: usize,
+    scratch: [Vec<f32>; 2],
+}
+
+impl SampleOutput {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            kernel: build_kernel(),
+            left: BandLimited::new(sample_rate),
+            right: BandLimited::new(sample_rate),
+            charge: CAPACITOR_CHARGE.powf(CLOCK_RATE / sample_rate as f64) as f32,
+            capacitor: [0.0; 2],
+            // One second of stereo audio
+            capacity: 2 * sample_rate as usize,
+            ring: VecDeque::with_capacity(2 * sample_rate as usize),
+            scratch: [Vec::new(), Vec::new()],
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Feeds the current mixed amplitude of each side, held for the given number of T-cycles
+    pub fn push(&mut self, left: f32, right: f32, cycles: u32) {
+        self.left.set_amplitude(left, &self.kernel);
+        self.right.set_amplitude(right, &self.kernel);
+        self.left.clock(cycles);
+        self.right.clock(cycles);
+
+        if self.left.time >= DRAIN_THRESHOLD {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        let [left, right] = &mut self.scratch;
+        left.clear();
+        right.clear();
+        self.left.read(left);
+        self.right.read(right);
+
+        for (&l, &r) in left.iter().zip(right.iter()) {
+            // Drop the oldest whole frame so the queue stays aligned on left/right pairs
+            if self.ring.len() + 2 > self.capacity {
+                self.ring.pop_front();
+                self.ring.pop_front();
+            }
+            for (side, sample) in [l, r].into_iter().enumerate() {
+                // High-pass filter: the capacitor slowly charges towards the DC level of the signal
+                let out = sample - self.capacitor[side];
+                self.capacitor[side] = sample - out * self.charge;
+                self.ring.push_back(out);
+            }
+        }
+    }
+
+    // Moves all queued interleaved stereo samples into out
+    pub fn drain(&mut self, out: &mut Vec<f32>) {
+        out.extend(self.ring.drain(..));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Holds the amplitudes for a number of T-cycles in small steps and returns the drained samples
+    fn run(output: &mut SampleOutput, left: f32, right: f32, cycles: u32) -> Vec<f32> {
+        for _ in 0..cycles / 4 {
+            output.push(left, right, 4);
+        }
+        let mut samples = Vec::new();
+        output.drain(&mut samples);
+        samples
+    }
+
+    #[test]
+    fn resamples_to_the_host_rate() {
+        let mut output = SampleOutput::new(48000);
+        let samples = run(&mut output, 0.0, 0.0, CLOCK_RATE as u32 / 10);
+        // Interleaved pairs, minus what is still held back for the kernel
+        assert_eq!(samples.len() % 2, 0);
+        assert!((4800 - 2 * DRAIN_THRESHOLD as usize..=4800).contains(&(samples.len() / 2)), "{}", samples.len());
+    }
+
+    #[test]
+    fn keeps_sides_apart_and_removes_dc() {
+        let mut output = SampleOutput::new(48000);
+        let samples = run(&mut output, 0.5, -0.5, CLOCK_RATE as u32 / 1000);
+        let (left, right) = (samples[samples.len() - 2], samples[samples.len() - 1]);
+        assert!(left > 0.4 && right < -0.4, "{} {}", left, right);
+        // The capacitor charges with a time constant of about 6 ms
+        let samples = run(&mut output, 0.5, -0.5, CLOCK_RATE as u32 / 10);
+        let (left, right) = (samples[samples.len() - 2], samples[samples.len() - 1]);
+        assert!(left.abs() < 0.01 && right.abs() < 0.01, "{} {}", left, right);
+    }
+
+    #[test]
+    fn keeps_the_last_second_in_whole_frames_when_full() {
+        let mut output = SampleOutput::new(1000);
+        for _ in 0..3 * CLOCK_RATE as u32 / 4 {
+            output.push(0.0, 0.0, 4);
+        }
+        for _ in 0..CLOCK_RATE as u32 / 25 / 4 {
+            output.push(0.5, -0.5, 4);
+        }
+        let mut samples = Vec::new();
+        output.drain(&mut samples);
+        assert_eq!(samples.len(), 2000);
+        let (left, right) = (samples[samples.len() - 2], samples[samples.len() - 1]);
+        assert!(left > 0.0 && right < 0.0, "{} {}", left, right);
+    }
+}
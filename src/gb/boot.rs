@@ -0,0 +1,74 @@
+use std::fmt;
+
+use crate::gb::cart::header::CartridgeHeader;
+
+// The DMG boot ROM is mapped over 0x0000-0x00FF
+pub const BOOT_ROM_SIZE: usize = 0x100;
+// The CGB boot ROM is also mapped over 0x0200-0x08FF, leaving the cartridge header visible
+pub const CGB_BOOT_ROM_SIZE: usize = 0x900;
+
+// D, E, H, L left by the CGB boot ROM when the cartridge has no CGB support and it falls back to DMG compatibility.
+// B depends on the title checksum of Nintendo cartridges and is left at 0
+pub const CGB_COMPAT_REGISTERS: [u8; 4] = [0x00, 0x08, 0x00, 0x7C];
+
+// Hardware revision, decides the register values left behind when the boot ROM is skipped
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Model {
+    // Original DMG with the early boot ROM
+    Dmg0,
+    #[default]
+    Dmg,
+    // Game Boy Pocket
+    Mgb,
+    Sgb,
+    Cgb,
+}
+
+impl Model {
+    // CGB for cartridges that declare CGB support, DMG otherwise
+    pub fn for_header(header: &CartridgeHeader) -> Self {
+        if header.supports_cgb() { Model::Cgb } else { Model::Dmg }
+    }
+
+    pub fn boot_rom_size(self) -> usize {
+        if self == Model::Cgb { CGB_BOOT_ROM_SIZE } else { BOOT_ROM_SIZE }
+    }
+
+    // A, F, B, C, D, E, H, L at 0x0100 once the boot ROM has finished.
+    // DMG and MGB set H and C from the header checksum, which is non-zero for nearly every cartridge
+    pub fn post_boot_registers(self) -> [u8; 8] {
+        match self {
+            Model::Dmg0 => [0x01, 0x00, 0xFF, 0x13, 0x00, 0xC1, 0x84, 0x03],
+            Model::Dmg => [0x01, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            Model::Mgb => [0xFF, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            Model::Sgb => [0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
+            Model::Cgb => [0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+        }
+    }
+
+    // System counter at 0x0100, DIV is its upper byte. The SGB and CGB values depend on how long
+    // the boot animation ran and are approximations
+    pub fn post_boot_sys_clock(self) -> u16 {
+        match self {
+            Model::Dmg0 => 0x1830,
+            Model::Dmg | Model::Mgb => 0xABCC,
+            Model::Sgb => 0xD85C,
+            Model::Cgb => 0x1EA0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BootRomError {
+    InvalidSize { size: usize, expected: usize },
+}
+
+impl fmt::Display for BootRomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BootRomError::InvalidSize { size, expected } => write!(f, "boot ROM is {} bytes, expected {}", size, expected),
+        }
+    }
+}
+
+impl std::error::Error for BootRomError {}
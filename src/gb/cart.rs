@@ -0,0 +1,436 @@
+pub mod header;
+mod rtc;
+
+use crate::gb::cart::header::{CartridgeHeader, HeaderError};
+use crate::gb::cart::rtc::{Rtc, RTC_FOOTER_SIZE, RTC_FOOTER_SIZE_32};
+use crate::gb::state::{StateError, StateReader, StateWriter};
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+// MBC2 has 512 half-bytes of RAM built into the controller
+const MBC2_RAM_SIZE: usize = 0x200;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum MbcKind {
+    None,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+}
+
+pub struct Cartridge {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    kind: MbcKind,
+    ram_enabled: bool,
+    // Full ROM bank number mapped at 0x4000-0x7FFF, before masking to the ROM size
+    rom_bank: u16,
+    // MBC1 uses this as the upper 2 bits of the ROM bank in addition to the RAM bank
+    ram_bank: u8,
+    // MBC1 banking mode select (0x6000-0x7FFF)
+    mbc1_mode: bool,
+    // MBC1 multicarts wire the upper bank bits one position lower
+    mbc1_multicart: bool,
+    // MBC3+TIMER carts
+    rtc: Option<Rtc>,
+    header: Option<CartridgeHeader>,
+    // Set when RAM or RTC registers are written so the frontend knows to flush the save file
+    save_dirty: bool,
+}
+
+impl Cartridge {
+    // Cartridge slot with nothing inserted, reads as a blank 32 KiB ROM
+    pub fn empty() -> Self {
+        Self::with_header(vec![0; 2 * ROM_BANK_SIZE], None)
+    }
+
+    // Refuses cartridges the emulator can't run, even when their header is well formed
+    pub fn new(data: &[u8]) -> Result<Self, HeaderError> {
+        let header = CartridgeHeader::parse(data)?;
+        if !header.cartridge_type_supported() {
+            return Err(HeaderError::UnsupportedCartridgeType(header.cartridge_type));
+        }
+        if !header.rom_size_valid() {
+            return Err(HeaderError::RomSizeMismatch { declared: header.rom_size, actual: header.file_size });
+        }
+        Ok(Self::with_header(data.to_vec(), Some(header)))
+    }
+
+    fn with_header(rom: Vec<u8>, header: Option<CartridgeHeader>) -> Self {
+        let cart_type = header.as_ref().map_or(0, |h| h.cartridge_type);
+        let kind = match cart_type {
+            0x01..=0x03 => MbcKind::Mbc1,
+            0x05 | 0x06 => MbcKind::Mbc2,
+            0x0F..=0x13 => MbcKind::Mbc3,
+            0x19..=0x1E => MbcKind::Mbc5,
+            _ => MbcKind::None,
+        };
+
+        let ram_size = if kind == MbcKind::Mbc2 {
+            MBC2_RAM_SIZE
+        } else {
+            header.as_ref().map_or(0, |h| h.ram_size)
+        };
+
+        // Multicarts repeat the Nintendo logo at the start of each 256 KiB game
+        let mbc1_multicart = kind == MbcKind::Mbc1 && rom.len() == 0x100000 && rom[0x104..0x134] == rom[0x40104..0x40134];
+        let rtc = if header.as_ref().is_some_and(|h| h.has_rtc()) { Some(Rtc::new()) } else { None };
+
+        Self {
+            rom,
+            ram: vec![0; ram_size],
+            kind,
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            mbc1_mode: false,
+            mbc1_multicart,
+            rtc,
+            header,
+            save_dirty: false,
+        }
+    }
+
+    pub fn header(&self) -> Option<&CartridgeHeader> {
+        self.header.as_ref()
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.header.as_ref().is_some_and(|h| h.has_battery())
+    }
+
+    // Returns true if battery backed state changed since the last call
+    pub fn take_save_dirty(&mut self) -> bool {
+        let dirty = self.save_dirty;
+        self.save_dirty = false;
+        dirty
+    }
+
+    pub fn set_rtc_host_sync(&mut self, enabled: bool) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.set_host_sync(enabled);
+        }
+    }
+
+    // Steps the RTC by emulated T-cycles
+    pub fn tick(&mut self, cycles: u32) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.tick(cycles);
+        }
+    }
+
+    // External RAM followed by the RTC footer if the cartridge has one
+    pub fn save_data(&mut self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        if let Some(rtc) = &mut self.rtc {
+            data.extend_from_slice(&rtc.footer());
+        }
+        data
+    }
+
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        let ram_len = self.ram.len().min(data.len());
+        self.ram[..ram_len].copy_from_slice(&data[..ram_len]);
+        let footer = &data[ram_len..];
+        if let Some(rtc) = &mut self.rtc
+            && (footer.len() == RTC_FOOTER_SIZE || footer.len() == RTC_FOOTER_SIZE_32)
+        {
+            rtc.load_footer(footer);
+        }
+    }
+
+    // The ROM itself is not stored, states only load on top of the same ROM
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.vec(&self.ram);
+        w.bool(self.ram_enabled);
+        w.u16(self.rom_bank);
+        w.u8(self.ram_bank);
+        w.bool(self.mbc1_mode);
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(w);
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.vec_into(&mut self.ram)?;
+        self.ram_enabled = r.bool()?;
+        self.rom_bank = r.u16()?;
+        self.ram_bank = r.u8()?;
+        self.mbc1_mode = r.bool()?;
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load_state(r)?;
+        }
+        // Loading a state changes what the save file should contain
+        self.save_dirty = true;
+        Ok(())
+    }
+
+    // MBC3 maps the RTC registers in place of RAM when banks 0x08-0x0C are selected
+    fn rtc_selected(&self) -> bool {
+        self.kind == MbcKind::Mbc3 && (0x08..=0x0C).contains(&self.ram_bank)
+    }
+
+    fn rom_bank_count(&self) -> usize {
+        self.rom.len() / ROM_BANK_SIZE
+    }
+
+    // Bank numbers mapped at 0x0000-0x3FFF and 0x4000-0x7FFF
+    fn rom_banks(&self) -> (usize, usize) {
+        let (low, high) = match self.kind {
+            MbcKind::None => (0, 1),
+            MbcKind::Mbc1 => {
+                let shift = if self.mbc1_multicart { 4 } else { 5 };
+                let lower_bits = if self.mbc1_multicart { self.rom_bank & 0xF } else { self.rom_bank };
+                let upper = (self.ram_bank as usize) << shift;
+                let low = if self.mbc1_mode { upper } else { 0 };
+                (low, upper | lower_bits as usize)
+            }
+            MbcKind::Mbc2 | MbcKind::Mbc3 | MbcKind::Mbc5 => (0, self.rom_bank as usize),
+        };
+        let mask = self.rom_bank_count() - 1;
+        (low & mask, high & mask)
+    }
+
+    pub fn read_rom(&self, addr: u16) -> u8 {
+        let (low, high) = self.rom_banks();
+        let offset = addr as usize & (ROM_BANK_SIZE - 1);
+        if addr < 0x4000 {
+            self.rom[low * ROM_BANK_SIZE + offset]
+        } else {
+            self.rom[high * ROM_BANK_SIZE + offset]
+        }
+    }
+
+    // Writes to ROM addresses set the MBC registers
+    pub fn write_rom(&mut self, addr: u16, data: u8) {
+        match self.kind {
+            MbcKind::None => {}
+            MbcKind::Mbc1 => {
+                if addr < 0x2000 {
+                    self.ram_enabled = data & 0xF == 0xA;
+                }
+                else if addr < 0x4000 {
+                    // Bank 0 is remapped to 1 based on the 5 bit value only
+                    self.rom_bank = (data & 0x1F).max(1) as u16;
+                }
+                else if addr < 0x6000 {
+                    self.ram_bank = data & 0b11;
+                }
+                else {
+                    self.mbc1_mode = data & 1 == 1;
+                }
+            }
+            MbcKind::Mbc2 => {
+                if addr < 0x4000 {
+                    // Address bit 8 selects between RAM enable and ROM bank
+                    if addr & 0x100 == 0 {
+                        self.ram_enabled = data & 0xF == 0xA;
+                    } else {
+                        self.rom_bank = (data & 0xF).max(1) as u16;
+                    }
+                }
+            }
+            MbcKind::Mbc3 => {
+                if addr < 0x2000 {
+                    self.ram_enabled = data & 0xF == 0xA;
+                }
+                else if addr < 0x4000 {
+                    self.rom_bank = (data & 0x7F).max(1) as u16;
+                }
+                else if addr < 0x6000 {
+                    self.ram_bank = data;
+                }
+                else if let Some(rtc) = &mut self.rtc {
+                    rtc.write_latch(data);
+                }
+            }
+            MbcKind::Mbc5 => {
+                if addr < 0x2000 {
+                    self.ram_enabled = data & 0xF == 0xA;
+                }
+                else if addr < 0x3000 {
+                    self.rom_bank = (self.rom_bank & 0x100) | data as u16;
+                }
+                else if addr < 0x4000 {
+                    self.rom_bank = (self.rom_bank & 0xFF) | ((data as u16 & 1) << 8);
+                }
+                else if addr < 0x6000 {
+                    self.ram_bank = data & 0xF;
+                }
+            }
+        }
+    }
+
+    // Offset into external RAM for an address in 0xA000-0xBFFF, None if RAM is unmapped
+    fn ram_offset(&self, addr: u16) -> Option<usize> {
+        if self.ram.is_empty() || (self.kind != MbcKind::None && !self.ram_enabled) {
+            return None;
+        }
+        let offset = addr as usize - 0xA000;
+        let bank = match self.kind {
+            MbcKind::Mbc1 => if self.mbc1_mode { self.ram_bank as usize } else { 0 },
+            // Only 0x08-0x0C select the RTC on MBC3, 0-3 select RAM
+            MbcKind::Mbc3 => if self.ram_bank < 4 { self.ram_bank as usize } else { return None },
+            MbcKind::Mbc5 => self.ram_bank as usize,
+            MbcKind::None | MbcKind::Mbc2 => 0,
+        };
+        // Sizes are powers of two, smaller RAMs are mirrored
+        Some((bank * RAM_BANK_SIZE + offset) & (self.ram.len() - 1))
+    }
+
+    pub fn read_ram(&self, addr: u16) -> u8 {
+        if self.rtc_selected() {
+            return match &self.rtc {
+                Some(rtc) if self.ram_enabled => rtc.read(self.ram_bank),
+                _ => 0xFF,
+            };
+        }
+        match self.ram_offset(addr) {
+            // Only the lower nibble of MBC2 RAM exists
+            Some(offset) if self.kind == MbcKind::Mbc2 => self.ram[offset] | 0xF0,
+            Some(offset) => self.ram[offset],
+            None => 0xFF,
+        }
+    }
+
+    pub fn write_ram(&mut self, addr: u16, data: u8) {
+        if self.rtc_selected() {
+            if let Some(rtc) = &mut self.rtc
+                && self.ram_enabled
+            {
+                rtc.write(self.ram_bank, data);
+                self.save_dirty = true;
+            }
+            return;
+        }
+        if let Some(offset) = self.ram_offset(addr) {
+            self.ram[offset] = data;
+            self.save_dirty = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gb::testing::cartridge;
+
+    fn bank_at(cart: &Cartridge, addr: u16) -> u16 {
+        u16::from_le_bytes([cart.read_rom(addr), cart.read_rom(addr + 1)])
+    }
+
+    #[test]
+    fn mbc1_maps_bank_0_as_1_and_uses_upper_bits() {
+        let mut cart = Cartridge::new(&cartridge(0x01, 0x06, 0x00, &[])).unwrap();
+        assert_eq!(bank_at(&cart, 0x4000), 1);
+        cart.write_rom(0x2000, 0x00);
+        assert_eq!(bank_at(&cart, 0x4000), 1);
+        cart.write_rom(0x2000, 0x05);
+        cart.write_rom(0x4000, 0x01);
+        assert_eq!(bank_at(&cart, 0x4000), 0x25);
+        assert_eq!(bank_at(&cart, 0x0000), 0);
+        // Mode 1 also applies the upper bits to 0x0000-0x3FFF
+        cart.write_rom(0x6000, 0x01);
+        assert_eq!(bank_at(&cart, 0x0000), 0x20);
+    }
+
+    #[test]
+    fn mbc1_ram_is_gated_and_banked_in_mode_1() {
+        let mut cart = Cartridge::new(&cartridge(0x03, 0x00, 0x03, &[])).unwrap();
+        cart.write_ram(0xA000, 0x12);
+        assert_eq!(cart.read_ram(0xA000), 0xFF);
+        assert!(!cart.take_save_dirty());
+
+        cart.write_rom(0x0000, 0x0A);
+        cart.write_ram(0xA000, 0x12);
+        cart.write_rom(0x6000, 0x01);
+        cart.write_rom(0x4000, 0x02);
+        cart.write_ram(0xA000, 0x34);
+        assert_eq!(cart.read_ram(0xA000), 0x34);
+        cart.write_rom(0x4000, 0x00);
+        assert_eq!(cart.read_ram(0xA000), 0x12);
+        assert!(cart.take_save_dirty());
+    }
+
+    #[test]
+    fn mbc2_selects_banks_with_address_bit_8_and_keeps_nibbles() {
+        let mut cart = Cartridge::new(&cartridge(0x06, 0x03, 0x00, &[])).unwrap();
+        cart.write_rom(0x2100, 0x05);
+        assert_eq!(bank_at(&cart, 0x4000), 5);
+        // Bit 8 clear is the RAM enable register
+        cart.write_rom(0x2000, 0x0A);
+        assert_eq!(bank_at(&cart, 0x4000), 5);
+        cart.write_ram(0xA000, 0x12);
+        assert_eq!(cart.read_ram(0xA000), 0xF2);
+        // 512 entries mirrored through 0xA000-0xBFFF
+        assert_eq!(cart.read_ram(0xA200), 0xF2);
+    }
+
+    #[test]
+    fn mbc3_uses_7_bit_banks_and_unmaps_ram_past_bank_3() {
+        let mut cart = Cartridge::new(&cartridge(0x13, 0x06, 0x03, &[])).unwrap();
+        cart.write_rom(0x2000, 0xFF);
+        assert_eq!(bank_at(&cart, 0x4000), 0x7F);
+        cart.write_rom(0x2000, 0x00);
+        assert_eq!(bank_at(&cart, 0x4000), 1);
+
+        cart.write_rom(0x0000, 0x0A);
+        cart.write_rom(0x4000, 0x03);
+        cart.write_ram(0xA000, 0x56);
+        assert_eq!(cart.read_ram(0xA000), 0x56);
+        cart.write_rom(0x4000, 0x04);
+        assert_eq!(cart.read_ram(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn mbc5_selects_9_bit_banks_including_0() {
+        let mut cart = Cartridge::new(&cartridge(0x19, 0x08, 0x00, &[])).unwrap();
+        cart.write_rom(0x2000, 0x00);
+        assert_eq!(bank_at(&cart, 0x4000), 0);
+        cart.write_rom(0x2000, 0x23);
+        cart.write_rom(0x3000, 0x01);
+        assert_eq!(bank_at(&cart, 0x4000), 0x123);
+        cart.write_rom(0x3000, 0x00);
+        assert_eq!(bank_at(&cart, 0x4000), 0x23);
+    }
+
+    #[test]
+    fn bank_numbers_wrap_to_the_rom_size() {
+        let mut cart = Cartridge::new(&cartridge(0x19, 0x01, 0x00, &[])).unwrap();
+        cart.write_rom(0x2000, 0x05);
+        assert_eq!(bank_at(&cart, 0x4000), 1);
+    }
+
+    #[test]
+    fn save_data_round_trips_ram_and_the_rtc_footer() {
+        let rom = cartridge(0x10, 0x00, 0x03, &[]);
+        let mut cart = Cartridge::new(&rom).unwrap();
+        assert!(cart.has_battery());
+        cart.write_rom(0x0000, 0x0A);
+        cart.write_rom(0x4000, 0x02);
+        cart.write_ram(0xA123, 0x5A);
+        cart.write_rom(0x4000, 0x0A);
+        cart.write_ram(0xA000, 17);
+        let save = cart.save_data();
+        assert_eq!(save.len(), 4 * RAM_BANK_SIZE + RTC_FOOTER_SIZE);
+
+        let mut restored = Cartridge::new(&rom).unwrap();
+        restored.load_save_data(&save);
+        restored.write_rom(0x0000, 0x0A);
+        restored.write_rom(0x4000, 0x02);
+        assert_eq!(restored.read_ram(0xA123), 0x5A);
+        restored.write_rom(0x4000, 0x0A);
+        assert_eq!(restored.read_ram(0xA000) & 0x1F, 17);
+        assert!(!Cartridge::new(&cartridge(0x01, 0x00, 0x00, &[])).unwrap().has_battery());
+    }
+
+    #[test]
+    fn refuses_unsupported_mappers_and_mis_sized_roms() {
+        let rom = cartridge(0x22, 0x00, 0x00, &[]);
+        assert!(matches!(Cartridge::new(&rom), Err(HeaderError::UnsupportedCartridgeType(0x22))));
+        let mut rom = cartridge(0x00, 0x00, 0x00, &[]);
+        rom.resize(0x10000, 0);
+        assert!(matches!(Cartridge::new(&rom), Err(HeaderError::RomSizeMismatch { declared: 0x8000, actual: 0x10000 })));
+    }
+}
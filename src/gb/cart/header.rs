@@ -0,0 +1,301 @@
+use std::fmt;
+
+// The header occupies 0x0100-0x014F, so a ROM must at least reach the end of it
+const HEADER_END: usize = 0x150;
+
+#[derive(Debug)]
+pub enum HeaderError {
+    Truncated(usize),
+    UnsupportedCartridgeType(u8),
+    InvalidRomSize(u8),
+    InvalidRamSize(u8),
+    RomSizeMismatch { declared: usize, actual: usize },
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaderError::Truncated(len) => write!(f, "ROM is {} bytes, too short to contain a cartridge header", len),
+            HeaderError::UnsupportedCartridgeType(code) => write!(f, "unsupported cartridge type {:#04X}", code),
+            HeaderError::InvalidRomSize(code) => write!(f, "invalid ROM size code {:#04X}", code),
+            HeaderError::InvalidRamSize(code) => write!(f, "invalid RAM size code {:#04X}", code),
+            HeaderError::RomSizeMismatch { declared, actual } => {
+                write!(f, "header declares a {} byte ROM but the file is {} bytes", declared, actual)
+            }
+        }
+    }
+}
+
+impl std::error::Error for HeaderError {}
+
+#[derive(Clone)]
+pub struct CartridgeHeader {
+    pub title: String,
+    // The whole 0x0134-0x0143 area before any decoding, the CGB boot ROM hashes it
+    pub raw_title: [u8; 16],
+    // 4 character code found in the end of the title area of some later cartridges
+    pub manufacturer_code: Option<String>,
+    pub old_licensee_code: u8,
+    // Only meaningful when the old licensee code is 0x33
+    pub new_licensee_code: String,
+    pub cgb_flag: u8,
+    pub sgb_flag: u8,
+    pub cartridge_type: u8,
+    // Declared in the header, file_size is what was actually dumped
+    pub rom_size: usize,
+    pub file_size: usize,
+    pub ram_size: usize,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub computed_header_checksum: u8,
+    pub global_checksum: u16,
+    pub computed_global_checksum: u16,
+}
+
+fn ascii(bytes: &[u8]) -> String {
+    bytes.iter().take_while(|&&b| b != 0).map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '?' }).collect()
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom: &[u8]) -> Result<Self, HeaderError> {
+        if rom.len() < HEADER_END {
+            return Err(HeaderError::Truncated(rom.len()));
+        }
+
+        let rom_size = match rom[0x148] {
+            code @ 0x00..=0x08 => 0x8000 << code,
+            code => return Err(HeaderError::InvalidRomSize(code)),
+        };
+
+        let ram_size = match rom[0x149] {
+            0x00 => 0,
+            0x01 => 0x800,
+            0x02 => 0x2000,
+            0x03 => 0x8000,
+            0x04 => 0x20000,
+            0x05 => 0x10000,
+            code => return Err(HeaderError::InvalidRamSize(code)),
+        };
+
+        // CGB cartridges shortened the title to make room for the manufacturer code and CGB flag
+        let cgb_flag = rom[0x143];
+        let code_area = &rom[0x13F..0x143];
+        let (title, manufacturer_code) = if cgb_flag & 0x80 != 0 && code_area.iter().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit()) {
+            (ascii(&rom[0x134..0x13F]), Some(ascii(code_area)))
+        } else if cgb_flag & 0x80 != 0 {
+            (ascii(&rom[0x134..0x143]), None)
+        } else {
+            (ascii(&rom[0x134..0x144]), None)
+        };
+
+        let computed_header_checksum = rom[0x134..0x14D].iter().fold(0u8, |sum, &b| sum.wrapping_sub(b).wrapping_sub(1));
+        // The global checksum covers every byte except the checksum itself
+        let computed_global_checksum = rom.iter().enumerate()
+            .filter(|&(i, _)| i != 0x14E && i != 0x14F)
+            .fold(0u16, |sum, (_, &b)| sum.wrapping_add(b as u16));
+
+        Ok(Self {
+            title,
+            raw_title: rom[0x134..0x144].try_into().unwrap(),
+            manufacturer_code,
+            old_licensee_code: rom[0x14B],
+            new_licensee_code: ascii(&rom[0x144..0x146]),
+            cgb_flag,
+            sgb_flag: rom[0x146],
+            cartridge_type: rom[0x147],
+            rom_size,
+            file_size: rom.len(),
+            ram_size,
+            version: rom[0x14C],
+            header_checksum: rom[0x14D],
+            computed_header_checksum,
+            global_checksum: u16::from_be_bytes([rom[0x14E], rom[0x14F]]),
+            computed_global_checksum,
+        })
+    }
+
+    pub fn header_checksum_valid(&self) -> bool {
+        self.header_checksum == self.computed_header_checksum
+    }
+
+    pub fn global_checksum_valid(&self) -> bool {
+        self.global_checksum == self.computed_global_checksum
+    }
+
+    // Overdumps and padded files don't match the size the header declares
+    pub fn rom_size_valid(&self) -> bool {
+        self.file_size == self.rom_size
+    }
+
+    pub fn cartridge_type_name(&self) -> &'static str {
+        cartridge_type_name(self.cartridge_type).unwrap_or("UNKNOWN")
+    }
+
+    // Whether the memory bank controller is one the emulator implements
+    pub fn cartridge_type_supported(&self) -> bool {
+        matches!(self.cartridge_type, 0x00..=0x03 | 0x05 | 0x06 | 0x08 | 0x09 | 0x0F..=0x13 | 0x19..=0x1E)
+    }
+
+    pub fn licensee_code(&self) -> String {
+        if self.old_licensee_code == 0x33 {
+            self.new_licensee_code.clone()
+        } else {
+            format!("{:02X}", self.old_licensee_code)
+        }
+    }
+
+    pub fn has_battery(&self) -> bool {
+        matches!(self.cartridge_type, 0x03 | 0x06 | 0x09 | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E)
+    }
+
+    pub fn has_rtc(&self) -> bool {
+        self.cartridge_type == 0x0F || self.cartridge_type == 0x10
+    }
+
+    // Bit 7 of the CGB flag is set by both CGB enhanced (0x80) and CGB only (0xC0) cartridges
+    pub fn supports_cgb(&self) -> bool {
+        self.cgb_flag & 0x80 != 0
+    }
+
+    // The SGB ignores command packets unless the header asks for them, which also needs the new licensee code
+    pub fn supports_sgb(&self) -> bool {
+        self.sgb_flag == 0x03 && self.old_licensee_code == 0x33
+    }
+
+    pub fn is_nintendo(&self) -> bool {
+        self.old_licensee_code == 0x01 || (self.old_licensee_code == 0x33 && self.new_licensee_code == "01")
+    }
+
+    // Wrapping sum of the title area, the CGB boot ROM uses it to pick palettes for DMG games
+    pub fn title_checksum(&self) -> u8 {
+        self.raw_title.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+    }
+}
+
+impl fmt::Display for CartridgeHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let valid = |ok: bool| if ok { "OK" } else { "MISMATCH" };
+        writeln!(f, "Title:            {}", self.title)?;
+        writeln!(f, "Title checksum:   {:#04X}", self.title_checksum())?;
+        if let Some(code) = &self.manufacturer_code {
+            writeln!(f, "Manufacturer:     {}", code)?;
+        }
+        writeln!(f, "Licensee:         {}", self.licensee_code())?;
+        writeln!(f, "CGB flag:         {:#04X}", self.cgb_flag)?;
+        writeln!(f, "SGB flag:         {:#04X}", self.sgb_flag)?;
+        let support = if self.cartridge_type_supported() { "" } else { ", not supported" };
+        writeln!(f, "Cartridge type:   {:#04X} ({}{})", self.cartridge_type, self.cartridge_type_name(), support)?;
+        writeln!(f, "ROM size:         {} KiB (file {} KiB, {})", self.rom_size / 1024, self.file_size / 1024, valid(self.rom_size_valid()))?;
+        writeln!(f, "RAM size:         {} KiB", self.ram_size / 1024)?;
+        writeln!(f, "Version:          {}", self.version)?;
+        writeln!(f, "Header checksum:  {:#04X} (computed {:#04X}, {})", self.header_checksum, self.computed_header_checksum, valid(self.header_checksum_valid()))?;
+        write!(f, "Global checksum:  {:#06X} (computed {:#06X}, {})", self.global_checksum, self.computed_global_checksum, valid(self.global_checksum_valid()))
+    }
+}
+
+// Names of the cartridge types listed in the header documentation
+fn cartridge_type_name(code: u8) -> Option<&'static str> {
+    match code {
+        0x00 => Some("ROM ONLY"),
+        0x01 => Some("MBC1"),
+        0x02 => Some("MBC1+RAM"),
+        0x03 => Some("MBC1+RAM+BATTERY"),
+        0x05 => Some("MBC2"),
+        0x06 => Some("MBC2+BATTERY"),
+        0x08 => Some("ROM+RAM"),
+        0x09 => Some("ROM+RAM+BATTERY"),
+        0x0F => Some("MBC3+TIMER+BATTERY"),
+        0x10 => Some("MBC3+TIMER+RAM+BATTERY"),
+        0x11 => Some("MBC3"),
+        0x12 => Some("MBC3+RAM"),
+        0x13 => Some("MBC3+RAM+BATTERY"),
+        0x19 => Some("MBC5"),
+        0x1A => Some("MBC5+RAM"),
+        0x1B => Some("MBC5+RAM+BATTERY"),
+        0x1C => Some("MBC5+RUMBLE"),
+        0x1D => Some("MBC5+RUMBLE+RAM"),
+        0x1E => Some("MBC5+RUMBLE+RAM+BATTERY"),
+        0x0B => Some("MMM01"),
+        0x0C => Some("MMM01+RAM"),
+        0x0D => Some("MMM01+RAM+BATTERY"),
+        0x20 => Some("MBC6"),
+        0x22 => Some("MBC7+SENSOR+RUMBLE+RAM+BATTERY"),
+        0xFC => Some("POCKET CAMERA"),
+        0xFD => Some("BANDAI TAMA5"),
+        0xFE => Some("HuC3"),
+        0xFF => Some("HuC1+RAM+BATTERY"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gb::testing::{cartridge, fix_checksums};
+
+    // MBC5 cartridge with a CGB title, manufacturer code and new licensee code
+    fn cgb_rom() -> Vec<u8> {
+        let mut rom = cartridge(0x1B, 0x00, 0x03, &[]);
+        rom[0x134..0x13F].copy_from_slice(b"TESTGAME\0\0\0");
+        rom[0x13F..0x143].copy_from_slice(b"ABCD");
+        rom[0x143] = 0x80;
+        rom[0x144..0x146].copy_from_slice(b"01");
+        rom[0x14B] = 0x33;
+        fix_checksums(&mut rom);
+        rom
+    }
+
+    #[test]
+    fn parses_fields_and_accepts_valid_checksums() {
+        let header = CartridgeHeader::parse(&cgb_rom()).unwrap();
+        assert_eq!(header.title, "TESTGAME");
+        assert_eq!(header.manufacturer_code.as_deref(), Some("ABCD"));
+        assert_eq!(header.licensee_code(), "01");
+        assert!(header.is_nintendo());
+        assert_eq!(header.cartridge_type_name(), "MBC5+RAM+BATTERY");
+        assert!(header.has_battery());
+        assert_eq!(header.ram_size, 0x8000);
+        assert!(header.header_checksum_valid());
+        assert!(header.global_checksum_valid());
+        assert!(header.cartridge_type_supported());
+        assert!(header.rom_size_valid());
+    }
+
+    #[test]
+    fn detects_corrupted_checksums() {
+        let mut data = cgb_rom();
+        data[0x134] = b'X';
+        let header = CartridgeHeader::parse(&data).unwrap();
+        assert!(!header.header_checksum_valid());
+        assert!(!header.global_checksum_valid());
+
+        // Bytes outside the header only change the global checksum
+        let mut data = cgb_rom();
+        data[0x4000] = 0xFF;
+        let header = CartridgeHeader::parse(&data).unwrap();
+        assert!(header.header_checksum_valid());
+        assert!(!header.global_checksum_valid());
+    }
+
+    #[test]
+    fn rejects_truncated_and_invalid_headers() {
+        assert!(matches!(CartridgeHeader::parse(&[0; 0x14F]), Err(HeaderError::Truncated(0x14F))));
+        let mut data = cgb_rom();
+        data[0x148] = 0x09;
+        assert!(matches!(CartridgeHeader::parse(&data), Err(HeaderError::InvalidRomSize(0x09))));
+        let mut data = cgb_rom();
+        data[0x149] = 0x06;
+        assert!(matches!(CartridgeHeader::parse(&data), Err(HeaderError::InvalidRamSize(0x06))));
+    }
+
+    #[test]
+    fn inspects_unsupported_and_mis_sized_roms() {
+        let mut data = cgb_rom();
+        data[0x147] = 0x22;
+        data.resize(0x10000, 0);
+        let header = CartridgeHeader::parse(&data).unwrap();
+        assert!(!header.cartridge_type_supported());
+        assert!(!header.rom_size_valid());
+        assert_eq!(header.file_size, 0x10000);
+    }
+}
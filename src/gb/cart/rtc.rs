@@ -0,0 +1,306 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::gb::state::{StateError, StateReader, StateWriter};
+
+// T-cycles per second of the main clock the RTC is stepped with
+const CYCLES_PER_SECOND: u32 = 4_194_304;
+// Size of the RTC footer appended to save files by BGB and VBA
+pub const RTC_FOOTER_SIZE: usize = 48;
+// Older VBA versions stored the timestamp as 32 bits
+pub const RTC_FOOTER_SIZE_32: usize = 44;
+
+// MBC3 real-time clock, registers are selected with RAM bank numbers 0x08-0x0C
+pub struct Rtc {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    // 9 bit day counter
+    days: u16,
+    halt: bool,
+    // Set when the day counter overflows, only cleared by writing DH
+    carry: bool,
+    latched: [u8; 5],
+    // Latching happens on a write of 0x00 followed by 0x01
+    latch_armed: bool,
+    // Sub-second T-cycles
+    cycles: u32,
+    // Follow the host clock instead of emulated cycles
+    host_sync: bool,
+    last_sync: u64,
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl Rtc {
+    pub fn new() -> Self {
+        Self {
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            days: 0,
+            halt: false,
+            carry: false,
+            latched: [0; 5],
+            latch_armed: false,
+            cycles: 0,
+            host_sync: false,
+            last_sync: unix_time(),
+        }
+    }
+
+    pub fn set_host_sync(&mut self, enabled: bool) {
+        self.host_sync = enabled;
+        self.last_sync = unix_time();
+    }
+
+    // Advances the clock by emulated T-cycles
+    pub fn tick(&mut self, cycles: u32) {
+        if self.host_sync || self.halt {
+            return;
+        }
+        self.cycles += cycles;
+        if self.cycles >= CYCLES_PER_SECOND {
+            self.cycles -= CYCLES_PER_SECOND;
+            self.advance(1);
+        }
+    }
+
+    // Catches up with the host clock when host sync is enabled
+    fn sync_host(&mut self) {
+        if !self.host_sync {
+            return;
+        }
+        let now = unix_time();
+        if now > self.last_sync && !self.halt {
+            self.advance(now - self.last_sync);
+        }
+        self.last_sync = now;
+    }
+
+    fn advance(&mut self, seconds: u64) {
+        // Out of range values written by software count up to their bit width before wrapping without carry
+        if self.seconds >= 60 || self.minutes >= 60 || self.hours >= 24 {
+            for _ in 0..seconds {
+                self.step_second();
+            }
+            return;
+        }
+
+        let total = self.seconds as u64 + self.minutes as u64 * 60 + self.hours as u64 * 3600 + self.days as u64 * 86400 + seconds;
+        self.seconds = (total % 60) as u8;
+        self.minutes = (total / 60 % 60) as u8;
+        self.hours = (total / 3600 % 24) as u8;
+        let days = total / 86400;
+        if days > 0x1FF {
+            self.carry = true;
+        }
+        self.days = (days & 0x1FF) as u16;
+    }
+
+    fn step_second(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.days += 1;
+        if self.days > 0x1FF {
+            self.days = 0;
+            self.carry = true;
+        }
+    }
+
+    fn registers(&self) -> [u8; 5] {
+        let day_high = ((self.days >> 8) as u8 & 1) | if self.halt { 0x40 } else { 0 } | if self.carry { 0x80 } else { 0 };
+        [self.seconds, self.minutes, self.hours, self.days as u8, day_high]
+    }
+
+    // Writes to 0x6000-0x7FFF
+    pub fn write_latch(&mut self, data: u8) {
+        if self.latch_armed && data == 1 {
+            self.sync_host();
+            self.latched = self.registers();
+        }
+        self.latch_armed = data == 0;
+    }
+
+    // Reads the latched copy of register 0x08-0x0C
+    pub fn read(&self, reg: u8) -> u8 {
+        let value = self.latched[(reg - 0x08) as usize];
+        match reg {
+            0x08 | 0x09 => value | 0xC0,
+            0x0A => value | 0xE0,
+            0x0B => value,
+            _ => value | 0x3E,
+        }
+    }
+
+    pub fn write(&mut self, reg: u8, data: u8) {
+        self.sync_host();
+        match reg {
+            0x08 => {
+                self.seconds = data & 0x3F;
+                // Writing seconds resets the sub-second divider
+                self.cycles = 0;
+            }
+            0x09 => self.minutes = data & 0x3F,
+            0x0A => self.hours = data & 0x1F,
+            0x0B => self.days = (self.days & 0x100) | data as u16,
+            _ => {
+                self.days = (self.days & 0xFF) | ((data as u16 & 1) << 8);
+                self.halt = data & 0x40 != 0;
+                self.carry = data & 0x80 != 0;
+            }
+        }
+        // Writes are visible in the latched registers straight away
+        self.latched[(reg - 0x08) as usize] = self.registers()[(reg - 0x08) as usize];
+    }
+
+    // Host sync is a frontend setting and stays as it is
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.seconds);
+        w.u8(self.minutes);
+        w.u8(self.hours);
+        w.u16(self.days);
+        w.bool(self.halt);
+        w.bool(self.carry);
+        w.bytes(&self.latched);
+        w.bool(self.latch_armed);
+        w.u32(self.cycles);
+        w.u64(self.last_sync);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.seconds = r.u8()? & 0x3F;
+        self.minutes = r.u8()? & 0x3F;
+        self.hours = r.u8()? & 0x1F;
+        self.days = r.u16()? & 0x1FF;
+        self.halt = r.bool()?;
+        self.carry = r.bool()?;
+        r.bytes(&mut self.latched)?;
+        self.latch_armed = r.bool()?;
+        self.cycles = r.u32()?.min(CYCLES_PER_SECOND - 1);
+        self.last_sync = r.u64()?;
+        Ok(())
+    }
+
+    // Serializes as the 48 byte BGB/VBA footer: live and latched registers as u32 then a u64 timestamp
+    pub fn footer(&mut self) -> Vec<u8> {
+        self.sync_host();
+        let mut footer = Vec::with_capacity(RTC_FOOTER_SIZE);
+        for value in self.registers().iter().chain(self.latched.iter()) {
+            footer.extend_from_slice(&(*value as u32).to_le_bytes());
+        }
+        footer.extend_from_slice(&unix_time().to_le_bytes());
+        footer
+    }
+
+    // Restores a 44 or 48 byte footer, catching up on the time spent away when host sync is enabled
+    pub fn load_footer(&mut self, footer: &[u8]) {
+        let word = |i: usize| u32::from_le_bytes(footer[i * 4..i * 4 + 4].try_into().unwrap()) as u8;
+        self.seconds = word(0) & 0x3F;
+        self.minutes = word(1) & 0x3F;
+        self.hours = word(2) & 0x1F;
+        let day_high = word(4);
+        self.days = word(3) as u16 | ((day_high as u16 & 1) << 8);
+        self.halt = day_high & 0x40 != 0;
+        self.carry = day_high & 0x80 != 0;
+        for i in 0..5 {
+            self.latched[i] = word(5 + i);
+        }
+
+        let saved_at = if footer.len() >= RTC_FOOTER_SIZE {
+            u64::from_le_bytes(footer[40..48].try_into().unwrap())
+        } else {
+            u32::from_le_bytes(footer[40..44].try_into().unwrap()) as u64
+        };
+        self.last_sync = saved_at;
+        if self.host_sync {
+            self.sync_host();
+        } else {
+            self.last_sync = unix_time();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn latch(rtc: &mut Rtc) {
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+    }
+
+    #[test]
+    fn reads_stay_latched_while_the_clock_runs() {
+        let mut rtc = Rtc::new();
+        rtc.tick(CYCLES_PER_SECOND * 3 / 2);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08) & 0x3F, 1);
+        rtc.tick(CYCLES_PER_SECOND);
+        assert_eq!(rtc.read(0x08) & 0x3F, 1);
+        // 0x01 alone does not latch again
+        rtc.write_latch(0x01);
+        assert_eq!(rtc.read(0x08) & 0x3F, 1);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08) & 0x3F, 2);
+    }
+
+    #[test]
+    fn halting_stops_the_clock() {
+        let mut rtc = Rtc::new();
+        rtc.write(0x0C, 0x40);
+        rtc.tick(CYCLES_PER_SECOND * 2);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x08) & 0x3F, 0);
+        assert_eq!(rtc.read(0x0C) & 0x40, 0x40);
+    }
+
+    #[test]
+    fn sets_the_carry_when_the_day_counter_overflows() {
+        let mut rtc = Rtc::new();
+        rtc.write(0x08, 59);
+        rtc.write(0x09, 59);
+        rtc.write(0x0A, 23);
+        rtc.write(0x0B, 0xFF);
+        rtc.write(0x0C, 0x01);
+        rtc.tick(CYCLES_PER_SECOND);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(0x0B), 0);
+        assert_eq!(rtc.read(0x0C) & 0x81, 0x80);
+    }
+
+    #[test]
+    fn restores_live_and_latched_registers_from_the_footer() {
+        let mut rtc = Rtc::new();
+        rtc.write(0x0A, 5);
+        latch(&mut rtc);
+        rtc.write(0x09, 42);
+        rtc.write(0x0B, 0x34);
+        rtc.write(0x0C, 0x41);
+        let footer = rtc.footer();
+        assert_eq!(footer.len(), RTC_FOOTER_SIZE);
+
+        let mut restored = Rtc::new();
+        restored.load_footer(&footer);
+        assert_eq!(restored.registers(), rtc.registers());
+        assert_eq!(restored.latched, rtc.latched);
+        // The 32 bit timestamp variant loads the same registers
+        let mut short = Rtc::new();
+        short.load_footer(&footer[..RTC_FOOTER_SIZE_32]);
+        assert_eq!(short.registers(), rtc.registers());
+    }
+}
@@ -0,0 +1,231 @@
+use crate::gb::state::{StateError, StateReader, StateWriter};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+// Every button at once, true means held down
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ButtonState {
+    pub right: bool,
+    pub left: bool,
+    pub up: bool,
+    pub down: bool,
+    pub a: bool,
+    pub b: bool,
+    pub select: bool,
+    pub start: bool,
+}
+
+impl ButtonState {
+    pub fn is_pressed(&self, button: Button) -> bool {
+        match button {
+            Button::Right => self.right,
+            Button::Left => self.left,
+            Button::Up => self.up,
+            Button::Down => self.down,
+            Button::A => self.a,
+            Button::B => self.b,
+            Button::Select => self.select,
+            Button::Start => self.start,
+        }
+    }
+
+    pub fn set(&mut self, button: Button, pressed: bool) {
+        match button {
+            Button::Right => self.right = pressed,
+            Button::Left => self.left = pressed,
+            Button::Up => self.up = pressed,
+            Button::Down => self.down = pressed,
+            Button::A => self.a = pressed,
+            Button::B => self.b = pressed,
+            Button::Select => self.select = pressed,
+            Button::Start => self.start = pressed,
+        }
+    }
+}
+
+// Controllers an SGB can multiplex onto P1
+pub const MAX_PLAYERS: usize = 4;
+
+// P1 register, both button groups are active low
+pub struct Joypad {
+    // Bits 4-5 of P1, 0 selects the d-pad (bit 4) or buttons (bit 5)
+    select: u8,
+    // Lines of every controller, only the first is read unless an SGB enabled multiplayer
+    buttons: [u8; MAX_PLAYERS],
+    dpad: [u8; MAX_PLAYERS],
+    // Controllers the SGB takes turns between, 1, 2 or 4
+    players: u8,
+    current_player: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Self {
+            select: 0x30,
+            buttons: [0xF; MAX_PLAYERS],
+            dpad: [0xF; MAX_PLAYERS],
+            players: 1,
+            current_player: 0,
+        }
+    }
+
+    // Input lines as seen through the current selection, 0xF when nothing is selected
+    fn lines(&self) -> u8 {
+        let player = self.current_player as usize;
+        let mut lines = 0xF;
+        if self.select & 0x10 == 0 {
+            lines &= self.dpad[player];
+        }
+        if self.select & 0x20 == 0 {
+            lines &= self.buttons[player];
+        }
+        lines
+    }
+
+    // SGB MLT_REQ, starts over at the first controller
+    pub fn set_players(&mut self, players: u8) {
+        self.players = players;
+        self.current_player = 0;
+    }
+
+    // True while a selected line is held low, which ends STOP mode
+    pub fn any_selected_pressed(&self) -> bool {
+        self.lines() != 0xF
+    }
+
+    // In multiplayer mode deselecting both groups reads the current controller's ID, 0xF for the first.
+    // It is only a readback, the ID never wakes the CPU or requests an interrupt
+    pub fn read(&self) -> u8 {
+        if self.select == 0x30 && self.players > 1 {
+            return 0xC0 | self.select | (0xF - self.current_player);
+        }
+        0xC0 | self.select | self.lines()
+    }
+
+    // Returns true if a line went from high to low and the joypad interrupt should be requested
+    pub fn write(&mut self, data: u8) -> bool {
+        let before = self.lines();
+        // The SGB moves on to the next controller when P15 goes back high
+        if self.select & 0x20 == 0 && data & 0x20 != 0 {
+            self.current_player = (self.current_player + 1) % self.players;
+        }
+        self.select = data & 0x30;
+        self.falling_edge(before)
+    }
+
+    // Buttons of one controller, 0 being the one plugged into the Game Boy itself.
+    // Returns true if a line went from high to low and the joypad interrupt should be requested
+    pub fn set_input(&mut self, player: usize, state: ButtonState) -> bool {
+        let before = self.lines();
+        let low = |pressed: bool, bit: u8| if pressed { 0 } else { bit };
+        self.dpad[player] = low(state.right, 0x1) | low(state.left, 0x2) | low(state.up, 0x4) | low(state.down, 0x8);
+        self.buttons[player] = low(state.a, 0x1) | low(state.b, 0x2) | low(state.select, 0x4) | low(state.start, 0x8);
+        self.falling_edge(before)
+    }
+
+    pub fn state(&self, player: usize) -> ButtonState {
+        let (dpad, buttons) = (self.dpad[player], self.buttons[player]);
+        ButtonState {
+            right: dpad & 0x1 == 0,
+            left: dpad & 0x2 == 0,
+            up: dpad & 0x4 == 0,
+            down: dpad & 0x8 == 0,
+            a: buttons & 0x1 == 0,
+            b: buttons & 0x2 == 0,
+            select: buttons & 0x4 == 0,
+            start: buttons & 0x8 == 0,
+        }
+    }
+
+    fn falling_edge(&self, before: u8) -> bool {
+        before & !self.lines() != 0
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.select);
+        w.bytes(&self.buttons);
+        w.bytes(&self.dpad);
+        w.u8(self.players);
+        w.u8(self.current_player);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.select = r.u8()? & 0x30;
+        r.bytes(&mut self.buttons)?;
+        r.bytes(&mut self.dpad)?;
+        self.buttons = self.buttons.map(|lines| lines & 0xF);
+        self.dpad = self.dpad.map(|lines| lines & 0xF);
+        self.players = match r.u8()? {
+            players @ (1 | 2 | 4) => players,
+            _ => return Err(StateError::Invalid("joypad player count")),
+        };
+        self.current_player = r.u8()? % self.players;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_selected_group() {
+        let mut joypad = Joypad::new();
+        assert!(!joypad.set_input(0, ButtonState { a: true, up: true, ..Default::default() }));
+        assert!(joypad.write(0x10));
+        assert_eq!(joypad.read(), 0xDE);
+        joypad.write(0x20);
+        assert_eq!(joypad.read(), 0xEB);
+        assert!(joypad.any_selected_pressed());
+        joypad.write(0x30);
+        assert_eq!(joypad.read(), 0xFF);
+        assert!(!joypad.any_selected_pressed());
+    }
+
+    #[test]
+    fn requests_an_interrupt_when_a_selected_line_goes_low() {
+        let mut joypad = Joypad::new();
+        joypad.write(0x20);
+        assert!(!joypad.set_input(0, ButtonState { a: true, ..Default::default() }));
+        assert!(joypad.set_input(0, ButtonState { a: true, down: true, ..Default::default() }));
+        // Releasing is a rising edge
+        assert!(!joypad.set_input(0, ButtonState::default()));
+        assert_eq!(joypad.state(0), ButtonState::default());
+    }
+
+    #[test]
+    fn cycles_through_controller_ids_without_waking_the_cpu() {
+        let mut joypad = Joypad::new();
+        joypad.set_players(4);
+        joypad.set_input(2, ButtonState { up: true, ..Default::default() });
+        let mut ids = Vec::new();
+        for _ in 0..4 {
+            assert!(!joypad.write(0x30));
+            ids.push(joypad.read() & 0xF);
+            // The ID is not a pressed button
+            assert!(!joypad.any_selected_pressed());
+            // Pulsing P15 moves on to the next controller
+            assert!(!joypad.write(0x10));
+            assert!(!joypad.write(0x30));
+        }
+        assert_eq!(ids, [0xF, 0xE, 0xD, 0xC]);
+
+        // Third controller, d-pad selected
+        joypad.write(0x10);
+        joypad.write(0x30);
+        joypad.write(0x10);
+        joypad.write(0x30);
+        assert!(joypad.write(0x20));
+        assert_eq!(joypad.read() & 0xF, 0xB);
+    }
+}
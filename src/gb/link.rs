@@ -0,0 +1,383 @@
+use std::cell::RefCell;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::gb::serial::{SerialDevice, TRANSFER_CYCLES};
+use crate::gb::Gameboy;
+
+// Neither side may run more than this many T-cycles ahead of the other
+const SYNC_INTERVAL: u64 = 8192;
+// Type byte, u64 cycle, data byte
+const MESSAGE_SIZE: usize = 10;
+
+const MSG_SYNC: u8 = 0;
+const MSG_TRANSFER: u8 = 1;
+const MSG_REPLY: u8 = 2;
+
+// Bookkeeping for one end of the cable, shared by the in-process and socket links
+struct Endpoint {
+    cycle: u64,
+    // Cycle of the last poll_external call and the byte that was in SB, i.e. an external clock transfer is armed
+    last_poll: Option<(u64, u8)>,
+    // Byte clocked in by the peer and the cycle the transfer completes at
+    inbound: Option<(u64, u8)>,
+}
+
+impl Endpoint {
+    fn new() -> Self {
+        Self {
+            cycle: 0,
+            last_poll: None,
+            inbound: None,
+        }
+    }
+
+    // SB of this side if it was waiting for an external clock during the last M-cycle
+    fn waiting_byte(&self) -> Option<u8> {
+        match self.last_poll {
+            Some((cycle, byte)) if cycle + 4 >= self.cycle => Some(byte),
+            _ => None,
+        }
+    }
+
+    // The peer started a transfer at master_cycle, returns the byte it receives in exchange
+    fn receive(&mut self, master_cycle: u64, byte: u8) -> u8 {
+        match self.waiting_byte() {
+            Some(outgoing) => {
+                self.inbound = Some((master_cycle + TRANSFER_CYCLES, byte));
+                outgoing
+            }
+            // Nobody listening, the line stays high
+            None => 0xFF,
+        }
+    }
+
+    fn poll(&mut self, outgoing: u8) -> Option<u8> {
+        self.last_poll = Some((self.cycle, outgoing));
+        match self.inbound {
+            Some((due, byte)) if self.cycle >= due => {
+                self.inbound = None;
+                self.last_poll = None;
+                Some(byte)
+            }
+            _ => None,
+        }
+    }
+}
+
+// Both ends of an in-process cable
+struct CableState {
+    ends: [Endpoint; 2],
+}
+
+// One end of a cable between two Gameboys in the same process, created with cable()
+pub struct LocalLink {
+    state: Rc<RefCell<CableState>>,
+    side: usize,
+}
+
+pub fn cable() -> (LocalLink, LocalLink) {
+    let state = Rc::new(RefCell::new(CableState { ends: [Endpoint::new(), Endpoint::new()] }));
+    (LocalLink { state: state.clone(), side: 0 }, LocalLink { state, side: 1 })
+}
+
+impl SerialDevice for LocalLink {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        let mut state = self.state.borrow_mut();
+        let cycle = state.ends[self.side].cycle;
+        state.ends[1 - self.side].receive(cycle, outgoing)
+    }
+
+    fn poll_external(&mut self, outgoing: u8) -> Option<u8> {
+        self.state.borrow_mut().ends[self.side].poll(outgoing)
+    }
+
+    fn clock(&mut self, cycles: u32) {
+        self.state.borrow_mut().ends[self.side].cycle += cycles as u64;
+    }
+}
+
+// Second Gameboy in the same process, wired to the first with a cable and stepped in lockstep with it.
+// Deterministic, so two games can be linked without any sockets
+pub struct LocalPeer {
+    gameboy: Gameboy,
+    state: Rc<RefCell<CableState>>,
+}
+
+impl LocalPeer {
+    pub fn new(first: &mut Gameboy, mut second: Gameboy) -> Self {
+        let (a, b) = cable();
+        let state = a.state.clone();
+        first.connect_serial(Box::new(a));
+        second.connect_serial(Box::new(b));
+        Self { gameboy: second, state }
+    }
+
+    pub fn gameboy(&self) -> &Gameboy {
+        &self.gameboy
+    }
+
+    pub fn gameboy_mut(&mut self) -> &mut Gameboy {
+        &mut self.gameboy
+    }
+
+    // Runs both machines until the first one finishes a frame, always stepping whichever is behind
+    pub fn run_frame(&mut self, first: &mut Gameboy) {
+        loop {
+            let (first_cycle, second_cycle) = {
+                let state = self.state.borrow();
+                (state.ends[0].cycle, state.ends[1].cycle)
+            };
+            if first_cycle <= second_cycle {
+                if first.step() {
+                    return;
+                }
+            } else {
+                self.gameboy.step();
+            }
+        }
+    }
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+// Link cable to another emulator process over a TCP or Unix domain socket.
+// Both sides exchange their cycle counts every SYNC_INTERVAL and wait for the other to catch up,
+// so a transfer started by the master completes on the slave at the matching cycle
+pub struct NetworkLink {
+    stream: Option<Stream>,
+    endpoint: Endpoint,
+    peer_cycle: u64,
+    next_sync: u64,
+    // Partially received message bytes
+    pending: Vec<u8>,
+    // Reply to our own transfer, set while waiting for it
+    reply: Option<u8>,
+}
+
+impl NetworkLink {
+    fn new(stream: Stream) -> io::Result<Self> {
+        if let Stream::Tcp(tcp) = &stream {
+            tcp.set_nodelay(true)?;
+        }
+        stream.set_nonblocking(true)?;
+        Ok(Self {
+            stream: Some(stream),
+            endpoint: Endpoint::new(),
+            peer_cycle: 0,
+            next_sync: 0,
+            pending: Vec::new(),
+            reply: None,
+        })
+    }
+
+    // Waits for the other emulator to connect
+    pub fn listen_tcp(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let (stream, _) = TcpListener::bind(addr)?.accept()?;
+        Self::new(Stream::Tcp(stream))
+    }
+
+    pub fn connect_tcp(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Self::new(Stream::Tcp(TcpStream::connect(addr)?))
+    }
+
+    #[cfg(unix)]
+    pub fn listen_unix(path: impl AsRef<Path>) -> io::Result<Self> {
+        let (stream, _) = UnixListener::bind(path)?.accept()?;
+        Self::new(Stream::Unix(stream))
+    }
+
+    #[cfg(unix)]
+    pub fn connect_unix(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(Stream::Unix(UnixStream::connect(path)?))
+    }
+
+    fn send(&mut self, kind: u8, cycle: u64, byte: u8) {
+        let mut message = [0; MESSAGE_SIZE];
+        message[0] = kind;
+        message[1..9].copy_from_slice(&cycle.to_le_bytes());
+        message[9] = byte;
+
+        let Some(stream) = &mut self.stream else {
+            return;
+        };
+        // Messages are tiny, write them out in blocking mode so they are never split by WouldBlock
+        let sent = stream.set_nonblocking(false).and_then(|_| stream.write_all(&message)).and_then(|_| stream.set_nonblocking(true));
+        if sent.is_err() {
+            self.disconnect();
+        }
+    }
+
+    // Reads whatever has arrived, blocking until at least one message is handled if block is set
+    fn receive(&mut self, block: bool) {
+        let Some(stream) = &mut self.stream else {
+            return;
+        };
+        if block && stream.set_nonblocking(false).is_err() {
+            self.disconnect();
+            return;
+        }
+
+        let mut buffer = [0; 256];
+        let result = stream.read(&mut buffer);
+        if block && stream.set_nonblocking(true).is_err() {
+            self.disconnect();
+            return;
+        }
+        match result {
+            Ok(0) => {
+                self.disconnect();
+                return;
+            }
+            Ok(count) => self.pending.extend_from_slice(&buffer[..count]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => return,
+            Err(_) => {
+                self.disconnect();
+                return;
+            }
+        }
+
+        while self.pending.len() >= MESSAGE_SIZE {
+            let message: Vec<u8> = self.pending.drain(..MESSAGE_SIZE).collect();
+            let cycle = u64::from_le_bytes(message[1..9].try_into().unwrap());
+            let byte = message[9];
+            match message[0] {
+                MSG_SYNC => self.peer_cycle = cycle,
+                MSG_TRANSFER => {
+                    self.peer_cycle = self.peer_cycle.max(cycle);
+                    let reply = self.endpoint.receive(cycle, byte);
+                    self.send(MSG_REPLY, self.endpoint.cycle, reply);
+                }
+                _ => self.reply = Some(byte),
+            }
+        }
+    }
+
+    // Peer went away, behave like an unplugged cable from now on
+    fn disconnect(&mut self) {
+        self.stream = None;
+        self.pending.clear();
+    }
+}
+
+impl SerialDevice for NetworkLink {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        self.send(MSG_TRANSFER, self.endpoint.cycle, outgoing);
+        self.reply = None;
+        while self.reply.is_none() && self.stream.is_some() {
+            self.receive(true);
+        }
+        self.reply.take().unwrap_or(0xFF)
+    }
+
+    fn poll_external(&mut self, outgoing: u8) -> Option<u8> {
+        self.receive(false);
+        self.endpoint.poll(outgoing)
+    }
+
+    fn clock(&mut self, cycles: u32) {
+        self.endpoint.cycle += cycles as u64;
+        if self.endpoint.cycle < self.next_sync {
+            return;
+        }
+        self.next_sync = self.endpoint.cycle + SYNC_INTERVAL;
+        self.send(MSG_SYNC, self.endpoint.cycle, 0);
+        self.receive(false);
+        // Too far ahead, wait for the peer
+        while self.stream.is_some() && self.endpoint.cycle > self.peer_cycle + SYNC_INTERVAL {
+            self.receive(true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gb::testing::rom;
+
+    fn gameboy(code: &[u8]) -> Gameboy {
+        let mut gameboy = Gameboy::new();
+        gameboy.load_rom(&rom(code)).unwrap();
+        gameboy
+    }
+
+    #[test]
+    fn completes_the_transfer_on_the_listening_side_after_8_bits() {
+        let (mut master, mut slave) = cable();
+        assert_eq!(slave.poll_external(0x34), None);
+        assert_eq!(master.transfer(0x12), 0x34);
+        slave.clock(TRANSFER_CYCLES as u32 - 4);
+        assert_eq!(slave.poll_external(0x34), None);
+        slave.clock(4);
+        assert_eq!(slave.poll_external(0x34), Some(0x12));
+    }
+
+    #[test]
+    fn reads_ff_when_nobody_is_listening() {
+        let (mut master, _slave) = cable();
+        assert_eq!(master.transfer(0x12), 0xFF);
+    }
+
+    #[test]
+    fn exchanges_bytes_between_linked_gameboys() {
+        // Waits for the other side to arm its transfer, then starts one on the internal clock
+        let mut first = gameboy(&[0x3E, 0x12, 0xE0, 0x01, 0x06, 0x00, 0x05, 0x20, 0xFD, 0x3E, 0x81, 0xE0, 0x02, 0x18, 0xFE]);
+        // Waits on the external clock
+        let second = gameboy(&[0x3E, 0x34, 0xE0, 0x01, 0x3E, 0x80, 0xE0, 0x02, 0x18, 0xFE]);
+        let mut peer = LocalPeer::new(&mut first, second);
+        peer.run_frame(&mut first);
+        peer.run_frame(&mut first);
+
+        assert_eq!(first.mem.read(0xFF01), 0x34);
+        assert_eq!(peer.gameboy_mut().mem.read(0xFF01), 0x12);
+        assert_eq!(first.mem.read(0xFF02) & 0x80, 0);
+        assert_eq!(peer.gameboy_mut().mem.read(0xFF02) & 0x80, 0);
+    }
+}
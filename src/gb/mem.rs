@@ -1,17 +1,34 @@
+use crate::gb::apu::Audio;
+use crate::gb::boot::Model;
+use crate::gb::cart::Cartridge;
+use crate::gb::cart::header::HeaderError;
+use crate::gb::joypad::{ButtonState, Joypad};
 use crate::gb::ppu::Video;
+use crate::gb::serial::{Serial, SerialDevice};
+use crate::gb::sgb::Sgb;
+use crate::gb::state::{StateError, StateReader, StateWriter};
 
 pub struct Memory {
     ppu: Video,
+    apu: Audio,
+    serial: Serial,
     sys_clock: u16,
-    // Split into 2 0x4000 arrays when implementing MBCs
-    rom_bank: [u8; 0x8000],
-    sram: [u8; 0x2000],
-    // Split into 2 0x1000 arrays if upgrading to CGB
-    wram: [u8; 0x2000],
+    // Mapped over the start of the cartridge ROM until 0xFF50 is written
+    boot_rom: Vec<u8>,
+    boot_rom_mapped: bool,
+    cart: Cartridge,
+    // CGB mode, enables the banking and speed registers
+    cgb: bool,
+    // Eight 0x1000 banks, bank 0 is fixed at 0xC000 and 0xD000 maps bank 1-7 in CGB mode
+    wram: [u8; 0x8000],
+    wram_bank: u8,
+    // KEY1 bit 0, a speed switch happens on the next STOP
+    speed_switch_armed: bool,
+    double_speed: bool,
     hram: [u8; 0x7F],
-    joypad: u8,
-    joypad_buttons: u8,
-    joypad_dpad: u8,
+    joypad: Joypad,
+    // Only present on the SGB, listens to P1 for command packets
+    sgb: Option<Sgb>,
     tima: u8,
     tma: u8,
     tac: u8,
@@ -22,20 +39,38 @@ pub struct Memory {
     dma: u8,
     dma_trigger: bool,
     dma_counter: u8,
+    // CGB VRAM DMA, source and destination advance by 16 bytes per block
+    hdma_source: u16,
+    // Offset into VRAM
+    hdma_dest: u16,
+    // Blocks left minus one, as read back from HDMA5
+    hdma_remaining: u8,
+    // An HBlank DMA is copying one block per HBlank
+    hdma_active: bool,
+    // M-cycles the CPU still has to wait for blocks already copied
+    hdma_stall: u32,
+    // Mirrors the CPU's HALT state, HBlank DMA doesn't copy anything while it is set
+    cpu_halted: bool,
 }
 
 impl Memory {
-    pub fn new() -> Self {
+    pub fn new(model: Model) -> Self {
         Self {
             ppu: Video::new(),
-            sys_clock: 0xAB00, 
-            rom_bank: [0; 0x8000],
-            sram: [0; 0x2000],
-            wram: [0; 0x2000],
+            apu: Audio::new(),
+            serial: Serial::new(),
+            sys_clock: model.post_boot_sys_clock(),
+            boot_rom: Vec::new(),
+            boot_rom_mapped: false,
+            cart: Cartridge::empty(),
+            cgb: false,
+            wram: [0; 0x8000],
+            wram_bank: 0,
+            speed_switch_armed: false,
+            double_speed: false,
             hram: [0; 0x7F],
-            joypad: 0xCF,
-            joypad_buttons: 0xF,
-            joypad_dpad: 0xF,
+            joypad: Joypad::new(),
+            sgb: (model == Model::Sgb).then(Sgb::new),
             tima: 0,
             tma: 0,
             tac: 0xF8,
@@ -46,23 +81,240 @@ impl Memory {
             dma: 0xFF,
             dma_trigger: false,
             dma_counter: 0,
+            hdma_source: 0,
+            hdma_dest: 0,
+            hdma_remaining: 0x7F,
+            hdma_active: false,
+            hdma_stall: 0,
+            cpu_halted: false,
         }
     }
 
+    // M-cycle spent in STOP, only the screen and the link cable's clock keep going so linked peers don't wait forever
+    pub fn tick_stopped(&mut self) {
+        for _x in 0..self.dots_per_m_cycle() {
+            self.tick_ppu();
+        }
+        self.serial.clock(self.dots_per_m_cycle());
+    }
+
     pub fn tick_ppu(&mut self) {
         self.ppu.tick();
+        self.if_reg |= self.ppu.take_interrupts();
+        if self.ppu.take_hblank() && self.hdma_active && !self.cpu_halted {
+            self.hdma_block();
+        }
+    }
+
+    pub fn set_cpu_halted(&mut self, halted: bool) {
+        self.cpu_halted = halted;
+    }
+
+    pub fn set_cgb_mode(&mut self, enabled: bool) {
+        self.cgb = enabled;
+        self.wram_bank = 0;
+        self.speed_switch_armed = false;
+        self.double_speed = false;
+        self.ppu.set_cgb_mode(enabled);
+    }
+
+    pub fn cgb_mode(&self) -> bool {
+        self.cgb
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    // PPU dots per M-cycle, the PPU keeps its pace when the CPU runs at double speed
+    pub fn dots_per_m_cycle(&self) -> u32 {
+        if self.double_speed { 2 } else { 4 }
+    }
+
+    // Called on STOP, switches speed if KEY1 was armed and returns whether it did
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.speed_switch_armed {
+            return false;
+        }
+        self.speed_switch_armed = false;
+        self.double_speed = !self.double_speed;
+        true
+    }
+
+    // Returns true while the CPU is held by a VRAM DMA, consuming one M-cycle of the wait
+    pub fn hdma_stalled(&mut self) -> bool {
+        if self.hdma_stall == 0 {
+            return false;
+        }
+        self.hdma_stall -= 1;
+        true
+    }
+
+    // Copies one 16 byte block into the selected VRAM bank. The CPU is held for 32 dots,
+    // 8 M-cycles at normal speed and 16 at double speed
+    fn hdma_block(&mut self) {
+        for offset in 0..16 {
+            let data = self.read(self.hdma_source.wrapping_add(offset));
+            self.ppu.write(0x8000 | ((self.hdma_dest + offset) & 0x1FFF), data);
+        }
+        self.hdma_source = self.hdma_source.wrapping_add(16);
+        self.hdma_dest = (self.hdma_dest + 16) & 0x1FF0;
+        self.hdma_stall += 32 / self.dots_per_m_cycle();
+
+        self.hdma_remaining = self.hdma_remaining.wrapping_sub(1) & 0x7F;
+        if self.hdma_remaining == 0x7F {
+            self.hdma_active = false;
+        }
+    }
+
+    // HDMA5 write, starts a general purpose DMA, starts an HBlank DMA or cancels the running one
+    fn start_hdma(&mut self, data: u8) {
+        if self.hdma_active && data & 0x80 == 0 {
+            self.hdma_active = false;
+            return;
+        }
+        self.hdma_remaining = data & 0x7F;
+        if data & 0x80 != 0 {
+            self.hdma_active = true;
+            // No HBlank will come with the LCD off, the first block is copied right away
+            if !self.ppu.lcd_enabled() {
+                self.hdma_block();
+            }
+        } else {
+            // General purpose DMA copies everything at once
+            self.hdma_active = false;
+            for _ in 0..=self.hdma_remaining {
+                self.hdma_block();
+            }
+        }
+    }
+
+    // Index into wram of a 0xC000-0xDFFF address, bank 0 in 0xD000 selects bank 1
+    fn wram_index(&self, addr: u16) -> usize {
+        let offset = (addr & 0x0FFF) as usize;
+        if addr < 0xD000 {
+            offset
+        } else {
+            self.wram_bank.max(1) as usize * 0x1000 + offset
+        }
+    }
+
+    // Maps the boot ROM and puts the hardware into its power-on state for it to initialise
+    pub fn map_boot_rom(&mut self, data: &[u8]) {
+        self.boot_rom = data.to_vec();
+        self.boot_rom_mapped = true;
+        self.sys_clock = 0;
+        self.if_reg = 0xE0;
+        self.ppu.power_on();
+        // Powering the APU off clears every register, the boot ROM turns it back on for the chime
+        self.apu.write(0xFF26, 0x00);
+    }
+
+    // The boot ROM itself is not stored, only whether it is still mapped
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.sys_clock);
+        w.bool(self.boot_rom_mapped);
+        w.bool(self.cgb);
+        w.bytes(&self.wram);
+        w.u8(self.wram_bank);
+        w.bool(self.speed_switch_armed);
+        w.bool(self.double_speed);
+        w.bytes(&self.hram);
+        w.u8(self.tima);
+        w.u8(self.tma);
+        w.u8(self.tac);
+        w.bool(self.timer_and);
+        w.bool(self.tima_overflowed);
+        w.u8(self.if_reg);
+        w.u8(self.ie_reg);
+        w.u8(self.dma);
+        w.bool(self.dma_trigger);
+        w.u8(self.dma_counter);
+        w.u16(self.hdma_source);
+        w.u16(self.hdma_dest);
+        w.u8(self.hdma_remaining);
+        w.bool(self.hdma_active);
+        w.u32(self.hdma_stall);
+        self.joypad.save_state(w);
+        self.serial.save_state(w);
+        self.cart.save_state(w);
+        self.ppu.save_state(w);
+        self.apu.save_state(w);
+        w.bool(self.sgb.is_some());
+        if let Some(sgb) = &self.sgb {
+            sgb.save_state(w);
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.sys_clock = r.u16()?;
+        self.boot_rom_mapped = r.bool()?;
+        if self.boot_rom_mapped && self.boot_rom.is_empty() {
+            return Err(StateError::Invalid("state was saved while the boot ROM was running, load the boot ROM first"));
+        }
+        if r.bool()? != self.cgb {
+            return Err(StateError::Invalid("state was saved with CGB mode set differently"));
+        }
+        r.bytes(&mut self.wram)?;
+        self.wram_bank = r.u8()? & 0b111;
+        self.speed_switch_armed = r.bool()?;
+        self.double_speed = r.bool()?;
+        r.bytes(&mut self.hram)?;
+        self.tima = r.u8()?;
+        self.tma = r.u8()?;
+        self.tac = r.u8()? | 0xF8;
+        self.timer_and = r.bool()?;
+        self.tima_overflowed = r.bool()?;
+        self.if_reg = r.u8()? | 0xE0;
+        self.ie_reg = r.u8()?;
+        self.dma = r.u8()?;
+        self.dma_trigger = r.bool()?;
+        self.dma_counter = r.u8()?.min(159);
+        self.hdma_source = r.u16()? & 0xFFF0;
+        self.hdma_dest = r.u16()? & 0x1FF0;
+        self.hdma_remaining = r.u8()? & 0x7F;
+        self.hdma_active = r.bool()?;
+        self.hdma_stall = r.u32()?;
+        // At most a whole 128 block general purpose DMA at double speed
+        if self.hdma_stall > 0x80 * 16 {
+            return Err(StateError::Invalid("HDMA stall out of range"));
+        }
+        self.joypad.load_state(r)?;
+        self.serial.load_state(r)?;
+        self.cart.load_state(r)?;
+        self.ppu.load_state(r)?;
+        self.apu.load_state(r)?;
+        if r.bool()? != self.sgb.is_some() {
+            return Err(StateError::Invalid("state was saved on a different model"));
+        }
+        if let Some(sgb) = &mut self.sgb {
+            sgb.load_state(r)?;
+        }
+        Ok(())
+    }
+
+    pub fn ppu(&self) -> &Video {
+        &self.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut Video {
+        &mut self.ppu
     }
 
     pub fn read(&mut self, addr: u16) -> u8 {
         let index = addr as usize;
         // Unused Addresses
-        if addr == 0xFF03 || (0xFF08 <= addr) && (addr <= 0xFF0E) || addr == 0xFF15 || addr == 0xFF1F || (0xFF27 <= addr) && (addr <= 0xFF2F) ||
-            (0xFF4C <= addr) && (addr <= 0xFF4E) || (0xFF56 <= addr) && (addr <= 0xFF67) || (0xFF6C <= addr) && (addr <= 0xFF6F) {
+        if addr == 0xFF03 || (0xFF08..=0xFF0E).contains(&addr) || addr == 0xFF15 || addr == 0xFF1F || (0xFF27..=0xFF2F).contains(&addr) ||
+            addr == 0xFF4C || addr == 0xFF4E || (0xFF56..=0xFF67).contains(&addr) || (0xFF6C..=0xFF6F).contains(&addr) {
                 0xFF
             }
+        // Boot ROM, the CGB one leaves a gap for the cartridge header
+        else if self.boot_rom_mapped && index < self.boot_rom.len() && !(0x100..0x200).contains(&index) {
+            self.boot_rom[index]
+        }
         // ROM
         else if addr < 0x8000 {
-            self.rom_bank[index]
+            self.cart.read_rom(addr)
         }
         // VRAM
         else if addr < 0xA000 {
@@ -70,15 +322,15 @@ impl Memory {
         }
         // SRAM
         else if addr < 0xC000 {
-            self.sram[index - 0xA000]
+            self.cart.read_ram(addr)
         }
         // WRAM
         else if addr < 0xE000 {
-            self.wram[index - 0xC000]
+            self.wram[self.wram_index(addr)]
         }
         // Echo RAM
         else if addr < 0xFE00 {
-            self.wram[index - 0xE000]
+            self.wram[self.wram_index(addr - 0x2000)]
         }
         // OAM
         else if addr < 0xFEA0 {
@@ -93,33 +345,12 @@ impl Memory {
         else if addr < 0xFF80 {
             // Joypad
             if addr == 0xFF00 {
-                let select = (self.joypad >> 4) & 0b0011;
-                // None
-                if select == 0b11 {
-                    0xF
-                }
-                // D-Pad
-                else if select == 0b10 {
-                    0xE0 | self.joypad_dpad
-                }
-                // Buttons
-                else if select == 0b01 {
-                    0xD0 | self.joypad_buttons
-                }
-                // Both
-                else {
-                    0xC0 | (self.joypad_buttons & self.joypad_dpad)
-                }
+                self.joypad.read()
             }
 
              // Serial
-            else if addr == 0xFF01 {
-                // TODO
-                0xFF
-            }
-            else if addr == 0xFF02 {
-                // TODO
-                0xFF
+            else if addr == 0xFF01 || addr == 0xFF02 {
+                self.serial.read(addr)
             }
 
             // Timers
@@ -145,15 +376,9 @@ impl Memory {
                 self.if_reg
             }
 
-            // Audio
-            else if addr < 0xFF27 {
-                // TODO
-                0xFF
-            }
-            // Wave Pattern
+            // Audio and Wave Pattern
             else if addr < 0xFF40 {
-                // TODO
-                0xFF
+                self.apu.read(addr)
             }
 
             // LCD
@@ -165,28 +390,45 @@ impl Memory {
                 }
             }
 
+            // Speed Switch
+            else if addr == 0xFF4D {
+                if self.cgb {
+                    0x7E | (self.double_speed as u8) << 7 | self.speed_switch_armed as u8
+                } else {
+                    0xFF
+                }
+            }
+
             // VRAM Bank Select
             else if addr == 0xFF4F {
-                // TODO if upgrading to CGB
+                self.ppu.read(addr)
+            }
+
+            // Boot ROM disable, write only
+            else if addr == 0xFF50 {
                 0xFF
             }
 
-            // VRAM DMA
+            // VRAM DMA, only the remaining length can be read back
+            else if addr == 0xFF55 && self.cgb {
+                if self.hdma_active { self.hdma_remaining } else { 0x80 | self.hdma_remaining }
+            }
             else if addr < 0xFF56 {
-                // TODO if upgrading to CGB
                 0xFF
             }
 
             // Color Palettes
             else if addr < 0xFF6C {
-                // TODO if upgrading to CGB
-                0xFF
+                self.ppu.read(addr)
             }
 
             // WRAM Bank Select
             else if addr == 0xFF70 {
-                // TODO if upgrading to CGB
-                0xFF
+                if self.cgb {
+                    0xF8 | self.wram_bank
+                } else {
+                    0xFF
+                }
             }
 
             else {
@@ -206,27 +448,31 @@ impl Memory {
     pub fn write(&mut self, addr: u16, data: u8) {
         let index = addr as usize;
         // Unused Addresses
-        if addr == 0xFF03 || (0xFF08 <= addr) && (addr <= 0xFF0E) || addr == 0xFF15 || addr == 0xFF1F || (0xFF27 <= addr) && (addr <= 0xFF2F) ||
-            (0xFF4C <= addr) && (addr <= 0xFF4E) || (0xFF56 <= addr) && (addr <= 0xFF67) || (0xFF6C <= addr) && (addr <= 0xFF6F) {
+        if addr == 0xFF03 || (0xFF08..=0xFF0E).contains(&addr) || addr == 0xFF15 || addr == 0xFF1F || (0xFF27..=0xFF2F).contains(&addr) ||
+            addr == 0xFF4C || addr == 0xFF4E || (0xFF56..=0xFF67).contains(&addr) || (0xFF6C..=0xFF6F).contains(&addr) {
                 // Do nothing
             }
-        // ROM (read-only)
-        if addr < 0x8000 {}
+        // ROM (MBC registers)
+        else if addr < 0x8000 {
+            self.cart.write_rom(addr, data);
+        }
         // VRAM
         else if addr < 0xA000 {
             self.ppu.write(addr, data);
         }
         // SRAM
         else if addr < 0xC000 {
-            self.sram[index - 0xA000] = data;
+            self.cart.write_ram(addr, data);
         }
         // WRAM
         else if addr < 0xE000 {
-            self.wram[index - 0xC000] = data;
+            let index = self.wram_index(addr);
+            self.wram[index] = data;
         }
         // Echo RAM
         else if addr < 0xFE00 {
-            self.wram[index - 0xE000] = data;
+            let index = self.wram_index(addr - 0x2000);
+            self.wram[index] = data;
         }
         // OAM
         else if addr < 0xFEA0 {
@@ -238,51 +484,52 @@ impl Memory {
         else if addr < 0xFF80 {
             // Joypad
             if addr == 0xFF00 {
-                self.joypad = (data & 0x30) | 0xC0;
+                if self.joypad.write(data) {
+                    self.if_reg |= 0b10000;
+                }
+                if let Some(sgb) = &mut self.sgb
+                    && let Some(players) = sgb.write_joypad(data)
+                {
+                    self.joypad.set_players(players);
+                }
             }
 
             // Serial
-            if addr == 0xFF01 {
-                // TODO
-
-                // Capturing writes to Serial Data for tests
-                print!("{}", data as char);
-            }
-            if addr == 0xFF02 {
-                // TODO
+            else if addr == 0xFF01 || addr == 0xFF02 {
+                self.serial.write(addr, data);
             }
 
             // Timers
             // DIV
-            if addr == 0xFF04 {
+            else if addr == 0xFF04 {
+                // Resetting the divider can cause a falling edge for the frame sequencer
+                if self.sys_clock & self.sequencer_bit() != 0 {
+                    self.apu.step_frame_sequencer();
+                }
                 self.sys_clock = 0;
             }
             // TIMA
-            if addr == 0xFF05 {
+            else if addr == 0xFF05 {
                 self.tima = data;
                 self.tima_overflowed = false;
             }
             // TMA
-            if addr == 0xFF06 {
+            else if addr == 0xFF06 {
                 self.tma = data;
             }
             // TAC
-            if addr == 0xFF07 {
+            else if addr == 0xFF07 {
                 self.tac = data | 0xF8;
             }
 
             // Interrupt Flag
-            if addr == 0xFF0F {
+            else if addr == 0xFF0F {
                 self.if_reg = data | 0xE0;
             }
 
-            // Audio
-            else if addr < 0xFF27 {
-                // TODO
-            }
-            // Wave Pattern
+            // Audio and Wave Pattern
             else if addr < 0xFF40 {
-                // TODO
+                self.apu.write(addr, data);
             }
 
             // LCD
@@ -295,24 +542,40 @@ impl Memory {
                 }
             }
 
+            // Speed Switch
+            else if addr == 0xFF4D {
+                if self.cgb {
+                    self.speed_switch_armed = data & 1 != 0;
+                }
+            }
+
             // VRAM Bank Select
             else if addr == 0xFF4F {
-                // TODO if upgrading to CGB
+                self.ppu.write(addr, data);
+            }
+
+            // Boot ROM disable, can't be mapped back in
+            else if addr == 0xFF50 {
+                if data != 0 {
+                    self.boot_rom_mapped = false;
+                }
             }
 
             // VRAM DMA
             else if addr < 0xFF56 {
-                // TODO if upgrading to CGB
+                if self.cgb {
+                    self.write_hdma(addr, data);
+                }
             }
 
             // Color Palettes
             else if addr < 0xFF6C {
-                // TODO if upgrading to CGB
+                self.ppu.write(addr, data);
             }
 
             // WRAM Bank Select
-            else if addr == 0xFF70 {
-                // TODO if upgrading to CGB
+            else if addr == 0xFF70 && self.cgb {
+                self.wram_bank = data & 0b111;
             }
         }
         // HRAM
@@ -325,14 +588,81 @@ impl Memory {
         }
     }
 
-    pub fn load_rom(&mut self, data: &[u8]) {
-        let end = data.len() as usize;
-        self.rom_bank[0..end].copy_from_slice(data);
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.boot_rom_mapped
+    }
+
+    pub fn load_rom(&mut self, data: &[u8]) -> Result<(), HeaderError> {
+        self.cart = Cartridge::new(data)?;
+        if let Some(sgb) = &mut self.sgb {
+            sgb.set_enabled(self.cart.header().is_some_and(|header| header.supports_sgb()));
+        }
+        Ok(())
+    }
+
+    pub fn apu(&self) -> &Audio {
+        &self.apu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Audio {
+        &mut self.apu
+    }
+
+    pub fn connect_serial(&mut self, device: Box<dyn SerialDevice>) {
+        self.serial.connect(device);
+    }
+
+    pub fn set_input(&mut self, player: usize, state: ButtonState) {
+        if self.joypad.set_input(player, state) {
+            self.if_reg |= 0b10000;
+        }
+    }
+
+    // Lets the SGB pick up VRAM transfers and the picture it shows once a frame is finished
+    pub fn end_frame(&mut self) {
+        if let Some(sgb) = &mut self.sgb {
+            sgb.end_frame(self.ppu.frame());
+        }
+    }
+
+    pub fn sgb(&self) -> Option<&Sgb> {
+        self.sgb.as_ref()
+    }
+
+    pub fn joypad(&self) -> &Joypad {
+        &self.joypad
+    }
+
+    pub fn cart(&self) -> &Cartridge {
+        &self.cart
+    }
+
+    pub fn cart_mut(&mut self) -> &mut Cartridge {
+        &mut self.cart
+    }
+
+    // Frame sequencer is clocked by the falling edge of DIV bit 4, bit 5 in double speed so it keeps its rate
+    fn sequencer_bit(&self) -> u16 {
+        if self.double_speed { 0x2000 } else { 0x1000 }
     }
 
     pub fn inc_clk(&mut self) {
         // Technically inaccurate as DIV should be represented by bits 6-13 instead of 8-15, but the top 2 bits do not motter for DMG
+        let previous = self.sys_clock;
         self.sys_clock = self.sys_clock.wrapping_add(4);
+        let sequencer_bit = self.sequencer_bit();
+        if previous & sequencer_bit != 0 && self.sys_clock & sequencer_bit == 0 {
+            self.apu.step_frame_sequencer();
+        }
+        // The APU and cartridge clock count real time, which passes at half the rate in double speed
+        let cycles = self.dots_per_m_cycle();
+        self.apu.tick(cycles);
+        self.cart.tick(cycles);
+        // Internal serial clock runs at 8192 Hz off bit 8 of the system counter
+        let serial_edge = previous & 0x100 != 0 && self.sys_clock & 0x100 == 0;
+        if self.serial.tick(serial_edge, cycles) {
+            self.if_reg |= 0b01000;
+        }
         self.detect_and();
         self.handle_dma();
     }
@@ -371,18 +701,30 @@ impl Memory {
         }
     }
 
+    // HDMA1-5, the low 4 bits of both addresses are ignored and the destination is always in VRAM
+    fn write_hdma(&mut self, addr: u16, data: u8) {
+        match addr {
+            0xFF51 => self.hdma_source = (data as u16) << 8 | (self.hdma_source & 0xF0),
+            0xFF52 => self.hdma_source = (self.hdma_source & 0xFF00) | (data & 0xF0) as u16,
+            0xFF53 => self.hdma_dest = ((data & 0x1F) as u16) << 8 | (self.hdma_dest & 0xF0),
+            0xFF54 => self.hdma_dest = (self.hdma_dest & 0x1F00) | (data & 0xF0) as u16,
+            _ => self.start_hdma(data),
+        }
+    }
+
     // TODO: Need to implement DMA blocking
     fn handle_dma(&mut self) {
         if !self.dma_trigger {
             return;
         }
 
+        // Sources past WRAM read its echo
         let mut addr = (self.dma as u16) << 8 | self.dma_counter as u16;
         if addr >= 0xE000 {
-            addr &= 0xDF;
+            addr &= 0xDFFF;
         }
         let data = self.read(addr);
-        self.ppu.write(addr, data);
+        self.ppu.write(0xFE00 | self.dma_counter as u16, data);
 
         self.dma_counter += 1;
         if self.dma_counter >= 160 {
@@ -390,4 +732,119 @@ impl Memory {
             self.dma_counter = 0;
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cgb_memory() -> Memory {
+        let mut mem = Memory::new(Model::Cgb);
+        mem.set_cgb_mode(true);
+        for i in 0..0x40 {
+            mem.write(0xC000 + i, i as u8 + 1);
+        }
+        // Source 0xC000, destination 0x8010
+        for (addr, data) in [(0xFF51, 0xC0), (0xFF52, 0x00), (0xFF53, 0x80), (0xFF54, 0x10)] {
+            mem.write(addr, data);
+        }
+        mem
+    }
+
+    #[test]
+    fn general_purpose_dma_copies_every_block_at_once() {
+        let mut mem = cgb_memory();
+        mem.write(0xFF40, 0x00);
+        mem.write(0xFF55, 0x01);
+        assert_eq!(mem.read(0xFF55), 0xFF);
+        assert_eq!(mem.read(0x800F), 0x00);
+        assert_eq!(mem.read(0x8010), 0x01);
+        assert_eq!(mem.read(0x802F), 0x20);
+        assert_eq!(mem.read(0x8030), 0x00);
+        // 8 M-cycles per block
+        assert_eq!(mem.hdma_stall, 16);
+    }
+
+    #[test]
+    fn hblank_dma_copies_a_block_per_line_until_cancelled() {
+        let mut mem = cgb_memory();
+        mem.write(0xFF55, 0x82);
+        assert_eq!(mem.read(0xFF55), 0x02);
+        while mem.read(0xFF55) == 0x02 {
+            mem.tick_ppu();
+        }
+        assert_eq!(mem.read(0xFF55), 0x01);
+        for _ in 0..456 {
+            mem.tick_ppu();
+        }
+        assert_eq!(mem.read(0xFF55), 0x00);
+
+        // Writing with bit 7 clear stops it, the remaining length reads back with bit 7 set
+        mem.write(0xFF55, 0x00);
+        assert_eq!(mem.read(0xFF55), 0x80);
+        for _ in 0..456 {
+            mem.tick_ppu();
+        }
+        assert_eq!(mem.read(0xFF55), 0x80);
+        mem.write(0xFF40, 0x00);
+        assert_eq!(mem.read(0x802F), 0x20);
+        assert_eq!(mem.read(0x8030), 0x00);
+    }
+
+    #[test]
+    fn hblank_dma_waits_out_halt_and_starts_at_once_with_the_lcd_off() {
+        let mut mem = cgb_memory();
+        mem.set_cpu_halted(true);
+        mem.write(0xFF55, 0x81);
+        for _ in 0..2 * 456 {
+            mem.tick_ppu();
+        }
+        assert_eq!(mem.read(0xFF55), 0x01);
+        mem.set_cpu_halted(false);
+        for _ in 0..456 {
+            mem.tick_ppu();
+        }
+        assert_eq!(mem.read(0xFF55), 0x00);
+
+        let mut mem = cgb_memory();
+        mem.write(0xFF40, 0x00);
+        mem.write(0xFF55, 0x81);
+        assert_eq!(mem.read(0xFF55), 0x00);
+        assert_eq!(mem.read(0x8010), 0x01);
+        assert_eq!(mem.read(0x8030), 0x00);
+    }
+
+    // Writes DIV four times with sys_clock at value and returns whether channel 1, 2 length steps from expiring, is still on
+    fn square1_survives_div_writes(double_speed: bool, value: u16) -> bool {
+        let mut mem = Memory::new(Model::Cgb);
+        mem.double_speed = double_speed;
+        mem.write(0xFF12, 0xF0);
+        mem.write(0xFF11, 0x3E);
+        mem.write(0xFF14, 0xC0);
+        for _ in 0..4 {
+            mem.sys_clock = value;
+            mem.write(0xFF04, 0x00);
+        }
+        mem.read(0xFF26) & 0x01 != 0
+    }
+
+    #[test]
+    fn div_writes_step_the_frame_sequencer_on_the_speed_dependent_bit() {
+        assert!(!square1_survives_div_writes(false, 0x1000));
+        assert!(square1_survives_div_writes(false, 0x2000));
+        assert!(square1_survives_div_writes(true, 0x1000));
+        assert!(!square1_survives_div_writes(true, 0x2000));
+    }
+
+    #[test]
+    fn rejects_states_with_an_impossible_hdma_stall() {
+        for (stall, valid) in [(0x80 * 16, true), (0x80 * 16 + 1, false)] {
+            let mut mem = Memory::new(Model::Dmg);
+            mem.hdma_stall = stall;
+            let mut w = StateWriter::new(None);
+            mem.save_state(&mut w);
+            let data = w.finish();
+            let mut r = StateReader::new(&data, None).unwrap();
+            assert_eq!(Memory::new(Model::Dmg).load_state(&mut r).is_ok(), valid);
+        }
+    }
+}
@@ -0,0 +1,282 @@
+mod bk2;
+mod vbm;
+
+use std::fmt;
+
+use crate::gb::cart::header::CartridgeHeader;
+use crate::gb::joypad::ButtonState;
+use crate::gb::state::StateError;
+use crate::gb::Gameboy;
+
+const MAGIC: &[u8; 4] = b"GBMV";
+const VERSION: u8 = 1;
+
+const START_POWER_ON: u8 = 0;
+const START_SAVE_RAM: u8 = 1;
+const START_SAVE_STATE: u8 = 2;
+
+#[derive(Debug)]
+pub enum MovieError {
+    Truncated,
+    UnknownFormat,
+    UnsupportedVersion(u32),
+    UnsupportedStart(&'static str),
+    InvalidArchive(&'static str),
+    RomMismatch { movie: String, rom: String },
+    State(StateError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::Truncated => write!(f, "movie file is truncated"),
+            MovieError::UnknownFormat => write!(f, "not a .gbmv, .bk2 or .vbm movie"),
+            MovieError::UnsupportedVersion(version) => write!(f, "unsupported movie version {}", version),
+            MovieError::UnsupportedStart(start) => write!(f, "movies starting from {} are not supported", start),
+            MovieError::InvalidArchive(reason) => write!(f, "invalid movie archive: {}", reason),
+            MovieError::RomMismatch { movie, rom } => write!(f, "movie was recorded on {} but the loaded ROM is {}", movie, rom),
+            MovieError::State(e) => write!(f, "embedded save state: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+// State the machine is in when frame 0 of the movie starts
+#[derive(Clone, Debug, PartialEq)]
+pub enum MovieStart {
+    PowerOn,
+    // Power-on with this battery-backed RAM loaded, like starting with an existing .sav
+    SaveRam(Vec<u8>),
+    // An embedded save state taken with save_state
+    SaveState(Vec<u8>),
+}
+
+// Joypad state for every frame plus enough information to start playback from the same state.
+// Playback is only deterministic with the cartridge clock on emulated time, not --rtc-host
+pub struct Movie {
+    // Identifies the ROM the movie was recorded on
+    pub title: String,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+    pub start: MovieStart,
+    inputs: Vec<ButtonState>,
+}
+
+impl Movie {
+    // Starts an empty recording for the loaded ROM
+    pub fn new(header: &CartridgeHeader, start: MovieStart) -> Self {
+        Self {
+            title: header.title.clone(),
+            header_checksum: header.header_checksum,
+            global_checksum: header.global_checksum,
+            start,
+            inputs: Vec::new(),
+        }
+    }
+
+    // Reads a native .gbmv movie, a BizHawk .bk2 or a VBA .vbm, telling them apart by their magic bytes.
+    // BizHawk movies identify their ROM by a SHA1 hash, which is checked against rom, the image header was parsed from
+    pub fn parse(data: &[u8], header: &CartridgeHeader, rom: &[u8]) -> Result<Self, MovieError> {
+        if data.starts_with(MAGIC) {
+            Self::from_bytes(data)
+        } else if data.starts_with(bk2::MAGIC) {
+            bk2::parse(data, header, rom)
+        } else if data.starts_with(vbm::MAGIC) {
+            vbm::parse(data)
+        } else {
+            Err(MovieError::UnknownFormat)
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    // Input for a frame, None once the movie has ended
+    pub fn input(&self, frame: usize) -> Option<ButtonState> {
+        self.inputs.get(frame).copied()
+    }
+
+    // Appends the input held during the next frame
+    pub fn push(&mut self, state: ButtonState) {
+        self.inputs.push(state);
+    }
+
+    // Checks the movie belongs to the loaded ROM and puts the machine into the movie's starting state.
+    // Has to be called on a freshly created Gameboy right after load_rom
+    pub fn apply_start(&self, gb: &mut Gameboy) -> Result<(), MovieError> {
+        let Some(header) = gb.cartridge_header() else {
+            return Err(MovieError::RomMismatch { movie: self.title.clone(), rom: String::from("no ROM") });
+        };
+        if header.header_checksum != self.header_checksum || header.global_checksum != self.global_checksum {
+            return Err(MovieError::RomMismatch {
+                movie: format!("{} ({:04X})", self.title, self.global_checksum),
+                rom: format!("{} ({:04X})", header.title, header.global_checksum),
+            });
+        }
+
+        match &self.start {
+            MovieStart::PowerOn => {}
+            MovieStart::SaveRam(data) => gb.load_save_data(data),
+            MovieStart::SaveState(state) => gb.load_state(state).map_err(MovieError::State)?,
+        }
+        Ok(())
+    }
+
+    // Serializes to the native format:
+    // "GBMV", version, header checksum, global checksum (u16), title length and title,
+    // start kind and its data (u32 length prefixed), frame count (u32), then one packed input byte per frame
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.push(VERSION);
+        data.push(self.header_checksum);
+        data.extend_from_slice(&self.global_checksum.to_le_bytes());
+        let title = self.title.as_bytes();
+        data.push(title.len() as u8);
+        data.extend_from_slice(title);
+
+        match &self.start {
+            MovieStart::PowerOn => data.push(START_POWER_ON),
+            MovieStart::SaveRam(ram) => {
+                data.push(START_SAVE_RAM);
+                data.extend_from_slice(&(ram.len() as u32).to_le_bytes());
+                data.extend_from_slice(ram);
+            }
+            MovieStart::SaveState(state) => {
+                data.push(START_SAVE_STATE);
+                data.extend_from_slice(&(state.len() as u32).to_le_bytes());
+                data.extend_from_slice(state);
+            }
+        }
+
+        data.extend_from_slice(&(self.inputs.len() as u32).to_le_bytes());
+        data.extend(self.inputs.iter().map(|&state| pack(state)));
+        data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, MovieError> {
+        let mut reader = Reader { data, pos: 0 };
+        if reader.take(4)? != MAGIC {
+            return Err(MovieError::UnknownFormat);
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(MovieError::UnsupportedVersion(version.into()));
+        }
+        let header_checksum = reader.u8()?;
+        let global_checksum = u16::from_le_bytes(reader.take(2)?.try_into().unwrap());
+        let title_len = reader.u8()? as usize;
+        let title = String::from_utf8_lossy(reader.take(title_len)?).into_owned();
+
+        let start = match reader.u8()? {
+            START_POWER_ON => MovieStart::PowerOn,
+            START_SAVE_RAM => {
+                let len = reader.u32()? as usize;
+                MovieStart::SaveRam(reader.take(len)?.to_vec())
+            }
+            START_SAVE_STATE => {
+                let len = reader.u32()? as usize;
+                MovieStart::SaveState(reader.take(len)?.to_vec())
+            }
+            _ => return Err(MovieError::UnsupportedStart("an unknown state")),
+        };
+
+        let frames = reader.u32()? as usize;
+        let inputs = reader.take(frames)?.iter().map(|&byte| unpack(byte)).collect();
+        Ok(Self { title, header_checksum, global_checksum, start, inputs })
+    }
+}
+
+// Bounds checked cursor over a movie file
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], MovieError> {
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or(MovieError::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, MovieError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, MovieError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
+
+// One byte per frame, bits 0-7 are A, B, Select, Start, Right, Left, Up, Down like P1 and VBA movies
+fn pack(state: ButtonState) -> u8 {
+    [state.a, state.b, state.select, state.start, state.right, state.left, state.up, state.down]
+        .iter()
+        .enumerate()
+        .fold(0, |byte, (bit, &pressed)| byte | ((pressed as u8) << bit))
+}
+
+fn unpack(byte: u8) -> ButtonState {
+    let bit = |n: u8| byte & (1 << n) != 0;
+    ButtonState {
+        a: bit(0),
+        b: bit(1),
+        select: bit(2),
+        start: bit(3),
+        right: bit(4),
+        left: bit(5),
+        up: bit(6),
+        down: bit(7),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gb::testing::rom;
+
+    #[test]
+    fn native_movies_round_trip() {
+        let header = CartridgeHeader::parse(&rom(&[])).unwrap();
+        let mut movie = Movie::new(&header, MovieStart::SaveRam(vec![1, 2, 3]));
+        movie.push(ButtonState { a: true, down: true, ..Default::default() });
+        movie.push(ButtonState::default());
+        movie.push(ButtonState { start: true, left: true, ..Default::default() });
+
+        let data = movie.to_bytes();
+        let loaded = Movie::parse(&data, &header, &rom(&[])).unwrap();
+        assert_eq!(loaded.start, movie.start);
+        assert_eq!(loaded.global_checksum, movie.global_checksum);
+        assert_eq!(loaded.len(), 3);
+        for frame in 0..3 {
+            assert_eq!(loaded.input(frame), movie.input(frame));
+        }
+        assert_eq!(loaded.input(3), None);
+
+        assert!(matches!(Movie::from_bytes(&data[..data.len() - 1]), Err(MovieError::Truncated)));
+        let mut data = data;
+        data[4] = 2;
+        assert!(matches!(Movie::from_bytes(&data), Err(MovieError::UnsupportedVersion(2))));
+    }
+
+    #[test]
+    fn packs_every_button_into_its_own_bit() {
+        for byte in 0..=255 {
+            assert_eq!(pack(unpack(byte)), byte);
+        }
+        assert_eq!(pack(ButtonState { a: true, ..Default::default() }), 0x01);
+        assert_eq!(pack(ButtonState { down: true, ..Default::default() }), 0x80);
+    }
+
+    #[test]
+    fn rejects_unknown_formats() {
+        let header = CartridgeHeader::parse(&rom(&[])).unwrap();
+        assert!(matches!(Movie::parse(b"RIFF", &header, &rom(&[])), Err(MovieError::UnknownFormat)));
+    }
+}
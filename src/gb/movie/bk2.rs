@@ -0,0 +1,468 @@
+// BizHawk .bk2 movies: a zip archive holding a text Header.txt and Input Log.txt,
+// usually deflate compressed
+
+use crate::gb::cart::header::CartridgeHeader;
+use crate::gb::joypad::{Button, ButtonState};
+use crate::gb::movie::{Movie, MovieError, MovieStart};
+
+pub const MAGIC: &[u8] = b"PK\x03\x04";
+
+// Column order used by the Gameboy cores when the log has no LogKey line
+const DEFAULT_LOG_KEY: &str = "#Up|Down|Left|Right|Start|Select|B|A|Power|";
+
+// rom is the loaded ROM image, whose SHA1 is compared with the one BizHawk recorded
+pub fn parse(data: &[u8], header: &CartridgeHeader, rom: &[u8]) -> Result<Movie, MovieError> {
+    let files = read_zip(data)?;
+    let file = |name: &str| files.iter().find(|(entry, _)| entry == name).map(|(_, contents)| contents);
+
+    let info = file("Header.txt").map(|text| String::from_utf8_lossy(text).into_owned()).unwrap_or_default();
+    let flag = |key: &str| info.lines().any(|line| line.trim().eq_ignore_ascii_case(&format!("{} True", key)));
+    let value = |key: &str| info.lines().find_map(|line| line.trim().strip_prefix(key)?.strip_prefix(' ')).map(str::trim);
+
+    // The hash identifies the exact dump. Without one the game name, usually the No-Intro name, has to contain the title
+    let game_name = value("GameName").unwrap_or_default();
+    let rom_hash = hex(&sha1(rom));
+    let same_rom = match value("SHA1") {
+        Some(hash) => hash.eq_ignore_ascii_case(&rom_hash),
+        None => header.title.is_empty() || game_name.is_empty() || game_name.to_uppercase().contains(&header.title.to_uppercase()),
+    };
+    if !same_rom {
+        return Err(MovieError::RomMismatch {
+            movie: format!("{} (SHA1 {})", game_name, value("SHA1").unwrap_or("unknown")),
+            rom: format!("{} (SHA1 {})", header.title, rom_hash),
+        });
+    }
+    if flag("StartsFromSavestate") {
+        return Err(MovieError::UnsupportedStart("a BizHawk savestate"));
+    }
+    let start = match file("SaveRam") {
+        Some(ram) if flag("StartsFromSaveRam") => MovieStart::SaveRam(ram.clone()),
+        _ => MovieStart::PowerOn,
+    };
+
+    let log = file("Input Log.txt").ok_or(MovieError::InvalidArchive("no Input Log.txt"))?;
+    let mut movie = Movie::new(header, start);
+    for state in parse_input_log(&String::from_utf8_lossy(log)) {
+        movie.push(state);
+    }
+    Ok(movie)
+}
+
+// Each frame is a line like |UDLRsSBAP| where every column is one button from the LogKey and '.' means released
+fn parse_input_log(log: &str) -> Vec<ButtonState> {
+    let key = log.lines().find_map(|line| line.strip_prefix("LogKey:")).unwrap_or(DEFAULT_LOG_KEY);
+    let columns: Vec<Option<Button>> = key
+        .split(['|', '#'])
+        .filter(|name| !name.is_empty())
+        .map(|name| match name.trim_start_matches("P1 ") {
+            "Up" => Some(Button::Up),
+            "Down" => Some(Button::Down),
+            "Left" => Some(Button::Left),
+            "Right" => Some(Button::Right),
+            "Start" => Some(Button::Start),
+            "Select" => Some(Button::Select),
+            "B" => Some(Button::B),
+            "A" => Some(Button::A),
+            // Power and any other console buttons
+            _ => None,
+        })
+        .collect();
+
+    log.lines()
+        .filter(|line| line.starts_with('|'))
+        .map(|line| {
+            let mut state = ButtonState::default();
+            for (column, c) in columns.iter().zip(line.chars().filter(|&c| c != '|')) {
+                if let Some(button) = column {
+                    state.set(*button, c != '.' && c != ' ');
+                }
+            }
+            state
+        })
+        .collect()
+}
+
+// Extracts every file from a zip archive using its central directory
+fn read_zip(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>, MovieError> {
+    let u16_at = |offset: usize| data.get(offset..offset + 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as usize);
+    let u32_at = |offset: usize| data.get(offset..offset + 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize);
+
+    // End of central directory record, searched from the back since it may be followed by a comment
+    let end = (0..data.len().saturating_sub(21))
+        .rev()
+        .find(|&i| data[i..].starts_with(b"PK\x05\x06"))
+        .ok_or(MovieError::InvalidArchive("no central directory"))?;
+    let entries = u16_at(end + 10).ok_or(MovieError::Truncated)?;
+    let mut offset = u32_at(end + 16).ok_or(MovieError::Truncated)?;
+
+    let mut files = Vec::with_capacity(entries);
+    for _ in 0..entries {
+        if !data.get(offset..).is_some_and(|rest| rest.starts_with(b"PK\x01\x02")) {
+            return Err(MovieError::InvalidArchive("corrupt central directory"));
+        }
+        let field = |at: usize| u16_at(offset + at).ok_or(MovieError::Truncated);
+        let method = field(10)?;
+        let compressed_size = u32_at(offset + 20).ok_or(MovieError::Truncated)?;
+        let (name_len, extra_len, comment_len) = (field(28)?, field(30)?, field(32)?);
+        let local = u32_at(offset + 42).ok_or(MovieError::Truncated)?;
+        let name = data.get(offset + 46..offset + 46 + name_len).ok_or(MovieError::Truncated)?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        offset += 46 + name_len + extra_len + comment_len;
+
+        // The local header repeats the name and has its own extra field before the data
+        let start = local + 30 + u16_at(local + 26).ok_or(MovieError::Truncated)? + u16_at(local + 28).ok_or(MovieError::Truncated)?;
+        let stored = data.get(start..start + compressed_size).ok_or(MovieError::Truncated)?;
+        let contents = match method {
+            0 => stored.to_vec(),
+            8 => inflate(stored)?,
+            _ => return Err(MovieError::InvalidArchive("unsupported compression method")),
+        };
+        files.push((name, contents));
+    }
+    Ok(files)
+}
+
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// Order code length code lengths are sent in
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+const CORRUPT: MovieError = MovieError::InvalidArchive("corrupt deflate stream");
+
+// Canonical Huffman code as the number of codes of each length and the symbols ordered by code
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Self { counts, symbols }
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit: u8,
+}
+
+impl BitReader<'_> {
+    // Deflate packs values starting at the least significant bit
+    fn bits(&mut self, count: u8) -> Result<u32, MovieError> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = *self.data.get(self.pos).ok_or(MovieError::Truncated)?;
+            value |= (((byte >> self.bit) & 1) as u32) << i;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.pos += 1;
+            }
+        }
+        Ok(value)
+    }
+
+    fn decode(&mut self, huffman: &Huffman) -> Result<u16, MovieError> {
+        // Huffman codes are packed most significant bit first
+        let (mut code, mut first, mut index) = (0, 0, 0);
+        for len in 1..16 {
+            code |= self.bits(1)? as i32;
+            let count = huffman.counts[len] as i32;
+            if code - count < first {
+                return Ok(huffman.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(CORRUPT)
+    }
+}
+
+// SHA-1 digest of the whole ROM, the hash BizHawk writes to Header.txt
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    // Padded with a 1 bit, zeros and the length in bits to a multiple of 64 bytes
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (word, bytes) in w.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_be_bytes(bytes.try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, word) in w.into_iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(word);
+            (e, d, c, b, a) = (d, c, b.rotate_left(30), a, temp);
+        }
+        for (value, add) in state.iter_mut().zip([a, b, c, d, e]) {
+            *value = value.wrapping_add(add);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (bytes, value) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+fn inflate(data: &[u8]) -> Result<Vec<u8>, MovieError> {
+    let mut reader = BitReader { data, pos: 0, bit: 0 };
+    let mut out = Vec::new();
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                // Stored blocks start on a byte boundary
+                if reader.bit != 0 {
+                    reader.bit = 0;
+                    reader.pos += 1;
+                }
+                let len = reader.bits(16)? as usize;
+                reader.bits(16)?;
+                let block = data.get(reader.pos..reader.pos + len).ok_or(MovieError::Truncated)?;
+                out.extend_from_slice(block);
+                reader.pos += len;
+            }
+            1 => {
+                let mut lengths = [0; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                inflate_block(&mut reader, &mut out, &Huffman::new(&lengths), &Huffman::new(&[5; 30]))?;
+            }
+            2 => {
+                let (literals, distances) = read_dynamic_codes(&mut reader)?;
+                inflate_block(&mut reader, &mut out, &literals, &distances)?;
+            }
+            _ => return Err(CORRUPT),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+fn read_dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), MovieError> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_count = reader.bits(4)? as usize + 4;
+
+    let mut code_lengths = [0; 19];
+    for &index in &CODE_LENGTH_ORDER[..code_count] {
+        code_lengths[index] = reader.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths);
+
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (value, repeat) = match reader.decode(&code_lengths)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => (*lengths.last().ok_or(CORRUPT)?, 3 + reader.bits(2)?),
+            17 => (0, 3 + reader.bits(3)?),
+            _ => (0, 11 + reader.bits(7)?),
+        };
+        lengths.extend(std::iter::repeat_n(value, repeat as usize));
+    }
+    if lengths.len() != literal_count + distance_count {
+        return Err(CORRUPT);
+    }
+    Ok((Huffman::new(&lengths[..literal_count]), Huffman::new(&lengths[literal_count..])))
+}
+
+fn inflate_block(reader: &mut BitReader, out: &mut Vec<u8>, literals: &Huffman, distances: &Huffman) -> Result<(), MovieError> {
+    loop {
+        let symbol = reader.decode(literals)? as usize;
+        if symbol < 256 {
+            out.push(symbol as u8);
+            continue;
+        }
+        if symbol == 256 {
+            return Ok(());
+        }
+
+        let index = symbol - 257;
+        if index >= LENGTH_BASE.len() {
+            return Err(CORRUPT);
+        }
+        let length = LENGTH_BASE[index] as usize + reader.bits(LENGTH_EXTRA[index])? as usize;
+        let index = reader.decode(distances)? as usize;
+        if index >= DISTANCE_BASE.len() {
+            return Err(CORRUPT);
+        }
+        let distance = DISTANCE_BASE[index] as usize + reader.bits(DISTANCE_EXTRA[index])? as usize;
+        if distance > out.len() {
+            return Err(CORRUPT);
+        }
+        // Copies byte by byte since the match may overlap the bytes it produces
+        let start = out.len() - distance;
+        for i in 0..length {
+            out.push(out[start + i]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gb::testing::{fix_checksums, rom};
+
+    // Input log with a few frames, raw deflate with dynamic Huffman codes as written by zlib
+    const LOG: &[u8] = b"|.UD.Rs.BA|\n|.A.Ss.LD.|\n|..L..L..L|\n|.RA.BD.Us|\n|.D.SA.Ls.|\n|.........|\n|.Bs.UA.RD|\n|.s.SD.LA.|\n";
+    const LOG_DEFLATED: [u8; 61] = [
+        0x2D, 0xCA, 0xB1, 0x0D, 0xC0, 0x40, 0x08, 0x43, 0xD1, 0x3E, 0xC3, 0xFC, 0x1D, 0x8C, 0x28, 0xA9, 0x38, 0x31, 0x09, 0x2D,
+        0xC3, 0x27, 0xE8, 0x62, 0xB9, 0x78, 0xB2, 0x3C, 0x94, 0x93, 0x8D, 0x69, 0x9E, 0x41, 0x9C, 0x26, 0x9C, 0x35, 0x71, 0xBB,
+        0x4E, 0x61, 0x4E, 0xF5, 0xDA, 0x39, 0x22, 0xFA, 0x7E, 0xFE, 0xAC, 0xAD, 0x29, 0x91, 0xBE, 0x6E, 0x8E, 0x13, 0xFA, 0xF6,
+        0x17,
+    ];
+
+    // Zip archive of (name, compression method, stored bytes) entries, CRCs are left out since they aren't checked
+    fn zip(files: &[(&str, u16, &[u8])]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut directory = Vec::new();
+        for &(name, method, stored) in files {
+            let local = data.len() as u32;
+            data.extend_from_slice(b"PK\x03\x04");
+            data.extend_from_slice(&[0; 4]);
+            data.extend_from_slice(&method.to_le_bytes());
+            data.extend_from_slice(&[0; 8]);
+            data.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+            data.extend_from_slice(&[0; 4]);
+            data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            data.extend_from_slice(&[0; 2]);
+            data.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(stored);
+
+            directory.extend_from_slice(b"PK\x01\x02");
+            directory.extend_from_slice(&[0; 6]);
+            directory.extend_from_slice(&method.to_le_bytes());
+            directory.extend_from_slice(&[0; 8]);
+            directory.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+            directory.extend_from_slice(&[0; 4]);
+            directory.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            directory.extend_from_slice(&[0; 12]);
+            directory.extend_from_slice(&local.to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+        }
+        let offset = data.len() as u32;
+        data.extend_from_slice(&directory);
+        data.extend_from_slice(b"PK\x05\x06");
+        data.extend_from_slice(&[0; 6]);
+        data.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        data.extend_from_slice(&offset.to_le_bytes());
+        data.extend_from_slice(&[0; 2]);
+        data
+    }
+
+    #[test]
+    fn inflates_stored_fixed_and_dynamic_blocks() {
+        assert_eq!(inflate(&[0x01, 0x05, 0x00, 0xFA, 0xFF, b'h', b'e', b'l', b'l', b'o']).unwrap(), b"hello");
+        // Back references overlapping the bytes they copy
+        assert_eq!(inflate(&[0x4B, 0x4C, 0x4A, 0x4E, 0x84, 0x21, 0x00]).unwrap(), b"abcabcabcabc");
+        assert_eq!(inflate(&LOG_DEFLATED).unwrap(), LOG);
+    }
+
+    #[test]
+    fn rejects_broken_deflate_streams() {
+        assert!(matches!(inflate(&LOG_DEFLATED[..30]), Err(MovieError::Truncated)));
+        assert!(inflate(&[0x07]).is_err());
+    }
+
+    #[test]
+    fn maps_log_columns_through_the_log_key() {
+        let states = parse_input_log("LogKey:#P1 A|P1 B|P1 Up|Power|\n|A.U.|\n|.B..|\n");
+        assert_eq!(states, [
+            ButtonState { a: true, up: true, ..Default::default() },
+            ButtonState { b: true, ..Default::default() },
+        ]);
+        let states = parse_input_log("|U.......P|\n|.......A.|\n");
+        assert_eq!(states, [ButtonState { up: true, ..Default::default() }, ButtonState { a: true, ..Default::default() }]);
+    }
+
+    #[test]
+    fn reads_movies_from_the_archive() {
+        let rom = rom(&[]);
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        let data = zip(&[
+            ("Header.txt", 0, b"StartsFromSaveRam True\n"),
+            ("SaveRam", 0, &[1, 2, 3]),
+            ("Input Log.txt", 8, &LOG_DEFLATED),
+        ]);
+        let movie = parse(&data, &header, &rom).unwrap();
+        assert_eq!(movie.start, MovieStart::SaveRam(vec![1, 2, 3]));
+        assert_eq!(movie.len(), 8);
+        assert_eq!(movie.input(5), Some(ButtonState::default()));
+
+        let data = zip(&[("Header.txt", 0, b"StartsFromSavestate True\n"), ("Input Log.txt", 0, LOG)]);
+        assert!(matches!(parse(&data, &header, &rom), Err(MovieError::UnsupportedStart(_))));
+        let data = zip(&[("Header.txt", 0, b"")]);
+        assert!(matches!(parse(&data, &header, &rom), Err(MovieError::InvalidArchive(_))));
+    }
+
+    #[test]
+    fn hashes_with_sha1() {
+        assert_eq!(hex(&sha1(b"")), "DA39A3EE5E6B4B0D3255BFEF95601890AFD80709");
+        assert_eq!(hex(&sha1(b"abc")), "A9993E364706816ABA3E25717850C26C9CD0D89D");
+        // Long enough for the length to spill into a second block
+        assert_eq!(hex(&sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")), "84983E441C3BD26EBAAE4AA1F95129E5E54670F1");
+    }
+
+    #[test]
+    fn checks_the_movie_was_made_on_the_loaded_rom() {
+        let mut rom = rom(&[]);
+        rom[0x134..0x13A].copy_from_slice(b"TETRIS");
+        fix_checksums(&mut rom);
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        let movie = |info: &str| zip(&[("Header.txt", 0, info.as_bytes()), ("Input Log.txt", 0, LOG)]);
+
+        let info = format!("GameName Something else\nSHA1 {}\n", hex(&sha1(&rom)).to_lowercase());
+        assert!(parse(&movie(&info), &header, &rom).is_ok());
+        let info = "GameName Tetris (World)\nSHA1 DA39A3EE5E6B4B0D3255BFEF95601890AFD80709\n";
+        assert!(matches!(parse(&movie(info), &header, &rom), Err(MovieError::RomMismatch { .. })));
+        // Without a hash the game name has to mention the title
+        assert!(parse(&movie("GameName Tetris (World) (Rev A)\n"), &header, &rom).is_ok());
+        assert!(matches!(parse(&movie("GameName Dr. Mario\n"), &header, &rom), Err(MovieError::RomMismatch { .. })));
+    }
+}
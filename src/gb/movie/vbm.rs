@@ -0,0 +1,99 @@
+// VisualBoyAdvance-rerecording movies: a 64 byte header followed by optional SRAM and one
+// 16-bit word per frame for each connected controller
+
+use crate::gb::movie::{unpack, Movie, MovieError, MovieStart, Reader};
+
+pub const MAGIC: &[u8] = b"VBM\x1A";
+
+const HEADER_SIZE: usize = 0x40;
+
+// Start flags
+const START_SNAPSHOT: u8 = 0x01;
+const START_SRAM: u8 = 0x02;
+
+pub fn parse(data: &[u8]) -> Result<Movie, MovieError> {
+    if data.len() < HEADER_SIZE {
+        return Err(MovieError::Truncated);
+    }
+    let word = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+
+    let version = word(0x04);
+    if version != 1 {
+        return Err(MovieError::UnsupportedVersion(version));
+    }
+    let frames = word(0x0C) as usize;
+    let start_flags = data[0x14];
+    // Only the first connected controller drives the Game Boy
+    let controllers = (data[0x15] & 0x0F).count_ones().max(1) as usize;
+    let title = data[0x24..0x30].iter().take_while(|&&b| b != 0).map(|&b| b as char).collect();
+    let header_checksum = data[0x31];
+    // Stored as the two header bytes in ROM order
+    let global_checksum = u16::from_be_bytes([data[0x32], data[0x33]]);
+    let save_offset = word(0x38) as usize;
+    let input_offset = word(0x3C) as usize;
+
+    let start = if start_flags & START_SNAPSHOT != 0 {
+        return Err(MovieError::UnsupportedStart("a VBA snapshot"));
+    } else if start_flags & START_SRAM != 0 {
+        let sram = data.get(save_offset..input_offset).ok_or(MovieError::Truncated)?;
+        MovieStart::SaveRam(sram.to_vec())
+    } else {
+        MovieStart::PowerOn
+    };
+
+    // The frame count is untrusted, never reserve more than the file could hold
+    let capacity = frames.min(data.len().saturating_sub(input_offset) / (controllers * 2));
+    let mut reader = Reader { data, pos: input_offset };
+    let mut movie = Movie { title, header_checksum, global_checksum, start, inputs: Vec::with_capacity(capacity) };
+    for _ in 0..frames {
+        let frame = reader.take(controllers * 2)?;
+        movie.push(unpack(frame[0]));
+    }
+    Ok(movie)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Power-on movie for two controllers, only the first one's words end up in the movie
+    fn vbm(frames: u32, inputs: &[u16]) -> Vec<u8> {
+        let mut data = vec![0; HEADER_SIZE];
+        data[..4].copy_from_slice(MAGIC);
+        data[0x04..0x08].copy_from_slice(&1u32.to_le_bytes());
+        data[0x0C..0x10].copy_from_slice(&frames.to_le_bytes());
+        data[0x15] = 0b0011;
+        data[0x24..0x28].copy_from_slice(b"GAME");
+        data[0x31] = 0x12;
+        data[0x32..0x34].copy_from_slice(&[0xAB, 0xCD]);
+        data[0x3C..0x40].copy_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+        for &input in inputs {
+            data.extend_from_slice(&input.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn reads_the_first_controller() {
+        let movie = parse(&vbm(2, &[0x0081, 0x0002, 0x0010, 0x00FF])).unwrap();
+        assert_eq!(movie.title, "GAME");
+        assert_eq!(movie.header_checksum, 0x12);
+        assert_eq!(movie.global_checksum, 0xABCD);
+        assert_eq!(movie.start, MovieStart::PowerOn);
+        assert_eq!(movie.len(), 2);
+        assert_eq!(movie.input(0), Some(unpack(0x81)));
+        assert_eq!(movie.input(1), Some(unpack(0x10)));
+    }
+
+    #[test]
+    fn rejects_bad_versions_and_frame_counts() {
+        let mut data = vbm(0, &[]);
+        data[0x04..0x08].copy_from_slice(&2u32.to_le_bytes());
+        assert!(matches!(parse(&data), Err(MovieError::UnsupportedVersion(2))));
+        data[0x04..0x08].copy_from_slice(&0x101u32.to_le_bytes());
+        assert!(matches!(parse(&data), Err(MovieError::UnsupportedVersion(0x101))));
+        assert!(matches!(parse(&vbm(3, &[0, 0, 0, 0])), Err(MovieError::Truncated)));
+        // A bogus frame count must not reserve memory for inputs the file doesn't have
+        assert!(matches!(parse(&vbm(u32::MAX, &[0, 0])), Err(MovieError::Truncated)));
+    }
+}
@@ -1,3 +1,44 @@
+mod compat;
+mod fifo;
+
+use crate::gb::ppu::fifo::PixelFifo;
+use crate::gb::state::{StateError, StateReader, StateWriter};
+
+pub use crate::gb::ppu::compat::{palettes_for_buttons, palettes_for_header, CompatPalettes};
+
+// Timing constants, in dots (1 dot = 1 T-cycle at normal speed)
+const DOTS_PER_LINE: u16 = 456;
+const OAM_SCAN_DOTS: u16 = 80;
+const DRAWING_DOTS: u16 = 172;
+const VBLANK_LINE: u8 = 144;
+const LINES_PER_FRAME: u8 = 154;
+const MAX_SPRITES_PER_LINE: usize = 10;
+const DOTS_PER_FRAME: u32 = DOTS_PER_LINE as u32 * LINES_PER_FRAME as u32;
+// Line 0 after the LCD is switched on is 4 dots shorter than normal
+const FIRST_LINE_SKIPPED_DOTS: u16 = 4;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+// RGB colours for the 4 DMG shades, lightest to darkest
+const DMG_PALETTE: [[u8; 3]; 4] = [
+    [0xE0, 0xF8, 0xD0],
+    [0x88, 0xC0, 0x70],
+    [0x34, 0x68, 0x56],
+    [0x08, 0x18, 0x20],
+];
+
+// RGB555 white, what the CGB shows while the LCD is off
+const CGB_WHITE: u16 = 0x7FFF;
+
+// Scanline renders each line in one go at the start of mode 3 with a fixed mode 3 length,
+// Fifo emulates the pixel FIFO dot by dot so mode 3 length and mid-line register writes are accurate
+#[derive(Clone, Copy, PartialEq)]
+pub enum RenderMode {
+    Scanline,
+    Fifo,
+}
+
 pub struct Video {
     lcdc: u8,
     stat: u8,
@@ -10,9 +51,53 @@ pub struct Video {
     obp1: u8,
     wy: u8,
     wx: u8,
-    vram: [u8; 0x2000],
+    // Two 0x2000 banks, the second only switchable in CGB mode
+    vram: [u8; 0x4000],
+    vram_bank: u8,
+    cgb: bool,
+    // DMG game on a CGB, shades are coloured through BG palette 0 and OBJ palettes 0-1
+    compat: bool,
     oam: [u8; 0xA0],
     mode: u8,
+    dots: u16,
+    // Shade (0-3) of every pixel on screen after palette mapping, the colour number in CGB mode
+    framebuffer: [u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+    // RGB555 colour of every pixel, only drawn in CGB and compatibility mode
+    color_framebuffer: [u16; SCREEN_WIDTH * SCREEN_HEIGHT],
+    // Raw BG/window colour indices of the current line, before BGP is applied
+    bg_line: [u8; SCREEN_WIDTH],
+    // CGB BG map attributes of every pixel of the current line
+    bg_attributes: [u8; SCREEN_WIDTH],
+    // CGB palette RAM, 8 palettes of 4 RGB555 colours each for BG and for sprites
+    bg_palettes: [u8; 64],
+    obj_palettes: [u8; 64],
+    // Palette RAM index (bits 0-5) and auto-increment (bit 7)
+    bcps: u8,
+    ocps: u8,
+    // Passes RGB555 colours through a curve approximating the CGB LCD instead of scaling them linearly
+    color_correction: bool,
+    // Internal line counter of the window, only advanced on lines where it was drawn
+    window_line: u8,
+    // Set once LY has matched WY during the current frame
+    window_triggered: bool,
+    frame_ready: bool,
+    // OAM indices of the sprites selected during OAM scan for the current line
+    line_sprites: [u8; MAX_SPRITES_PER_LINE],
+    sprite_count: usize,
+    render_mode: RenderMode,
+    fifo: PixelFifo,
+    // Combined state of the enabled STAT sources, the interrupt fires only on its rising edge
+    stat_line: bool,
+    // Interrupt flags requested since the memory unit last collected them
+    interrupts: u8,
+    // Set on entering HBlank of a visible line, paces HBlank DMA
+    hblank_started: bool,
+    // Line 0 right after enabling the LCD stays in mode 0 instead of scanning OAM
+    first_line: bool,
+    // The first frame after enabling the LCD is not shown
+    first_frame: bool,
+    // Dots counted while the LCD is off so frames keep being presented at the normal rate
+    off_dots: u32,
 }
 
 impl Video {
@@ -29,18 +114,568 @@ impl Video {
             obp1: 0xFF,
             wy: 0,
             wx: 0,
-            vram: [0; 0x2000],
+            vram: [0; 0x4000],
+            vram_bank: 0,
+            cgb: false,
+            compat: false,
             oam: [0; 0xA0],
             mode: 2,
+            dots: 0,
+            framebuffer: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            color_framebuffer: [CGB_WHITE; SCREEN_WIDTH * SCREEN_HEIGHT],
+            bg_line: [0; SCREEN_WIDTH],
+            bg_attributes: [0; SCREEN_WIDTH],
+            bg_palettes: [0xFF; 64],
+            obj_palettes: [0; 64],
+            bcps: 0,
+            ocps: 0,
+            color_correction: false,
+            window_line: 0,
+            window_triggered: false,
+            frame_ready: false,
+            line_sprites: [0; MAX_SPRITES_PER_LINE],
+            sprite_count: 0,
+            render_mode: RenderMode::Scanline,
+            fifo: PixelFifo::new(),
+            stat_line: false,
+            interrupts: 0,
+            hblank_started: false,
+            first_line: false,
+            first_frame: false,
+            off_dots: 0,
         }
     }
 
+    // Enables the CGB-only registers, chosen when the cartridge is inserted
+    pub fn set_cgb_mode(&mut self, enabled: bool) {
+        self.cgb = enabled;
+        self.compat = false;
+        self.vram_bank = 0;
+    }
+
+    // Colours DMG output the way the CGB boot ROM sets up palette RAM for games without CGB support
+    pub fn set_compat_palettes(&mut self, palettes: CompatPalettes) {
+        self.compat = true;
+        Self::write_palette(&mut self.bg_palettes, 0, palettes.bg);
+        Self::write_palette(&mut self.obj_palettes, 0, palettes.obj0);
+        Self::write_palette(&mut self.obj_palettes, 1, palettes.obj1);
+    }
+
+    pub fn set_color_correction(&mut self, enabled: bool) {
+        self.color_correction = enabled;
+    }
+
+    // Registers as they come out of reset, with the LCD off
+    pub fn power_on(&mut self) {
+        self.lcdc = 0;
+        self.stat = 0x80;
+        self.bgp = 0;
+        self.obp0 = 0;
+        self.obp1 = 0;
+        self.ly = 0;
+        self.mode = 0;
+        self.dots = 0;
+        self.stat_line = false;
+        self.off_dots = 0;
+    }
+
+    // Advances the PPU by a single dot
     pub fn tick(&mut self) {
-        // TODO
+        if !self.lcd_enabled() {
+            self.off_dots += 1;
+            if self.off_dots == DOTS_PER_FRAME {
+                self.off_dots = 0;
+                self.frame_ready = true;
+            }
+            return;
+        }
+
+        self.dots += 1;
+
+        match self.mode {
+            // OAM Scan
+            2 => {
+                if self.dots == OAM_SCAN_DOTS {
+                    self.start_drawing();
+                }
+            }
+            // Drawing
+            3 => {
+                let done = if self.render_mode == RenderMode::Scanline {
+                    self.dots == OAM_SCAN_DOTS + DRAWING_DOTS
+                } else {
+                    self.fifo_tick()
+                };
+                if done {
+                    self.set_mode(0);
+                    self.hblank_started = true;
+                }
+            }
+            // HBlank
+            0 => {
+                if self.first_line && self.dots == OAM_SCAN_DOTS {
+                    self.first_line = false;
+                    self.start_drawing();
+                }
+                else if self.dots == DOTS_PER_LINE {
+                    self.next_line();
+                    if self.ly == VBLANK_LINE {
+                        self.set_mode(1);
+                        self.frame_ready = true;
+                        self.interrupts |= 0b00001;
+                        if self.first_frame {
+                            self.first_frame = false;
+                            self.clear_screen();
+                        }
+                    } else {
+                        self.set_mode(2);
+                    }
+                }
+            }
+            // VBlank
+            _ => {
+                if self.dots == DOTS_PER_LINE {
+                    self.next_line();
+                    if self.ly == 0 {
+                        self.window_line = 0;
+                        self.window_triggered = false;
+                        self.set_mode(2);
+                    }
+                }
+            }
+        }
+
+        self.update_stat_line();
+    }
+
+    // Requests a STAT interrupt when any enabled source becomes active while none were before
+    fn update_stat_line(&mut self) {
+        let line = (self.stat & 0x40 != 0 && self.stat & 0b100 != 0)
+            || (self.stat & 0x20 != 0 && self.mode == 2)
+            || (self.stat & 0x10 != 0 && self.mode == 1)
+            || (self.stat & 0x08 != 0 && self.mode == 0);
+        if line && !self.stat_line {
+            self.interrupts |= 0b00010;
+        }
+        self.stat_line = line;
+    }
+
+    // Returns the interrupt flags raised since the last call
+    pub fn take_interrupts(&mut self) -> u8 {
+        let interrupts = self.interrupts;
+        self.interrupts = 0;
+        interrupts
+    }
+
+    // Returns true once each time the PPU enters HBlank after drawing a line
+    pub fn take_hblank(&mut self) -> bool {
+        let started = self.hblank_started;
+        self.hblank_started = false;
+        started
+    }
+
+    // The render mode is a frontend setting and stays as it is
+    pub fn save_state(&self, w: &mut StateWriter) {
+        for register in [self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc, self.bgp, self.obp0, self.obp1, self.wy, self.wx] {
+            w.u8(register);
+        }
+        w.bytes(&self.vram);
+        w.u8(self.vram_bank);
+        w.bytes(&self.oam);
+        w.u8(self.mode);
+        w.u16(self.dots);
+        w.bytes(&self.framebuffer);
+        for color in self.color_framebuffer {
+            w.u16(color);
+        }
+        w.bytes(&self.bg_line);
+        w.bytes(&self.bg_attributes);
+        w.bytes(&self.bg_palettes);
+        w.bytes(&self.obj_palettes);
+        w.u8(self.bcps);
+        w.u8(self.ocps);
+        w.u8(self.window_line);
+        w.bool(self.window_triggered);
+        w.bool(self.frame_ready);
+        w.bytes(&self.line_sprites);
+        w.u8(self.sprite_count as u8);
+        self.fifo.save_state(w);
+        w.bool(self.stat_line);
+        w.u8(self.interrupts);
+        w.bool(self.hblank_started);
+        w.bool(self.first_line);
+        w.bool(self.first_frame);
+        w.u32(self.off_dots);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        for register in [
+            &mut self.lcdc, &mut self.stat, &mut self.scy, &mut self.scx, &mut self.ly, &mut self.lyc,
+            &mut self.bgp, &mut self.obp0, &mut self.obp1, &mut self.wy, &mut self.wx,
+        ] {
+            *register = r.u8()?;
+        }
+        r.bytes(&mut self.vram)?;
+        self.vram_bank = r.u8()? & 1;
+        r.bytes(&mut self.oam)?;
+        self.mode = r.u8()? & 0b11;
+        self.dots = r.u16()?;
+        r.bytes(&mut self.framebuffer)?;
+        for color in &mut self.color_framebuffer {
+            *color = r.u16()? & 0x7FFF;
+        }
+        r.bytes(&mut self.bg_line)?;
+        r.bytes(&mut self.bg_attributes)?;
+        r.bytes(&mut self.bg_palettes)?;
+        r.bytes(&mut self.obj_palettes)?;
+        self.bcps = r.u8()? & 0xBF;
+        self.ocps = r.u8()? & 0xBF;
+        self.window_line = r.u8()?;
+        self.window_triggered = r.bool()?;
+        self.frame_ready = r.bool()?;
+        r.bytes(&mut self.line_sprites)?;
+        self.sprite_count = (r.u8()? as usize).min(MAX_SPRITES_PER_LINE);
+        if self.line_sprites[..self.sprite_count].iter().any(|&index| index >= 40) {
+            return Err(StateError::Invalid("sprite index out of range"));
+        }
+        self.fifo.load_state(r)?;
+        self.stat_line = r.bool()?;
+        self.interrupts = r.u8()?;
+        self.hblank_started = r.bool()?;
+        self.first_line = r.bool()?;
+        self.first_frame = r.bool()?;
+        self.off_dots = r.u32()?;
+        if self.ly >= LINES_PER_FRAME || self.dots > DOTS_PER_LINE {
+            return Err(StateError::Invalid("LCD position out of range"));
+        }
+        Ok(())
+    }
+
+    fn start_drawing(&mut self) {
+        self.oam_scan();
+        self.set_mode(3);
+        if self.render_mode == RenderMode::Scanline {
+            self.render_line();
+        } else {
+            self.fifo_start_line();
+        }
+    }
+
+    pub fn lcd_enabled(&self) -> bool {
+        self.lcdc & 0x80 != 0
+    }
+
+    // Handles LCDC.7 being toggled
+    fn set_lcd_enabled(&mut self, enabled: bool) {
+        if enabled {
+            // Restarts on a shortened line 0 that skips OAM scan
+            self.ly = 0;
+            self.dots = FIRST_LINE_SKIPPED_DOTS;
+            self.first_line = true;
+            self.first_frame = true;
+            self.window_line = 0;
+            self.window_triggered = self.wy == 0;
+            self.set_mode(0);
+        } else {
+            // LY is held at 0 and VRAM/OAM are unlocked while off
+            self.ly = 0;
+            self.dots = 0;
+            self.off_dots = 0;
+            self.first_line = false;
+            self.set_mode(0);
+            self.clear_screen();
+        }
+        self.compare_lyc();
+        self.update_stat_line();
+    }
+
+    // Blanks the screen, lightest shade on DMG and white on CGB
+    fn clear_screen(&mut self) {
+        self.framebuffer = [0; SCREEN_WIDTH * SCREEN_HEIGHT];
+        self.color_framebuffer = [CGB_WHITE; SCREEN_WIDTH * SCREEN_HEIGHT];
+    }
+
+    fn next_line(&mut self) {
+        self.dots = 0;
+        self.ly += 1;
+        if self.ly == LINES_PER_FRAME {
+            self.ly = 0;
+        }
+        self.compare_lyc();
+    }
+
+    fn set_mode(&mut self, mode: u8) {
+        self.mode = mode;
+        self.stat = (self.stat & 0xFC) | mode;
+        if mode == 2 && self.ly == self.wy {
+            self.window_triggered = true;
+        }
+    }
+
+    pub fn set_render_mode(&mut self, mode: RenderMode) {
+        self.render_mode = mode;
+    }
+
+    // Returns true once per frame, when the PPU has entered VBlank
+    pub fn take_frame(&mut self) -> bool {
+        let ready = self.frame_ready;
+        self.frame_ready = false;
+        ready
+    }
+
+    pub fn frame(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    // Framebuffer converted to packed RGB888, using the DMG palette or the RGB555 colours in CGB and compatibility mode
+    pub fn rgb_frame(&self) -> Vec<u8> {
+        if self.cgb || self.compat {
+            self.color_framebuffer.iter().flat_map(|&color| rgb555_to_rgb888(color, self.color_correction)).collect()
+        } else {
+            self.framebuffer.iter().flat_map(|&shade| DMG_PALETTE[shade as usize]).collect()
+        }
+    }
+
+    // RGB555 colour of a colour number in one of the 8 palettes of a palette RAM
+    fn palette_color(palettes: &[u8; 64], palette: u8, color: u8) -> u16 {
+        let index = (palette as usize & 0b111) * 8 + color as usize * 2;
+        u16::from_le_bytes([palettes[index], palettes[index + 1]]) & 0x7FFF
+    }
+
+    fn write_palette(palettes: &mut [u8; 64], palette: usize, colors: [u16; 4]) {
+        for (i, color) in colors.iter().enumerate() {
+            let index = palette * 8 + i * 2;
+            palettes[index..index + 2].copy_from_slice(&color.to_le_bytes());
+        }
+    }
+
+    // Writes a finished BG/window pixel to the framebuffer
+    fn put_bg_pixel(&mut self, x: usize, color: u8, attributes: u8) {
+        let pixel = self.ly as usize * SCREEN_WIDTH + x;
+        if self.cgb {
+            self.framebuffer[pixel] = color;
+            self.color_framebuffer[pixel] = Self::palette_color(&self.bg_palettes, attributes, color);
+        } else {
+            let shade = (self.bgp >> (color * 2)) & 0b11;
+            self.framebuffer[pixel] = shade;
+            if self.compat {
+                self.color_framebuffer[pixel] = Self::palette_color(&self.bg_palettes, 0, shade);
+            }
+        }
+    }
+
+    // Writes a finished sprite pixel to the framebuffer, palette is 0-1 for OBP0/OBP1 or a CGB palette number
+    fn put_sprite_pixel(&mut self, x: usize, color: u8, palette: u8) {
+        let pixel = self.ly as usize * SCREEN_WIDTH + x;
+        if self.cgb {
+            self.framebuffer[pixel] = color;
+            self.color_framebuffer[pixel] = Self::palette_color(&self.obj_palettes, palette, color);
+        } else {
+            let shade = (if palette != 0 { self.obp1 } else { self.obp0 } >> (color * 2)) & 0b11;
+            self.framebuffer[pixel] = shade;
+            if self.compat {
+                self.color_framebuffer[pixel] = Self::palette_color(&self.obj_palettes, palette, shade);
+            }
+        }
+    }
+
+    // Whether the BG/window pixel is drawn over a sprite pixel.
+    // On CGB clearing LCDC.0 takes priority away from the BG, otherwise either the sprite's or the tile's priority bit puts BG colours 1-3 on top
+    fn bg_over_sprite(&self, bg_color: u8, bg_attributes: u8, sprite_attributes: u8) -> bool {
+        if bg_color == 0 {
+            return false;
+        }
+        if self.cgb {
+            self.lcdc & 0x01 != 0 && (bg_attributes | sprite_attributes) & 0x80 != 0
+        } else {
+            sprite_attributes & 0x80 != 0
+        }
+    }
+
+    // Draws the background and window for the current line
+    fn render_line(&mut self) {
+        let y = self.ly;
+        self.bg_line = [0; SCREEN_WIDTH];
+        self.bg_attributes = [0; SCREEN_WIDTH];
+
+        // LCDC.0 disables both background and window on DMG, on CGB it only removes their priority over sprites
+        if self.lcdc & 0x01 != 0 || self.cgb {
+            let bg_map = if self.lcdc & 0x08 != 0 { 0x1C00 } else { 0x1800 };
+            let bg_y = self.scy.wrapping_add(y);
+            for x in 0..SCREEN_WIDTH {
+                let bg_x = self.scx.wrapping_add(x as u8);
+                (self.bg_line[x], self.bg_attributes[x]) = self.tile_pixel(bg_map, bg_x, bg_y);
+            }
+
+            // Window is drawn from WX - 7 onwards
+            if self.lcdc & 0x20 != 0 && self.window_triggered && self.wx < 167 {
+                let win_map = if self.lcdc & 0x40 != 0 { 0x1C00 } else { 0x1800 };
+                let start = (self.wx as usize).saturating_sub(7);
+                for x in start..SCREEN_WIDTH {
+                    let win_x = (x + 7 - self.wx as usize) as u8;
+                    (self.bg_line[x], self.bg_attributes[x]) = self.tile_pixel(win_map, win_x, self.window_line);
+                }
+                self.window_line = self.window_line.wrapping_add(1);
+            }
+        }
+
+        for x in 0..SCREEN_WIDTH {
+            self.put_bg_pixel(x, self.bg_line[x], self.bg_attributes[x]);
+        }
+
+        if self.lcdc & 0x02 != 0 {
+            self.render_sprites();
+        }
+    }
+
+    fn sprite_height(&self) -> u8 {
+        if self.lcdc & 0x04 != 0 { 16 } else { 8 }
+    }
+
+    // Selects the first 10 sprites in OAM order that overlap the current line
+    fn oam_scan(&mut self) {
+        let height = self.sprite_height();
+        self.sprite_count = 0;
+        for index in 0..40 {
+            let sprite_y = self.oam[index * 4];
+            // OAM Y is offset by 16 so sprites can be partially above the screen
+            let top = sprite_y as i16 - 16;
+            let line = self.ly as i16;
+            if line >= top && line < top + height as i16 {
+                self.line_sprites[self.sprite_count] = index as u8;
+                self.sprite_count += 1;
+                if self.sprite_count == MAX_SPRITES_PER_LINE {
+                    break;
+                }
+            }
+        }
+    }
+
+    // Draws the sprites selected by OAM scan over the current line
+    fn render_sprites(&mut self) {
+        let height = self.sprite_height();
+
+        // On DMG the sprite with the smaller X wins, ties go to the lower OAM index. On CGB only the OAM index counts
+        let mut sprites = self.line_sprites;
+        let sprites = &mut sprites[..self.sprite_count];
+        if !self.cgb {
+            sprites.sort_by_key(|&index| (self.oam[index as usize * 4 + 1], index));
+        }
+
+        // Drawn in priority order, the first opaque sprite pixel at each X claims it
+        let mut drawn = [false; SCREEN_WIDTH];
+        for &index in sprites.iter() {
+            let base = index as usize * 4;
+            let sprite_y = self.oam[base];
+            let sprite_x = self.oam[base + 1];
+            let mut tile = self.oam[base + 2];
+            let attributes = self.oam[base + 3];
+
+            let mut tile_row = self.ly.wrapping_sub(sprite_y.wrapping_sub(16));
+            // Y flip
+            if attributes & 0x40 != 0 {
+                tile_row = height - 1 - tile_row;
+            }
+            // Bit 0 of the tile index is ignored in 8x16 mode
+            if height == 16 {
+                tile &= 0xFE;
+            }
+            let row_addr = self.sprite_bank(attributes) + tile as usize * 16 + tile_row as usize * 2;
+            let low = self.vram[row_addr];
+            let high = self.vram[row_addr + 1];
+            let palette = self.sprite_palette(attributes);
+
+            for column in 0..8u8 {
+                // OAM X is offset by 8 so sprites can be partially left of the screen
+                let x = sprite_x as i16 - 8 + column as i16;
+                if !(0..SCREEN_WIDTH as i16).contains(&x) || drawn[x as usize] {
+                    continue;
+                }
+                let x = x as usize;
+                // X flip
+                let bit = if attributes & 0x20 != 0 { column } else { 7 - column };
+                let color = (((high >> bit) & 1) << 1) | ((low >> bit) & 1);
+                // Colour 0 is transparent
+                if color == 0 {
+                    continue;
+                }
+                // Lower priority sprites cannot draw here even if this one is hidden behind the BG
+                drawn[x] = true;
+                if self.bg_over_sprite(self.bg_line[x], self.bg_attributes[x], attributes) {
+                    continue;
+                }
+                self.put_sprite_pixel(x, color, palette);
+            }
+        }
+    }
+
+    // Offset of the VRAM bank a sprite's tiles are read from, bank 1 is only selectable in CGB mode
+    fn sprite_bank(&self, attributes: u8) -> usize {
+        if self.cgb && attributes & 0x08 != 0 { 0x2000 } else { 0 }
+    }
+
+    // CGB palette number, or 0-1 for OBP0/OBP1 on DMG
+    fn sprite_palette(&self, attributes: u8) -> u8 {
+        if self.cgb { attributes & 0b111 } else { (attributes >> 4) & 1 }
+    }
+
+    // Colour index and CGB attributes of the pixel at (x, y) of the 256x256 tile map starting at map_base
+    fn tile_pixel(&self, map_base: usize, x: u8, y: u8) -> (u8, u8) {
+        let map_addr = map_base + (y as usize / 8) * 32 + (x as usize / 8);
+        let tile = self.vram[map_addr];
+        let attributes = self.bg_map_attributes(map_addr);
+        let row_addr = self.bg_tile_addr(tile, attributes) + self.bg_tile_row(y, attributes) * 2;
+        // X flip
+        let bit = if attributes & 0x20 != 0 { x % 8 } else { 7 - (x % 8) };
+        let low = (self.vram[row_addr] >> bit) & 1;
+        let high = (self.vram[row_addr + 1] >> bit) & 1;
+        ((high << 1) | low, attributes)
+    }
+
+    // In CGB mode bank 1 holds the palette, bank, flip and priority of every tile in the map at the same address
+    fn bg_map_attributes(&self, map_addr: usize) -> u8 {
+        if self.cgb { self.vram[0x2000 + map_addr] } else { 0 }
+    }
+
+    // VRAM address of a BG/window tile
+    fn bg_tile_addr(&self, tile: u8, attributes: u8) -> usize {
+        // LCDC.4 selects between unsigned addressing from 0x8000 and signed addressing from 0x9000
+        let tile_addr = if self.lcdc & 0x10 != 0 {
+            tile as usize * 16
+        } else {
+            (0x1000 + (tile as i8 as isize) * 16) as usize
+        };
+        if attributes & 0x08 != 0 { 0x2000 + tile_addr } else { tile_addr }
+    }
+
+    // Row within a BG/window tile for a line y of the tile map, applying Y flip
+    fn bg_tile_row(&self, y: u8, attributes: u8) -> usize {
+        let row = y as usize % 8;
+        if attributes & 0x40 != 0 { 7 - row } else { row }
+    }
+
+    // Updates the LY=LYC coincidence flag (STAT bit 2)
+    fn compare_lyc(&mut self) {
+        if self.ly == self.lyc {
+            self.stat |= 0b100;
+        } else {
+            self.stat &= !0b100;
+        }
+    }
+
+    // Index into vram of a 0x8000-0x9FFF address in the selected bank
+    fn vram_index(&self, addr: u16) -> usize {
+        self.vram_bank as usize * 0x2000 + (addr - 0x8000) as usize
+    }
+
+    // Whether an address belongs to the PPU, its registers are 0xFF40-0xFF4B plus the CGB VRAM bank and palette registers
+    fn is_ppu_addr(addr: u16) -> bool {
+        (0x8000..0xA000).contains(&addr) || (0xFE00..0xFEA0).contains(&addr) || (0xFF40..=0xFF4B).contains(&addr)
+            || addr == 0xFF4F || (0xFF68..=0xFF6B).contains(&addr)
     }
 
     pub fn read(&mut self, addr: u16) -> u8 {
-        if (addr < 0x8000) || ((addr >= 0xA000) && (addr < 0xFE00)) || ((addr >= 0xFEA0) && (addr < 0xFF40)) || (addr > 0xFF4B) {
+        if !Self::is_ppu_addr(addr) {
             // Non-PPU address
             0xFF
         }
@@ -48,7 +683,7 @@ impl Video {
             if self.mode == 3 {
                 0xFF
             } else {
-                self.vram[(addr - 0x8000) as usize]
+                self.vram[self.vram_index(addr)]
             }
         }
         else if addr < 0xFEA0 {
@@ -92,26 +727,56 @@ impl Video {
         else if addr == 0xFF4A {
             self.wy
         }
-        else {
+        else if addr == 0xFF4B {
             self.wx
         }
+        // The CGB registers read 0xFF outside CGB mode
+        else if !self.cgb {
+            0xFF
+        }
+        // VRAM bank select, only bit 0 is readable
+        else if addr == 0xFF4F {
+            0xFE | self.vram_bank
+        }
+        else if addr == 0xFF68 {
+            self.bcps | 0x40
+        }
+        // Palette RAM is inaccessible while the PPU is drawing
+        else if addr == 0xFF69 {
+            if self.mode == 3 { 0xFF } else { self.bg_palettes[(self.bcps & 0x3F) as usize] }
+        }
+        else if addr == 0xFF6A {
+            self.ocps | 0x40
+        }
+        else if self.mode == 3 {
+            0xFF
+        }
+        else {
+            self.obj_palettes[(self.ocps & 0x3F) as usize]
+        }
     }
 
     pub fn write(&mut self, addr: u16, data: u8) {
-        if (addr < 0x8000) || ((addr >= 0xA000) && (addr < 0xFE00)) || ((addr >= 0xFEA0) && (addr < 0xFF40)) || (addr > 0xFF4B) {
+        if !Self::is_ppu_addr(addr) {
             // Non-PPU address
         }
         else if addr < 0xA000 {
-            self.vram[(addr - 0x8000) as usize] = data;
+            let index = self.vram_index(addr);
+            self.vram[index] = data;
         }
         else if addr < 0xFEA0 {
             self.oam[(addr - 0xFE00) as usize] = data;
         }
         else if addr == 0xFF40 {
+            let was_enabled = self.lcd_enabled();
             self.lcdc = data;
+            if was_enabled != self.lcd_enabled() {
+                self.set_lcd_enabled(!was_enabled);
+            }
         }
         else if addr == 0xFF41 {
             self.stat = data | 0x80 | (self.stat & 0x7);
+            self.update_stat_line();
         }
         else if addr == 0xFF42 {    
             self.scy = data;
@@ -124,6 +789,8 @@ impl Video {
         }
         else if addr == 0xFF45 {
             self.lyc = data;
+            self.compare_lyc();
+            self.update_stat_line();
         }
         else if addr == 0xFF46 {
             // Handled in memory unit
@@ -140,8 +807,325 @@ impl Video {
         else if addr == 0xFF4A {
             self.wy = data;
         }
-        else {
+        else if addr == 0xFF4B {
             self.wx = data;
         }
+        else if !self.cgb {
+            // CGB registers are ignored outside CGB mode
+        }
+        else if addr == 0xFF4F {
+            self.vram_bank = data & 1;
+        }
+        else if addr == 0xFF68 {
+            self.bcps = data & 0xBF;
+        }
+        else if addr == 0xFF69 {
+            if self.mode != 3 {
+                self.bg_palettes[(self.bcps & 0x3F) as usize] = data;
+            }
+            // The index still advances when the write is blocked
+            if self.bcps & 0x80 != 0 {
+                self.bcps = (self.bcps & 0x80) | (self.bcps.wrapping_add(1) & 0x3F);
+            }
+        }
+        else if addr == 0xFF6A {
+            self.ocps = data & 0xBF;
+        }
+        else {
+            if self.mode != 3 {
+                self.obj_palettes[(self.ocps & 0x3F) as usize] = data;
+            }
+            if self.ocps & 0x80 != 0 {
+                self.ocps = (self.ocps & 0x80) | (self.ocps.wrapping_add(1) & 0x3F);
+            }
+        }
     }
-}
\ No newline at end of file
+}
+
+// Expands a 5 bit per channel colour to RGB888. With correction the channels are mixed and the range compressed
+// like the CGB's LCD does, which makes fully saturated colours look much less harsh
+pub fn rgb555_to_rgb888(color: u16, correction: bool) -> [u8; 3] {
+    let r = (color & 0x1F) as u32;
+    let g = ((color >> 5) & 0x1F) as u32;
+    let b = ((color >> 10) & 0x1F) as u32;
+    if correction {
+        [
+            ((r * 26 + g * 4 + b * 2).min(960) >> 2) as u8,
+            ((g * 24 + b * 8).min(960) >> 2) as u8,
+            ((r * 6 + g * 4 + b * 22).min(960) >> 2) as u8,
+        ]
+    } else {
+        [r, g, b].map(|channel| ((channel << 3) | (channel >> 2)) as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(video: &mut Video, dots: u32) {
+        for _ in 0..dots {
+            video.tick();
+        }
+    }
+
+    fn mode(video: &mut Video) -> u8 {
+        video.read(0xFF41) & 0b11
+    }
+
+    #[test]
+    fn steps_through_the_modes_of_a_line() {
+        let mut video = Video::new();
+        run(&mut video, 80);
+        assert_eq!(mode(&mut video), 3);
+        run(&mut video, 172);
+        assert_eq!(mode(&mut video), 0);
+        run(&mut video, 203);
+        assert_eq!((mode(&mut video), video.read(0xFF44)), (0, 0));
+        run(&mut video, 1);
+        assert_eq!((mode(&mut video), video.read(0xFF44)), (2, 1));
+    }
+
+    #[test]
+    fn enters_vblank_at_line_144_and_wraps_after_154() {
+        let mut video = Video::new();
+        run(&mut video, 144 * 456);
+        assert_eq!((mode(&mut video), video.read(0xFF44)), (1, 144));
+        run(&mut video, 10 * 456 - 1);
+        assert_eq!((mode(&mut video), video.read(0xFF44)), (1, 153));
+        run(&mut video, 1);
+        assert_eq!((mode(&mut video), video.read(0xFF44)), (2, 0));
+    }
+
+    #[test]
+    fn flags_ly_matching_lyc() {
+        let mut video = Video::new();
+        video.write(0xFF45, 2);
+        assert_eq!(video.read(0xFF41) & 0b100, 0);
+        run(&mut video, 2 * 456);
+        assert_eq!(video.read(0xFF41) & 0b100, 0b100);
+        run(&mut video, 456);
+        assert_eq!(video.read(0xFF41) & 0b100, 0);
+    }
+
+    #[test]
+    fn blocks_vram_in_mode_3_and_oam_in_modes_2_and_3() {
+        let mut video = Video::new();
+        video.write(0x8000, 0x12);
+        video.write(0xFE00, 0x34);
+        assert_eq!((video.read(0x8000), video.read(0xFE00)), (0x12, 0xFF));
+        run(&mut video, 80);
+        assert_eq!((video.read(0x8000), video.read(0xFE00)), (0xFF, 0xFF));
+        run(&mut video, 172);
+        assert_eq!((video.read(0x8000), video.read(0xFE00)), (0x12, 0x34));
+    }
+
+    fn render_frame(video: &mut Video) {
+        while !video.take_frame() {
+            video.tick();
+        }
+    }
+
+    // Tile 1 is solid colour 1 and sits at the top left of both tile maps
+    fn video_with_tile() -> Video {
+        let mut video = Video::new();
+        for row in 0..8 {
+            video.write(0x8010 + row * 2, 0xFF);
+        }
+        video.write(0x9800, 0x01);
+        video.write(0xFF47, 0xE4);
+        video
+    }
+
+    #[test]
+    fn draws_background_tiles_with_scrolling() {
+        let mut video = video_with_tile();
+        video.write(0xFF43, 4);
+        render_frame(&mut video);
+        let frame = video.frame();
+        assert_eq!(frame[..5], [1, 1, 1, 1, 0]);
+        assert_eq!(frame[3 * SCREEN_WIDTH + 3], 1);
+        assert_eq!(frame[8 * SCREEN_WIDTH], 0);
+    }
+
+    #[test]
+    fn draws_the_window_from_wx_minus_7() {
+        let mut video = video_with_tile();
+        video.write(0xFF4A, 8);
+        video.write(0xFF4B, 87);
+        video.write(0xFF40, 0xB1);
+        render_frame(&mut video);
+        let frame = video.frame();
+        assert_eq!(frame[0], 1);
+        assert_eq!(frame[7 * SCREEN_WIDTH + 80], 0);
+        assert_eq!(frame[8 * SCREEN_WIDTH + 79..8 * SCREEN_WIDTH + 89], [0, 1, 1, 1, 1, 1, 1, 1, 1, 0]);
+    }
+
+    fn set_sprite(video: &mut Video, index: u16, y: u8, x: u8, tile: u8, attributes: u8) {
+        for (i, value) in [y, x, tile, attributes].into_iter().enumerate() {
+            video.write(0xFE00 + index * 4 + i as u16, value);
+        }
+    }
+
+    #[test]
+    fn draws_at_most_10_sprites_per_line() {
+        let mut video = video_with_tile();
+        video.write(0x9800, 0x00);
+        video.write(0xFF48, 0xFF);
+        video.write(0xFF40, 0x93);
+        for index in 0..11 {
+            set_sprite(&mut video, index, 16, 8 + index as u8 * 8, 1, 0);
+        }
+        render_frame(&mut video);
+        let frame = video.frame();
+        assert_eq!(frame[9 * 8..9 * 8 + 8], [3; 8]);
+        assert_eq!(frame[10 * 8..10 * 8 + 8], [0; 8]);
+        assert_eq!(frame[8 * SCREEN_WIDTH], 0);
+    }
+
+    #[test]
+    fn gives_overlapping_sprites_to_the_smaller_x() {
+        let mut video = video_with_tile();
+        video.write(0x9800, 0x00);
+        video.write(0xFF48, 0xFF);
+        video.write(0xFF49, 0xE4);
+        video.write(0xFF40, 0x93);
+        set_sprite(&mut video, 0, 16, 20, 1, 0x10);
+        set_sprite(&mut video, 1, 16, 16, 1, 0x00);
+        render_frame(&mut video);
+        assert_eq!(video.frame()[8..20], [3, 3, 3, 3, 3, 3, 3, 3, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn hides_background_priority_sprites_behind_non_zero_colours() {
+        let mut video = video_with_tile();
+        video.write(0xFF48, 0xFF);
+        video.write(0xFF40, 0x93);
+        set_sprite(&mut video, 0, 16, 12, 1, 0x80);
+        render_frame(&mut video);
+        assert_eq!(video.frame()[..12], [1, 1, 1, 1, 1, 1, 1, 1, 3, 3, 3, 3]);
+    }
+
+    // VBlank and STAT interrupts requested over the next frame's worth of dots
+    fn count_interrupts(video: &mut Video) -> (u32, u32) {
+        video.take_interrupts();
+        let (mut vblank, mut stat) = (0, 0);
+        for _ in 0..154 * 456 {
+            video.tick();
+            let interrupts = video.take_interrupts();
+            vblank += (interrupts & 0b01 != 0) as u32;
+            stat += (interrupts & 0b10 != 0) as u32;
+        }
+        (vblank, stat)
+    }
+
+    #[test]
+    fn requests_vblank_once_per_frame() {
+        assert_eq!(count_interrupts(&mut Video::new()), (1, 0));
+    }
+
+    #[test]
+    fn requests_stat_on_each_enabled_source() {
+        let mut video = Video::new();
+        video.write(0xFF41, 0x08);
+        assert_eq!(count_interrupts(&mut video), (1, 144));
+
+        let mut video = Video::new();
+        video.write(0xFF45, 10);
+        video.write(0xFF41, 0x40);
+        assert_eq!(count_interrupts(&mut video), (1, 1));
+    }
+
+    #[test]
+    fn only_requests_stat_on_a_rising_edge_of_the_combined_line() {
+        // HBlank hands over straight to OAM scan, so mode 2 only fires after VBlank
+        let mut video = Video::new();
+        video.write(0xFF41, 0x28);
+        assert_eq!(count_interrupts(&mut video), (1, 145));
+    }
+
+    #[test]
+    fn holds_ly_at_0_while_the_lcd_is_off() {
+        let mut video = Video::new();
+        run(&mut video, 10 * 456 + 100);
+        video.write(0xFF40, 0x11);
+        assert_eq!((mode(&mut video), video.read(0xFF44)), (0, 0));
+        run(&mut video, 154 * 456 - 1);
+        assert_eq!(video.read(0xFF44), 0);
+        assert!(!video.take_frame());
+        // Frames keep coming at the normal rate so the frontend doesn't stall
+        run(&mut video, 1);
+        assert!(video.take_frame());
+    }
+
+    #[test]
+    fn restarts_on_a_short_line_0_and_hides_the_first_frame() {
+        let mut video = video_with_tile();
+        video.write(0xFF40, 0x11);
+        video.write(0xFF40, 0x91);
+        assert_eq!(mode(&mut video), 0);
+        run(&mut video, 75);
+        assert_eq!(mode(&mut video), 0);
+        run(&mut video, 1);
+        assert_eq!(mode(&mut video), 3);
+
+        render_frame(&mut video);
+        assert!(video.frame().iter().all(|&shade| shade == 0));
+        render_frame(&mut video);
+        assert_eq!(video.frame()[0], 1);
+    }
+
+    #[test]
+    fn auto_increments_palette_writes_only() {
+        let mut video = Video::new();
+        video.set_cgb_mode(true);
+        video.write(0xFF40, 0x00);
+        video.write(0xFF68, 0xBE);
+        video.write(0xFF69, 0x11);
+        video.write(0xFF69, 0x22);
+        // The index wraps within the 64 bytes and bit 6 always reads set
+        assert_eq!(video.read(0xFF68), 0xC0);
+        video.write(0xFF68, 0x3F);
+        assert_eq!(video.read(0xFF69), 0x22);
+        assert_eq!(video.read(0xFF68), 0x7F);
+        video.write(0xFF69, 0x33);
+        assert_eq!(video.read(0xFF68), 0x7F);
+        assert_eq!(video.read(0xFF69), 0x33);
+
+        video.write(0xFF6A, 0x80);
+        video.write(0xFF6B, 0x44);
+        video.write(0xFF6B, 0x55);
+        video.write(0xFF6A, 0x01);
+        assert_eq!(video.read(0xFF6B), 0x55);
+        assert_eq!(video.read(0xFF6A), 0x41);
+    }
+
+    #[test]
+    fn colours_background_tiles_with_the_palette_from_their_attributes() {
+        let mut video = video_with_tile();
+        video.set_cgb_mode(true);
+        video.write(0xFF4F, 0x01);
+        video.write(0x9800, 0x02);
+        video.write(0xFF4F, 0x00);
+        // Colour 1 of palette 2 is pure blue
+        video.write(0xFF68, 0x80 | 18);
+        video.write(0xFF69, 0x00);
+        video.write(0xFF69, 0x7C);
+        render_frame(&mut video);
+        let rgb = video.rgb_frame();
+        assert_eq!(rgb[..3], [0x00, 0x00, 0xFF]);
+        assert_eq!(video.frame()[0], 1);
+    }
+
+    #[test]
+    fn rejects_states_with_sprites_past_the_end_of_oam() {
+        let mut video = Video::new();
+        video.line_sprites[0] = 40;
+        video.sprite_count = 1;
+        let mut w = StateWriter::new(None);
+        video.save_state(&mut w);
+        let data = w.finish();
+        let mut r = StateReader::new(&data, None).unwrap();
+        assert!(matches!(Video::new().load_state(&mut r), Err(StateError::Invalid(_))));
+    }
+}
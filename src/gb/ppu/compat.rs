@@ -0,0 +1,167 @@
+use crate::gb::cart::header::CartridgeHeader;
+use crate::gb::joypad::ButtonState;
+
+// Palettes the CGB boot ROM gives games without CGB support, as RGB555 colours for BG, OBP0 and OBP1
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompatPalettes {
+    pub bg: [u16; 4],
+    pub obj0: [u16; 4],
+    pub obj1: [u16; 4],
+}
+
+// Every colour the boot ROM knows, 4 per palette
+const COLORS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000,
+    0x639F, 0x4279, 0x15B0, 0x04CB,
+    0x7FFF, 0x6E31, 0x454A, 0x0000,
+    0x7FFF, 0x1BEF, 0x0200, 0x0000,
+    0x7FFF, 0x421F, 0x1CF2, 0x0000,
+    0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000,
+    0x7FFF, 0x03EF, 0x01D6, 0x0000,
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000,
+    0x7E74, 0x03FF, 0x0180, 0x0000,
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B,
+    0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000,
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,
+    0x03ED, 0x7FFF, 0x255F, 0x0000,
+    0x036A, 0x021F, 0x03FF, 0x7FFF,
+    0x7FFF, 0x01DF, 0x0112, 0x0000,
+    0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000,
+    0x299F, 0x001A, 0x000C, 0x0000,
+    0x7FFF, 0x027F, 0x001F, 0x0000,
+    0x7FFF, 0x03E0, 0x0206, 0x0120,
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00,
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000,
+    0x03FF, 0x001F, 0x000C, 0x0000,
+    0x7FFF, 0x033F, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037F, 0x7FFF,
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000,
+    0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+// Starting colour of the OBP0, OBP1 and BG palettes of each combination.
+// Most start on a palette boundary, the few that don't borrow the last colour of the previous palette to get a dark first colour
+const COMBINATIONS: [[u8; 3]; 51] = [
+    [16, 16, 116], [72, 72, 72], [80, 80, 80], [96, 96, 96], [36, 36, 36],
+    [0, 0, 0], [108, 108, 108], [20, 20, 20], [48, 48, 48], [104, 104, 104],
+    [64, 32, 32], [16, 112, 112], [16, 8, 8], [12, 16, 16], [16, 116, 116],
+    [112, 16, 112], [8, 68, 8], [64, 64, 32], [16, 16, 28], [16, 16, 72],
+    [16, 16, 80], [76, 76, 36], [15, 15, 44], [68, 68, 8], [16, 16, 8],
+    [16, 16, 12], [112, 112, 0], [12, 12, 0], [0, 0, 4], [72, 88, 72],
+    [80, 88, 80], [96, 88, 96], [64, 88, 32], [68, 16, 52], [111, 0, 56],
+    [111, 16, 60], [76, 88, 36], [64, 112, 40], [16, 92, 112], [68, 88, 8],
+    [16, 0, 8], [16, 112, 12], [112, 12, 0], [12, 112, 16], [84, 112, 16],
+    [12, 112, 0], [100, 12, 112], [0, 112, 32], [16, 12, 112], [112, 12, 24],
+    [16, 112, 116],
+];
+
+// Title checksums of the licensed games the boot ROM recognises.
+// The entries from DUPLICATES_START on share a checksum with another game and also compare the 4th title letter
+const TITLE_CHECKSUMS: [u8; 94] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B, 0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+    0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+];
+const DUPLICATES_START: usize = 65;
+const DUPLICATE_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+// Combination picked for each entry of TITLE_CHECKSUMS
+const TITLE_COMBINATIONS: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44,
+    21, 32, 31, 20, 5, 33, 13, 14, 5, 29, 5, 18, 9, 3, 2, 26,
+    25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34,
+    5, 42, 6, 5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0,
+    39, 36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50, 17,
+    46, 6, 27, 0, 47, 41, 41, 0, 0, 19, 34, 23, 18, 29,
+];
+
+// Combination shown without any buttons held for games the boot ROM doesn't recognise
+const DEFAULT_COMBINATION: u8 = 0;
+
+// Combinations chosen by holding a direction, optionally with A or B, while the logo is shown.
+// Rows are Right, Left, Up and Down, columns are the direction alone, with A and with B
+const BUTTON_COMBINATIONS: [[u8; 3]; 4] = [
+    [1, 0, 6],
+    [48, 40, 7],
+    [5, 43, 28],
+    [8, 3, 49],
+];
+
+fn combination(index: u8) -> CompatPalettes {
+    let palette = |start: u8| -> [u16; 4] { COLORS[start as usize..start as usize + 4].try_into().unwrap() };
+    let [obj0, obj1, bg] = COMBINATIONS[index as usize];
+    CompatPalettes { bg: palette(bg), obj0: palette(obj0), obj1: palette(obj1) }
+}
+
+// Palettes the boot ROM picks for a cartridge. Only games licensed by Nintendo are looked up, everything else gets the default
+pub fn palettes_for_header(header: &CartridgeHeader) -> CompatPalettes {
+    if !header.is_nintendo() {
+        return combination(DEFAULT_COMBINATION);
+    }
+    let checksum = header.title_checksum();
+    let letter = header.raw_title[3];
+    let entry = TITLE_CHECKSUMS.iter().enumerate().position(|(i, &sum)| {
+        sum == checksum && (i < DUPLICATES_START || DUPLICATE_LETTERS[i - DUPLICATES_START] == letter)
+    });
+    combination(entry.map_or(DEFAULT_COMBINATION, |i| TITLE_COMBINATIONS[i]))
+}
+
+// Palettes selected manually with the buttons held during boot, None if they don't form one of the 12 combinations
+pub fn palettes_for_buttons(held: ButtonState) -> Option<CompatPalettes> {
+    let directions = [held.right, held.left, held.up, held.down];
+    if directions.iter().filter(|&&d| d).count() != 1 || (held.a && held.b) {
+        return None;
+    }
+    let row = directions.iter().position(|&d| d)?;
+    let column = if held.a { 1 } else if held.b { 2 } else { 0 };
+    Some(combination(BUTTON_COMBINATIONS[row][column]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gb::testing::rom;
+
+    fn header(title: &[u8], licensee: u8) -> CartridgeHeader {
+        let mut data = rom(&[]);
+        data[0x134..0x134 + title.len()].copy_from_slice(title);
+        data[0x14B] = licensee;
+        CartridgeHeader::parse(&data).unwrap()
+    }
+
+    #[test]
+    fn looks_up_licensed_games_by_title_checksum() {
+        let tetris = header(b"TETRIS", 0x01);
+        assert_eq!(tetris.title_checksum(), 0xDB);
+        assert_eq!(palettes_for_header(&tetris).bg, [0x7FFF, 0x03FF, 0x001F, 0x0000]);
+        // Other publishers always get the default
+        let default = combination(DEFAULT_COMBINATION);
+        assert_eq!(default.bg, [0x7FFF, 0x1BEF, 0x6180, 0x0000]);
+        assert_eq!(palettes_for_header(&header(b"TETRIS", 0x02)), default);
+        assert_eq!(palettes_for_header(&header(b"DEMO", 0x01)), default);
+    }
+
+    #[test]
+    fn tells_shared_checksums_apart_by_the_4th_letter() {
+        // 0xB3 is shared by three games
+        let palettes = palettes_for_header(&header(b"AAAU\x9B", 0x01));
+        assert_eq!(palettes.bg, [0x7FFF, 0x42B5, 0x3DC8, 0x0000]);
+        assert_eq!(palettes.obj0, [0x7FFF, 0x01DF, 0x0112, 0x0000]);
+        assert_eq!(palettes_for_header(&header(b"AAAX\x98", 0x01)), combination(DEFAULT_COMBINATION));
+    }
+
+    #[test]
+    fn picks_manual_palettes_from_one_direction_and_a_or_b() {
+        let palettes = palettes_for_buttons(ButtonState { left: true, a: true, ..Default::default() }).unwrap();
+        assert_eq!(palettes.bg, [0x7FFF, 0x6E31, 0x454A, 0x0000]);
+        assert_eq!(palettes_for_buttons(ButtonState { up: true, down: true, ..Default::default() }), None);
+        assert_eq!(palettes_for_buttons(ButtonState { up: true, a: true, b: true, ..Default::default() }), None);
+    }
+}
@@ -0,0 +1,457 @@
+use std::collections::VecDeque;
+
+use crate::gb::ppu::{Video, SCREEN_WIDTH};
+use crate::gb::state::{StateError, StateReader, StateWriter};
+
+// Dots taken by each of the fetcher's tile, data low and data high steps
+const FETCH_STEP_DOTS: u8 = 2;
+// Dots spent fetching the sprite's tile data once the background fetcher is ready
+const SPRITE_FETCH_DOTS: u8 = 6;
+
+#[derive(Clone, Copy)]
+struct BgPixel {
+    color: u8,
+    // CGB BG map attributes of the tile the pixel came from
+    attributes: u8,
+}
+
+#[derive(Clone, Copy)]
+struct SpritePixel {
+    color: u8,
+    // OAM attributes byte, palette and priority are taken from it when the pixel is output
+    attributes: u8,
+    // Decides which of two overlapping sprites is drawn in CGB mode
+    oam_index: u8,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FetchStep {
+    Tile,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+pub struct PixelFifo {
+    bg: VecDeque<BgPixel>,
+    sprites: VecDeque<SpritePixel>,
+    step: FetchStep,
+    step_dots: u8,
+    // Tile column the fetcher will read next, relative to the start of the BG or window
+    fetch_x: u8,
+    tile: u8,
+    attributes: u8,
+    data_low: u8,
+    data_high: u8,
+    // Pixels already sent to the LCD on this line
+    lcd_x: usize,
+    // Pixels still to be dropped for SCX fine scroll or a window with WX < 7
+    discard: u8,
+    // Dots remaining before the fetcher may start, used for the discarded first fetch of a line
+    stall: u8,
+    in_window: bool,
+    // Set for each selected sprite once its pixels have been fetched this line
+    sprite_fetched: [bool; 10],
+    sprite_dots: u8,
+}
+
+impl PixelFifo {
+    pub fn new() -> Self {
+        Self {
+            bg: VecDeque::with_capacity(16),
+            sprites: VecDeque::with_capacity(8),
+            step: FetchStep::Tile,
+            step_dots: 0,
+            fetch_x: 0,
+            tile: 0,
+            attributes: 0,
+            data_low: 0,
+            data_high: 0,
+            lcd_x: 0,
+            discard: 0,
+            stall: 0,
+            in_window: false,
+            sprite_fetched: [false; 10],
+            sprite_dots: 0,
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.bg.len() as u8);
+        for pixel in &self.bg {
+            w.u8(pixel.color);
+            w.u8(pixel.attributes);
+        }
+        w.u8(self.sprites.len() as u8);
+        for pixel in &self.sprites {
+            w.u8(pixel.color);
+            w.u8(pixel.attributes);
+            w.u8(pixel.oam_index);
+        }
+        w.u8(self.step as u8);
+        w.u8(self.step_dots);
+        w.u8(self.fetch_x);
+        w.u8(self.tile);
+        w.u8(self.attributes);
+        w.u8(self.data_low);
+        w.u8(self.data_high);
+        w.u8(self.lcd_x as u8);
+        w.u8(self.discard);
+        w.u8(self.stall);
+        w.bool(self.in_window);
+        for fetched in self.sprite_fetched {
+            w.bool(fetched);
+        }
+        w.u8(self.sprite_dots);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.bg.clear();
+        for _ in 0..r.u8()? {
+            let color = r.u8()? & 0b11;
+            self.bg.push_back(BgPixel { color, attributes: r.u8()? });
+        }
+        self.sprites.clear();
+        for _ in 0..r.u8()? {
+            let color = r.u8()? & 0b11;
+            self.sprites.push_back(SpritePixel { color, attributes: r.u8()?, oam_index: r.u8()? });
+        }
+        self.step = match r.u8()? {
+            0 => FetchStep::Tile,
+            1 => FetchStep::DataLow,
+            2 => FetchStep::DataHigh,
+            3 => FetchStep::Push,
+            _ => return Err(StateError::Invalid("unknown fetcher step")),
+        };
+        self.step_dots = r.u8()?;
+        self.fetch_x = r.u8()?;
+        self.tile = r.u8()?;
+        self.attributes = r.u8()?;
+        self.data_low = r.u8()?;
+        self.data_high = r.u8()?;
+        self.lcd_x = (r.u8()? as usize).min(SCREEN_WIDTH);
+        self.discard = r.u8()?;
+        self.stall = r.u8()?;
+        self.in_window = r.bool()?;
+        for fetched in &mut self.sprite_fetched {
+            *fetched = r.bool()?;
+        }
+        self.sprite_dots = r.u8()?;
+        Ok(())
+    }
+}
+
+impl Video {
+    // Resets the FIFO and fetcher at the start of mode 3
+    pub(super) fn fifo_start_line(&mut self) {
+        let fifo = &mut self.fifo;
+        fifo.bg.clear();
+        fifo.sprites.clear();
+        fifo.step = FetchStep::Tile;
+        fifo.step_dots = 0;
+        fifo.fetch_x = 0;
+        fifo.lcd_x = 0;
+        fifo.discard = self.scx & 7;
+        // The first tile fetched on every line is thrown away
+        fifo.stall = FETCH_STEP_DOTS * 3;
+        fifo.in_window = false;
+        fifo.sprite_fetched = [false; 10];
+        fifo.sprite_dots = 0;
+    }
+
+    // Advances mode 3 by a single dot, returns true once all 160 pixels have been output
+    pub(super) fn fifo_tick(&mut self) -> bool {
+        if self.fifo.stall > 0 {
+            self.fifo.stall -= 1;
+            return false;
+        }
+
+        // Window activation restarts the fetcher on the window tile map.
+        // LCDC.0 also disables the window on DMG, which then doesn't advance its line counter either
+        let window_enabled = self.lcdc & 0x20 != 0 && (self.lcdc & 0x01 != 0 || self.cgb);
+        if !self.fifo.in_window && self.window_triggered && window_enabled && self.fifo.lcd_x + 7 >= self.wx as usize {
+            self.fifo.in_window = true;
+            self.fifo.bg.clear();
+            self.fifo.step = FetchStep::Tile;
+            self.fifo.step_dots = 0;
+            self.fifo.fetch_x = 0;
+            // A window from the left edge replaces whatever fine scroll was still being discarded
+            if self.fifo.lcd_x == 0 {
+                self.fifo.discard = 7u8.saturating_sub(self.wx);
+            }
+        }
+
+        // A sprite at the current X pauses pixel output while its data is fetched
+        if self.fifo.discard == 0 && self.lcdc & 0x02 != 0 && let Some(slot) = self.pending_sprite() {
+            if self.fifo.step != FetchStep::Push || self.fifo.bg.is_empty() {
+                self.fetcher_tick();
+            } else {
+                self.fifo.sprite_dots += 1;
+                if self.fifo.sprite_dots == SPRITE_FETCH_DOTS {
+                    self.fifo.sprite_dots = 0;
+                    self.fifo.sprite_fetched[slot] = true;
+                    self.fetch_sprite(slot);
+                }
+            }
+            return false;
+        }
+
+        self.fetcher_tick();
+
+        let Some(bg) = self.fifo.bg.pop_front() else {
+            return false;
+        };
+        let sprite = self.fifo.sprites.pop_front();
+
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return false;
+        }
+
+        // Registers are sampled as each pixel leaves the FIFO so mid-line writes land on the right pixel
+        let bg_color = if self.lcdc & 0x01 != 0 || self.cgb { bg.color } else { 0 };
+        let x = self.fifo.lcd_x;
+        if let Some(pixel) = sprite && pixel.color != 0 && self.lcdc & 0x02 != 0
+            && !self.bg_over_sprite(bg_color, bg.attributes, pixel.attributes)
+        {
+            self.put_sprite_pixel(x, pixel.color, self.sprite_palette(pixel.attributes));
+        } else {
+            self.put_bg_pixel(x, bg_color, bg.attributes);
+        }
+        self.fifo.lcd_x += 1;
+
+        if self.fifo.lcd_x == SCREEN_WIDTH {
+            if self.fifo.in_window {
+                self.window_line = self.window_line.wrapping_add(1);
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    // Index into line_sprites of the next sprite starting at the current LCD X, if any
+    fn pending_sprite(&self) -> Option<usize> {
+        (0..self.sprite_count).find(|&slot| {
+            let sprite_x = self.oam[self.line_sprites[slot] as usize * 4 + 1] as usize;
+            !self.fifo.sprite_fetched[slot] && sprite_x > 0 && sprite_x < 168 && sprite_x <= self.fifo.lcd_x + 8
+        })
+    }
+
+    fn fetcher_tick(&mut self) {
+        if self.fifo.step == FetchStep::Push {
+            // Pixels are only pushed once the FIFO has fully drained
+            if self.fifo.bg.is_empty() {
+                let attributes = self.fifo.attributes;
+                for column in 0..8 {
+                    // X flip
+                    let bit = if attributes & 0x20 != 0 { column } else { 7 - column };
+                    let color = (((self.fifo.data_high >> bit) & 1) << 1) | ((self.fifo.data_low >> bit) & 1);
+                    self.fifo.bg.push_back(BgPixel { color, attributes });
+                }
+                self.fifo.fetch_x = self.fifo.fetch_x.wrapping_add(1);
+                self.fifo.step = FetchStep::Tile;
+            }
+            return;
+        }
+
+        self.fifo.step_dots += 1;
+        if self.fifo.step_dots < FETCH_STEP_DOTS {
+            return;
+        }
+        self.fifo.step_dots = 0;
+
+        match self.fifo.step {
+            FetchStep::Tile => {
+                let map_addr = if self.fifo.in_window {
+                    let map = if self.lcdc & 0x40 != 0 { 0x1C00 } else { 0x1800 };
+                    map + (self.window_line as usize / 8) * 32 + (self.fifo.fetch_x as usize & 31)
+                } else {
+                    let map = if self.lcdc & 0x08 != 0 { 0x1C00 } else { 0x1800 };
+                    let y = self.scy.wrapping_add(self.ly) as usize;
+                    let x = ((self.scx as usize / 8) + self.fifo.fetch_x as usize) & 31;
+                    map + (y / 8) * 32 + x
+                };
+                self.fifo.tile = self.vram[map_addr];
+                self.fifo.attributes = self.bg_map_attributes(map_addr);
+                self.fifo.step = FetchStep::DataLow;
+            }
+            FetchStep::DataLow => {
+                self.fifo.data_low = self.vram[self.bg_tile_row_addr()];
+                self.fifo.step = FetchStep::DataHigh;
+            }
+            FetchStep::DataHigh => {
+                self.fifo.data_high = self.vram[self.bg_tile_row_addr() + 1];
+                self.fifo.step = FetchStep::Push;
+            }
+            FetchStep::Push => {}
+        }
+    }
+
+    // VRAM address of the current row of the tile being fetched, using the addressing mode in LCDC.4 and the tile's attributes
+    fn bg_tile_row_addr(&self) -> usize {
+        let y = if self.fifo.in_window {
+            self.window_line
+        } else {
+            self.scy.wrapping_add(self.ly)
+        };
+        self.bg_tile_addr(self.fifo.tile, self.fifo.attributes) + self.bg_tile_row(y, self.fifo.attributes) * 2
+    }
+
+    // Mixes a sprite's 8 pixels into the sprite FIFO, already occupied opaque pixels keep priority on DMG
+    fn fetch_sprite(&mut self, slot: usize) {
+        let height = self.sprite_height();
+        let oam_index = self.line_sprites[slot];
+        let base = oam_index as usize * 4;
+        let sprite_y = self.oam[base];
+        let sprite_x = self.oam[base + 1] as usize;
+        let mut tile = self.oam[base + 2];
+        let attributes = self.oam[base + 3];
+
+        let mut tile_row = self.ly.wrapping_sub(sprite_y.wrapping_sub(16));
+        if attributes & 0x40 != 0 {
+            tile_row = height - 1 - tile_row;
+        }
+        if height == 16 {
+            tile &= 0xFE;
+        }
+        let row_addr = self.sprite_bank(attributes) + tile as usize * 16 + tile_row as usize * 2;
+        let low = self.vram[row_addr];
+        let high = self.vram[row_addr + 1];
+
+        // Sprites partially off the left edge only contribute their visible columns
+        let skip = (self.fifo.lcd_x + 8).saturating_sub(sprite_x).min(8);
+        while self.fifo.sprites.len() < 8 - skip {
+            self.fifo.sprites.push_back(SpritePixel { color: 0, attributes: 0, oam_index: 0 });
+        }
+        for column in skip..8 {
+            let bit = if attributes & 0x20 != 0 { column } else { 7 - column };
+            let color = (((high >> bit) & 1) << 1) | ((low >> bit) & 1);
+            let existing = &mut self.fifo.sprites[column - skip];
+            // On CGB a lower OAM index wins even over a sprite fetched earlier
+            if existing.color == 0 || (self.cgb && color != 0 && oam_index < existing.oam_index) {
+                *existing = SpritePixel { color, attributes, oam_index };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gb::ppu::{RenderMode, Video};
+
+    // A few different tiles across the maps, a window and sprites with and without flips
+    fn busy_video(mode: RenderMode) -> Video {
+        let mut video = Video::new();
+        video.set_render_mode(mode);
+        for row in 0..8 {
+            video.write(0x8010 + row * 2, 0xFF);
+            video.write(0x8020 + row * 2, 0xAA);
+            video.write(0x8021 + row * 2, 0x0F << (row % 4));
+        }
+        for i in 0..0x800 {
+            video.write(0x9800 + i, (i % 3) as u8);
+        }
+        for (index, (y, x, attributes)) in [(20u8, 30u8, 0x00u8), (24, 34, 0x20), (40, 4, 0x90), (60, 100, 0x40)].into_iter().enumerate() {
+            for (i, value) in [y, x, 2, attributes].into_iter().enumerate() {
+                video.write(0xFE00 + index as u16 * 4 + i as u16, value);
+            }
+        }
+        video.write(0xFF42, 5);
+        video.write(0xFF43, 3);
+        video.write(0xFF47, 0xE4);
+        video.write(0xFF48, 0xD2);
+        video.write(0xFF49, 0x1B);
+        video.write(0xFF4A, 70);
+        video.write(0xFF4B, 60);
+        video.write(0xFF40, 0xF3);
+        video
+    }
+
+    fn render_frame(video: &mut Video) {
+        while !video.take_frame() {
+            video.tick();
+        }
+    }
+
+    // Dots spent in mode 3 on the first line
+    fn mode_3_dots(video: &mut Video) -> u32 {
+        while video.read(0xFF41) & 0b11 != 3 {
+            video.tick();
+        }
+        let mut dots = 0;
+        while video.read(0xFF41) & 0b11 == 3 {
+            video.tick();
+            dots += 1;
+        }
+        dots
+    }
+
+    #[test]
+    fn draws_the_same_picture_as_the_scanline_renderer() {
+        let mut scanline = busy_video(RenderMode::Scanline);
+        let mut fifo = busy_video(RenderMode::Fifo);
+        render_frame(&mut scanline);
+        render_frame(&mut fifo);
+        assert!(scanline.frame() == fifo.frame());
+    }
+
+    #[test]
+    fn starts_a_window_at_wx_7_without_the_fine_scroll_discard() {
+        let mut scanline = busy_video(RenderMode::Scanline);
+        let mut fifo = busy_video(RenderMode::Fifo);
+        for video in [&mut scanline, &mut fifo] {
+            video.write(0xFF4A, 0);
+            video.write(0xFF4B, 7);
+            video.write(0xFF43, 5);
+        }
+        // WY only matches from the second frame on
+        for _ in 0..2 {
+            render_frame(&mut scanline);
+            render_frame(&mut fifo);
+        }
+        assert!(scanline.frame() == fifo.frame());
+    }
+
+    fn fifo_video() -> Video {
+        let mut video = Video::new();
+        video.set_render_mode(RenderMode::Fifo);
+        video
+    }
+
+    #[test]
+    fn lengthens_mode_3_for_fine_scroll_and_sprites() {
+        assert_eq!(mode_3_dots(&mut fifo_video()), 172);
+
+        let mut video = fifo_video();
+        video.write(0xFF43, 3);
+        assert_eq!(mode_3_dots(&mut video), 175);
+
+        let mut video = fifo_video();
+        video.write(0xFF40, 0x93);
+        video.write(0xFE00, 16);
+        video.write(0xFE01, 20);
+        assert_eq!(mode_3_dots(&mut video), 181);
+    }
+
+    #[test]
+    fn keeps_the_window_line_still_while_lcdc_0_hides_the_window_on_dmg() {
+        for (mode, name) in [(RenderMode::Scanline, "scanline"), (RenderMode::Fifo, "fifo")] {
+            let mut video = Video::new();
+            video.set_render_mode(mode);
+            video.write(0xFF4A, 0);
+            video.write(0xFF4B, 7);
+            video.write(0xFF40, 0xA0);
+            // Into the second frame, where WY has matched on line 0
+            for _ in 0..(154 + 10) * 456 {
+                video.tick();
+            }
+            assert_eq!(video.window_line, 0, "{}", name);
+            video.write(0xFF40, 0xA1);
+            for _ in 0..10 * 456 {
+                video.tick();
+            }
+            assert_eq!(video.window_line, 10, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,354 @@
+mod png;
+
+use std::fs::{create_dir_all, write};
+use std::path::PathBuf;
+
+use crate::gb::printer::png::encode_grayscale;
+use crate::gb::serial::SerialDevice;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+
+// Status bits
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_PRINTING: u8 = 0x02;
+const STATUS_DATA_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED: u8 = 0x08;
+
+// A data packet carries two rows of 20 tiles, 16 pixel lines
+const BAND_BYTES: usize = 0x280;
+const BAND_LINES: usize = 16;
+// The print buffer holds 9 bands, a full screen
+const BUFFER_BYTES: usize = BAND_BYTES * 9;
+const PRINT_WIDTH: usize = 160;
+// Blank lines fed per unit of margin
+const MARGIN_LINES: usize = 8;
+// Time the head takes per printed line, keeps the busy flag up for roughly as long as real hardware
+const CYCLES_PER_LINE: u32 = 4_194_304 / 100;
+
+// Used by print commands whose palette byte is 0x00
+const DEFAULT_PALETTE: u8 = 0xE4;
+
+// Grayscale values for the four print shades, white to black
+const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+// Position within a packet: 0x88 0x33, command, compression, length, data, checksum,
+// then two bytes where the printer answers with its ID and status
+#[derive(Clone, Copy, PartialEq)]
+enum PacketStep {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+// Game Boy Printer on the link port. Each finished sheet is written to the output directory as a PNG,
+// prints without a bottom margin are joined onto the next one like on the paper roll
+pub struct Printer {
+    directory: PathBuf,
+    step: PacketStep,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+    status: u8,
+    // Decompressed 2bpp tile data waiting for a print command
+    buffer: Vec<u8>,
+    // Shades of the sheet printed so far, PRINT_WIDTH wide
+    sheet: Vec<u8>,
+    busy_cycles: u32,
+    printed: u32,
+}
+
+impl Printer {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+            step: PacketStep::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+            status: 0,
+            buffer: Vec::new(),
+            sheet: Vec::new(),
+            busy_cycles: 0,
+            printed: 0,
+        }
+    }
+
+    // Feeds one byte of a packet, returns the byte the printer shifts back
+    fn receive(&mut self, byte: u8) -> u8 {
+        let mut reply = 0x00;
+        self.step = match self.step {
+            PacketStep::Magic1 => if byte == 0x88 { PacketStep::Magic2 } else { PacketStep::Magic1 },
+            PacketStep::Magic2 => if byte == 0x33 { PacketStep::Command } else { PacketStep::Magic1 },
+            PacketStep::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                PacketStep::Compression
+            }
+            PacketStep::Compression => {
+                self.compressed = byte & 0x01 != 0;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                PacketStep::LengthLow
+            }
+            PacketStep::LengthLow => {
+                self.length = byte as u16;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                PacketStep::LengthHigh
+            }
+            PacketStep::LengthHigh => {
+                self.length |= (byte as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.data.clear();
+                if self.length == 0 { PacketStep::ChecksumLow } else { PacketStep::Data }
+            }
+            PacketStep::Data => {
+                self.data.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.data.len() == self.length as usize { PacketStep::ChecksumLow } else { PacketStep::Data }
+            }
+            PacketStep::ChecksumLow => {
+                self.received_checksum = byte as u16;
+                PacketStep::ChecksumHigh
+            }
+            PacketStep::ChecksumHigh => {
+                self.received_checksum |= (byte as u16) << 8;
+                PacketStep::Alive
+            }
+            PacketStep::Alive => {
+                // Device ID, bit 7 set means a printer is connected
+                reply = 0x81;
+                self.run_command();
+                PacketStep::Status
+            }
+            PacketStep::Status => {
+                reply = self.status;
+                PacketStep::Magic1
+            }
+        };
+        reply
+    }
+
+    fn run_command(&mut self) {
+        if self.checksum != self.received_checksum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+
+        match self.command {
+            COMMAND_INIT => {
+                self.buffer.clear();
+                self.status = 0;
+            }
+            COMMAND_DATA => {
+                // An empty data packet only marks the end of the image
+                if self.data.is_empty() {
+                    return;
+                }
+                let data = if self.compressed { decompress(&self.data) } else { self.data.clone() };
+                let free = BUFFER_BYTES - self.buffer.len();
+                self.buffer.extend_from_slice(&data[..data.len().min(free)]);
+                self.status |= STATUS_UNPROCESSED;
+                if self.buffer.len() == BUFFER_BYTES {
+                    self.status |= STATUS_DATA_FULL;
+                }
+            }
+            COMMAND_PRINT if self.data.len() == 4 => {
+                let (margins, palette) = (self.data[1], self.data[2]);
+                // Several games send 0x00, which the printer treats as the default palette
+                let palette = if palette == 0 { DEFAULT_PALETTE } else { palette };
+                self.print(margins, palette);
+            }
+            // Status requests (0x0F) are only answered with the status byte
+            _ => {}
+        }
+    }
+
+    // Prints the buffered image, feeding the top margin before and bottom margin after it.
+    // The sheet is only cut off and saved once there is a bottom margin
+    fn print(&mut self, margins: u8, palette: u8) {
+        let top = (margins >> 4) as usize * MARGIN_LINES;
+        let bottom = (margins & 0x0F) as usize * MARGIN_LINES;
+
+        self.feed(top);
+        let bands = self.buffer.len() / BAND_BYTES;
+        for band in 0..bands {
+            for line in 0..BAND_LINES {
+                for x in 0..PRINT_WIDTH {
+                    let tile = band * 40 + (line / 8) * 20 + x / 8;
+                    let addr = tile * 16 + (line % 8) * 2;
+                    let bit = 7 - (x % 8);
+                    let color = ((self.buffer[addr] >> bit) & 1) | (((self.buffer[addr + 1] >> bit) & 1) << 1);
+                    let shade = (palette >> (color * 2)) & 0b11;
+                    self.sheet.push(SHADES[shade as usize]);
+                }
+            }
+        }
+        self.feed(bottom);
+
+        let lines = top + bands * BAND_LINES + bottom;
+        self.busy_cycles = lines as u32 * CYCLES_PER_LINE;
+        self.buffer.clear();
+        self.status = STATUS_PRINTING;
+
+        if bottom > 0 {
+            self.save_sheet();
+        }
+    }
+
+    fn feed(&mut self, lines: usize) {
+        // Leading paper is only fed once something is printed
+        if !self.sheet.is_empty() {
+            self.sheet.resize(self.sheet.len() + lines * PRINT_WIDTH, SHADES[0]);
+        }
+    }
+
+    fn save_sheet(&mut self) {
+        let height = self.sheet.len() / PRINT_WIDTH;
+        if height == 0 {
+            return;
+        }
+        let png = encode_grayscale(PRINT_WIDTH, height, &self.sheet);
+        self.sheet.clear();
+
+        // Don't overwrite printouts from earlier sessions
+        let path = loop {
+            self.printed += 1;
+            let path = self.directory.join(format!("print_{:04}.png", self.printed));
+            if !path.exists() {
+                break path;
+            }
+        };
+        if let Err(e) = create_dir_all(&self.directory).and_then(|_| write(&path, png)) {
+            eprintln!("Unable to write printout {}: {}", path.display(), e);
+        }
+    }
+}
+
+// Expands the printer's run-length encoding: a control byte with bit 7 set repeats the next byte
+// (n & 0x7F) + 2 times, otherwise the next n + 1 bytes are copied as is
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(BAND_BYTES);
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        if control & 0x80 != 0 {
+            if let Some(&byte) = data.get(i) {
+                out.extend(std::iter::repeat_n(byte, (control & 0x7F) as usize + 2));
+            }
+            i += 1;
+        } else {
+            let end = (i + control as usize + 1).min(data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    out
+}
+
+impl SerialDevice for Printer {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        self.receive(outgoing)
+    }
+
+    fn clock(&mut self, cycles: u32) {
+        if self.busy_cycles == 0 {
+            return;
+        }
+        self.busy_cycles = self.busy_cycles.saturating_sub(cycles);
+        if self.busy_cycles == 0 {
+            self.status &= !STATUS_PRINTING;
+        }
+    }
+}
+
+// Flush whatever is still on the roll when the printer is unplugged
+impl Drop for Printer {
+    fn drop(&mut self) {
+        self.save_sheet();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sends a whole packet and returns the ID and status bytes the printer answers with
+    fn send(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> (u8, u8) {
+        let mut bytes = vec![command, compressed as u8];
+        bytes.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(data);
+        let checksum = bytes.iter().fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+        let mut packet = vec![0x88, 0x33];
+        packet.extend_from_slice(&bytes);
+        packet.extend_from_slice(&checksum.to_le_bytes());
+        for byte in packet {
+            printer.receive(byte);
+        }
+        (printer.receive(0), printer.receive(0))
+    }
+
+    #[test]
+    fn decompresses_runs_and_literals() {
+        assert_eq!(decompress(&[0x81, 0xAA, 0x02, 1, 2, 3, 0x80, 0x55]), [0xAA, 0xAA, 0xAA, 1, 2, 3, 0x55, 0x55]);
+        // Truncated input keeps what could be decoded
+        assert_eq!(decompress(&[0x01, 1, 2, 0x85]), [1, 2]);
+    }
+
+    #[test]
+    fn buffers_data_and_prints_a_band() {
+        let mut printer = Printer::new(std::env::temp_dir());
+        assert_eq!(send(&mut printer, COMMAND_INIT, false, &[]), (0x81, 0x00));
+        // One band of color 3, compressed as 5 runs of 128 bytes
+        let band = [0xFE, 0xFF].repeat(5);
+        let (_, status) = send(&mut printer, COMMAND_DATA, true, &band);
+        assert_eq!(status, STATUS_UNPROCESSED);
+        assert_eq!(printer.buffer.len(), BAND_BYTES);
+
+        let (_, status) = send(&mut printer, COMMAND_PRINT, false, &[0x01, 0x00, 0xE4, 0x40]);
+        assert_eq!(status, STATUS_PRINTING);
+        assert_eq!(printer.sheet.len(), BAND_LINES * PRINT_WIDTH);
+        assert!(printer.sheet.iter().all(|&shade| shade == SHADES[3]));
+
+        printer.clock(printer.busy_cycles);
+        assert_eq!(send(&mut printer, 0x0F, false, &[]).1, 0x00);
+        // Nothing to save on drop
+        printer.sheet.clear();
+    }
+
+    #[test]
+    fn flags_checksum_errors() {
+        let mut printer = Printer::new(std::env::temp_dir());
+        for byte in [0x88, 0x33, COMMAND_INIT, 0x00, 0x00, 0x00, 0x02, 0x00] {
+            printer.receive(byte);
+        }
+        assert_eq!(printer.receive(0), 0x81);
+        assert_eq!(printer.receive(0), STATUS_CHECKSUM_ERROR);
+    }
+
+    #[test]
+    fn prints_with_the_default_palette_when_given_0() {
+        let mut printer = Printer::new(std::env::temp_dir());
+        send(&mut printer, COMMAND_INIT, false, &[]);
+        send(&mut printer, COMMAND_DATA, true, &[0xFE, 0xFF].repeat(5));
+        send(&mut printer, COMMAND_PRINT, false, &[0x01, 0x00, 0x00, 0x40]);
+        assert!(printer.sheet.iter().all(|&shade| shade == SHADES[3]));
+        printer.sheet.clear();
+    }
+}
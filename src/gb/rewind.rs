@@ -0,0 +1,283 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::gb::state::StateError;
+use crate::gb::Gameboy;
+
+// Default history budget, several minutes of play for most games
+pub const DEFAULT_REWIND_BYTES: usize = 32 * 1024 * 1024;
+
+// An older snapshot stored as the difference to the snapshot taken after it
+struct Delta {
+    frame: u64,
+    len: usize,
+    data: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RewindStats {
+    // Snapshots held, including the newest full one
+    pub snapshots: usize,
+    // How far back the oldest snapshot is
+    pub frames: u64,
+    // Memory taken by the full snapshot and all deltas
+    pub bytes: usize,
+    pub average_delta_bytes: usize,
+    // Time taken to capture and compress the last snapshot
+    pub last_snapshot_time: Duration,
+}
+
+// Rewind history as a ring of save states. Only the newest snapshot is kept in full, every older one is
+// XORed against its successor and run-length encoded, so unchanged RAM costs almost nothing.
+// The oldest deltas are dropped once the memory budget is exceeded
+pub struct Rewind {
+    // Frames between snapshots, the granularity of rewinding
+    interval: u64,
+    max_bytes: usize,
+    frame: u64,
+    newest: Option<(u64, Vec<u8>)>,
+    deltas: VecDeque<Delta>,
+    delta_bytes: usize,
+    last_snapshot_time: Duration,
+}
+
+impl Rewind {
+    pub fn new(interval: u32, max_bytes: usize) -> Self {
+        Self {
+            interval: interval.max(1) as u64,
+            max_bytes,
+            frame: 0,
+            newest: None,
+            deltas: VecDeque::new(),
+            delta_bytes: 0,
+            last_snapshot_time: Duration::ZERO,
+        }
+    }
+
+    // Call once after every frame, snapshots the machine every interval frames
+    pub fn record(&mut self, gb: &Gameboy) {
+        self.frame += 1;
+        if self.newest.as_ref().is_some_and(|(frame, _)| self.frame - frame < self.interval) {
+            return;
+        }
+
+        let start = Instant::now();
+        let state = gb.save_state();
+        if let Some((frame, previous)) = self.newest.take() {
+            let data = encode_delta(&previous, &state);
+            self.delta_bytes += data.len();
+            self.deltas.push_back(Delta { frame, len: previous.len(), data });
+        }
+        self.newest = Some((self.frame, state));
+        self.trim();
+        self.last_snapshot_time = start.elapsed();
+    }
+
+    fn trim(&mut self) {
+        let newest_len = self.newest.as_ref().map_or(0, |(_, state)| state.len());
+        while newest_len + self.delta_bytes > self.max_bytes && let Some(oldest) = self.deltas.pop_front() {
+            self.delta_bytes -= oldest.data.len();
+        }
+    }
+
+    // Steps back at least the given number of frames, or as far as the history goes, and loads that snapshot.
+    // Returns how many frames were actually rewound, which is rounded to whole snapshot intervals.
+    // If the snapshot doesn't load, e.g. after another ROM was loaded, the history is cleared and gb is left as it was
+    pub fn rewind(&mut self, gb: &mut Gameboy, frames: u64) -> Result<u64, StateError> {
+        let Some((mut newest_frame, mut state)) = self.newest.take() else {
+            return Ok(0);
+        };
+        let target = self.frame.saturating_sub(frames);
+        while newest_frame > target && let Some(delta) = self.deltas.pop_back() {
+            self.delta_bytes -= delta.data.len();
+            state = decode_delta(&state, &delta.data, delta.len);
+            newest_frame = delta.frame;
+        }
+
+        if let Err(e) = gb.load_state(&state) {
+            self.clear();
+            return Err(e);
+        }
+        let rewound = self.frame - newest_frame;
+        self.frame = newest_frame;
+        self.newest = Some((newest_frame, state));
+        Ok(rewound)
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+        self.delta_bytes = 0;
+    }
+
+    pub fn stats(&self) -> RewindStats {
+        let (snapshots, frames, newest_bytes) = match &self.newest {
+            Some((frame, state)) => {
+                let oldest = self.deltas.front().map_or(*frame, |delta| delta.frame);
+                (self.deltas.len() + 1, self.frame - oldest, state.len())
+            }
+            None => (0, 0, 0),
+        };
+        RewindStats {
+            snapshots,
+            frames,
+            bytes: newest_bytes + self.delta_bytes,
+            average_delta_bytes: if self.deltas.is_empty() { 0 } else { self.delta_bytes / self.deltas.len() },
+            last_snapshot_time: self.last_snapshot_time,
+        }
+    }
+}
+
+// XORs the older state with the newer one and run-length encodes the result as pairs of
+// (unchanged byte count, changed byte count) varints, each followed by the changed bytes
+fn encode_delta(older: &[u8], newer: &[u8]) -> Vec<u8> {
+    let len = older.len().max(newer.len());
+    let xor = |i: usize| older.get(i).copied().unwrap_or(0) ^ newer.get(i).copied().unwrap_or(0);
+
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < len {
+        let zeros_start = i;
+        while i < len && xor(i) == 0 {
+            i += 1;
+        }
+        let literal_start = i;
+        // A lone unchanged byte costs more to encode as a run than as a literal
+        while i < len && (xor(i) != 0 || (i + 1 < len && xor(i + 1) != 0)) {
+            i += 1;
+        }
+        write_varint(&mut out, literal_start - zeros_start);
+        write_varint(&mut out, i - literal_start);
+        out.extend((literal_start..i).map(xor));
+    }
+    out
+}
+
+fn decode_delta(newer: &[u8], delta: &[u8], older_len: usize) -> Vec<u8> {
+    let mut older = newer.to_vec();
+    older.resize(older_len.max(newer.len()), 0);
+
+    let mut pos = 0;
+    let mut i = 0;
+    while pos < delta.len() {
+        i += read_varint(delta, &mut pos);
+        let literals = read_varint(delta, &mut pos);
+        for &byte in &delta[pos..pos + literals] {
+            older[i] ^= byte;
+            i += 1;
+        }
+        pos += literals;
+    }
+    older.truncate(older_len);
+    older
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gb::testing::rom;
+
+    #[test]
+    fn deltas_restore_the_older_state() {
+        let older: Vec<u8> = (0..300).map(|i| (i * 7) as u8).collect();
+        let mut newer = older.clone();
+        newer[0] ^= 1;
+        newer[2] ^= 1;
+        newer[200..210].fill(0xAA);
+        let delta = encode_delta(&older, &newer);
+        // The unchanged byte between the first two changes stays inside the literal run
+        assert_eq!(delta[..5], [0, 3, 1, 0, 1]);
+        assert_eq!(delta.len(), 20);
+        assert_eq!(decode_delta(&newer, &delta, older.len()), older);
+
+        // States of different sizes, like after a layout change in the cartridge RAM
+        assert_eq!(decode_delta(&newer[..100], &encode_delta(&older, &newer[..100]), older.len()), older);
+        assert_eq!(decode_delta(&newer, &encode_delta(&older[..100], &newer), 100), older[..100]);
+        assert_eq!(encode_delta(&older, &older), [0xAC, 0x02, 0x00]);
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        let mut data = Vec::new();
+        let values = [0, 1, 0x7F, 0x80, 0x3FFF, 0x4000, usize::MAX];
+        for value in values {
+            write_varint(&mut data, value);
+        }
+        let mut pos = 0;
+        for value in values {
+            assert_eq!(read_varint(&data, &mut pos), value);
+        }
+        assert_eq!(pos, data.len());
+    }
+
+    #[test]
+    fn rewinds_to_whole_snapshots() {
+        let mut gb = Gameboy::new();
+        let mut rewind = Rewind::new(2, DEFAULT_REWIND_BYTES);
+        let mut states = Vec::new();
+        for _ in 0..10 {
+            gb.run_frame();
+            rewind.record(&gb);
+            states.push(gb.save_state());
+        }
+        // Snapshots were taken after frames 1, 3, 5, 7 and 9
+        assert_eq!(rewind.stats().snapshots, 5);
+        assert_eq!(rewind.rewind(&mut gb, 4).unwrap(), 5);
+        assert!(gb.save_state() == states[4]);
+        assert_eq!(rewind.rewind(&mut gb, 100).unwrap(), 4);
+        assert!(gb.save_state() == states[0]);
+        assert_eq!(rewind.rewind(&mut gb, 1).unwrap(), 0);
+    }
+
+    #[test]
+    fn drops_the_oldest_deltas_over_budget() {
+        let mut gb = Gameboy::new();
+        let mut rewind = Rewind::new(1, 0);
+        for _ in 0..5 {
+            gb.run_frame();
+            rewind.record(&gb);
+        }
+        let stats = rewind.stats();
+        assert_eq!((stats.snapshots, stats.frames), (1, 0));
+        assert_eq!(stats.bytes, gb.save_state().len());
+    }
+
+    #[test]
+    fn clears_the_history_when_a_snapshot_does_not_load() {
+        let mut gb = Gameboy::new();
+        let mut rewind = Rewind::new(1, DEFAULT_REWIND_BYTES);
+        for _ in 0..3 {
+            gb.run_frame();
+            rewind.record(&gb);
+        }
+        let mut other = Gameboy::new();
+        other.load_rom(&rom(&[0x18, 0xFE])).unwrap();
+        let before = other.save_state();
+        assert!(matches!(rewind.rewind(&mut other, 1), Err(StateError::RomMismatch { .. })));
+        assert!(other.save_state() == before);
+        assert_eq!(rewind.stats().snapshots, 0);
+        assert_eq!(rewind.rewind(&mut other, 1).unwrap(), 0);
+    }
+}
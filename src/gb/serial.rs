@@ -0,0 +1,204 @@
+use std::io::{stdout, Write};
+
+use crate::gb::state::{StateError, StateReader, StateWriter};
+
+// T-cycles taken by an 8 bit transfer on the 8192 Hz internal clock
+pub const TRANSFER_CYCLES: u64 = 8 * 512;
+
+// Something plugged into the link port
+pub trait SerialDevice {
+    // Internal clock transfer started by this Game Boy, returns the byte shifted back in by the device
+    fn transfer(&mut self, outgoing: u8) -> u8;
+
+    // Called every M-cycle while waiting on an external clock transfer,
+    // returns the byte shifted in once the device has clocked a full transfer
+    fn poll_external(&mut self, outgoing: u8) -> Option<u8> {
+        let _ = outgoing;
+        None
+    }
+
+    // Called every M-cycle with the number of T-cycles elapsed at normal speed, for devices that keep time
+    fn clock(&mut self, cycles: u32) {
+        let _ = cycles;
+    }
+}
+
+// Nothing connected, the input line is pulled high
+pub struct Disconnected;
+
+impl SerialDevice for Disconnected {
+    fn transfer(&mut self, _outgoing: u8) -> u8 {
+        0xFF
+    }
+}
+
+// Prints every byte sent as a character, used by test ROMs to report results
+pub struct StdoutCapture;
+
+impl SerialDevice for StdoutCapture {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        print!("{}", outgoing as char);
+        let _ = stdout().flush();
+        0xFF
+    }
+}
+
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    // Bits left to shift in the current internal clock transfer
+    bits_remaining: u8,
+    // Byte being shifted in from the device, MSB first
+    incoming: u8,
+    device: Box<dyn SerialDevice>,
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Self {
+            sb: 0,
+            sc: 0x7E,
+            bits_remaining: 0,
+            incoming: 0xFF,
+            device: Box::new(Disconnected),
+        }
+    }
+
+    pub fn connect(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = device;
+    }
+
+    // The connected device is not part of the state
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.sb);
+        w.u8(self.sc);
+        w.u8(self.bits_remaining);
+        w.u8(self.incoming);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.sb = r.u8()?;
+        self.sc = r.u8()? | 0x7E;
+        self.bits_remaining = r.u8()?.min(8);
+        self.incoming = r.u8()?;
+        Ok(())
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        if addr == 0xFF01 {
+            self.sb
+        } else {
+            self.sc | 0x7E
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        if addr == 0xFF01 {
+            self.sb = data;
+        } else {
+            self.sc = data | 0x7E;
+            // Internal clock transfers exchange the byte with the device up front and shift it in bit by bit
+            if self.sc & 0x81 == 0x81 {
+                self.incoming = self.device.transfer(self.sb);
+                self.bits_remaining = 8;
+            }
+        }
+    }
+
+    // Keeps the device's notion of time moving while the CPU is stopped and no transfer can progress
+    pub fn clock(&mut self, cycles: u32) {
+        self.device.clock(cycles);
+    }
+
+    // Called every M-cycle, serial_edge is the falling edge of the 8192 Hz serial clock and cycles the time passed.
+    // Returns true when a transfer completes and the serial interrupt should be requested
+    pub fn tick(&mut self, serial_edge: bool, cycles: u32) -> bool {
+        self.clock(cycles);
+        if self.sc & 0x80 == 0 {
+            return false;
+        }
+
+        // External clock
+        if self.sc & 0x01 == 0 {
+            if let Some(incoming) = self.device.poll_external(self.sb) {
+                self.sb = incoming;
+                self.sc &= 0x7F;
+                return true;
+            }
+            return false;
+        }
+
+        if !serial_edge || self.bits_remaining == 0 {
+            return false;
+        }
+        self.sb = (self.sb << 1) | (self.incoming >> 7);
+        self.incoming <<= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.sc &= 0x7F;
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gb::Model;
+    use crate::gb::mem::Memory;
+
+    // Answers every transfer with a fixed byte
+    struct Reply(u8);
+
+    impl SerialDevice for Reply {
+        fn transfer(&mut self, _outgoing: u8) -> u8 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn shifts_one_bit_per_clock_edge_msb_first() {
+        let mut serial = Serial::new();
+        serial.connect(Box::new(Reply(0xA5)));
+        serial.write(0xFF01, 0x00);
+        serial.write(0xFF02, 0x81);
+        assert!(!serial.tick(false, 4));
+        assert!(!serial.tick(true, 4));
+        assert_eq!(serial.read(0xFF01), 0x01);
+        for _ in 0..6 {
+            assert!(!serial.tick(true, 4));
+        }
+        assert!(serial.tick(true, 4));
+        assert_eq!(serial.read(0xFF01), 0xA5);
+        assert_eq!(serial.read(0xFF02), 0x7F);
+    }
+
+    #[test]
+    fn completes_an_internal_transfer_after_8_bits_at_8192_hz() {
+        let mut mem = Memory::new(Model::Dmg);
+        mem.connect_serial(Box::new(Reply(0x3C)));
+        mem.write(0xFF0F, 0x00);
+        mem.write(0xFF01, 0x42);
+        mem.write(0xFF02, 0x81);
+        let mut m_cycles = 0;
+        while mem.read(0xFF0F) & 0x08 == 0 {
+            mem.inc_clk();
+            m_cycles += 1;
+            assert!(m_cycles <= 8 * 128, "transfer did not finish");
+        }
+        // The first bit waits for the next edge of the serial clock
+        assert!(m_cycles > 7 * 128);
+        assert_eq!(mem.read(0xFF01), 0x3C);
+    }
+
+    #[test]
+    fn waits_for_an_external_clock() {
+        let mut serial = Serial::new();
+        serial.write(0xFF02, 0x80);
+        for _ in 0..100 {
+            assert!(!serial.tick(true, 4));
+        }
+        assert_eq!(serial.read(0xFF02), 0xFE);
+    }
+}
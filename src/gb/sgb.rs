@@ -0,0 +1,618 @@
+use crate::gb::ppu::{rgb555_to_rgb888, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::gb::state::{StateError, StateReader, StateWriter};
+
+// The SNES shows the Game Boy screen in the middle of a 256x224 picture, the rest is the border
+pub const SGB_SCREEN_WIDTH: usize = 256;
+pub const SGB_SCREEN_HEIGHT: usize = 224;
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+const PACKET_BYTES: usize = 16;
+const PACKET_BITS: usize = PACKET_BYTES * 8;
+
+// Palettes are assigned to 8x8 cells of the Game Boy screen
+const CELLS_X: usize = SCREEN_WIDTH / 8;
+const CELLS_Y: usize = SCREEN_HEIGHT / 8;
+// ATTR_TRN sends 45 attribute files packing a 2 bit palette number per cell
+const ATTRIBUTE_FILE_BYTES: usize = CELLS_X * CELLS_Y / 4;
+const ATTRIBUTE_FILES: usize = 45;
+
+// VRAM transfers read 4 KiB off the screen, 256 tiles laid out 20 per line
+const TRANSFER_BYTES: usize = 0x1000;
+// PCT_TRN carries the 32x32 border map followed by 4 palettes of 16 colours
+const BORDER_MAP_BYTES: usize = 0x800;
+const BORDER_BYTES: usize = BORDER_MAP_BYTES + 4 * 16 * 2;
+// Border tiles hidden behind the Game Boy screen
+const BORDER_HOLE_X: std::ops::Range<usize> = SCREEN_X / 8..(SCREEN_X + SCREEN_WIDTH) / 8;
+const BORDER_HOLE_Y: std::ops::Range<usize> = SCREEN_Y / 8..(SCREEN_Y + SCREEN_HEIGHT) / 8;
+
+// Command codes, the top 5 bits of a command's first byte
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const ATTR_DIV: u8 = 0x06;
+const ATTR_CHR: u8 = 0x07;
+const PAL_SET: u8 = 0x0A;
+const PAL_TRN: u8 = 0x0B;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const ATTR_TRN: u8 = 0x15;
+const ATTR_SET: u8 = 0x16;
+const MASK_EN: u8 = 0x17;
+
+// MASK_EN modes, what the TV shows instead of the Game Boy screen
+const MASK_NONE: u8 = 0;
+const MASK_FREEZE: u8 = 1;
+const MASK_BLACK: u8 = 2;
+const MASK_COLOR0: u8 = 3;
+
+// Palette the SGB starts with before a game sets its own
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+// Destination of the data on screen at the end of the next frame
+#[derive(Clone, Copy, PartialEq)]
+enum Transfer {
+    None,
+    SystemPalettes,
+    AttributeFiles,
+    // Border tiles 0x00-0x7F or 0x80-0xFF
+    BorderTiles(u8),
+    BorderMap,
+}
+
+impl Transfer {
+    fn to_u8(self) -> u8 {
+        match self {
+            Transfer::None => 0,
+            Transfer::SystemPalettes => 1,
+            Transfer::AttributeFiles => 2,
+            Transfer::BorderTiles(bank) => 3 + bank,
+            Transfer::BorderMap => 5,
+        }
+    }
+
+    fn from_u8(value: u8) -> Result<Self, StateError> {
+        Ok(match value {
+            0 => Transfer::None,
+            1 => Transfer::SystemPalettes,
+            2 => Transfer::AttributeFiles,
+            3 | 4 => Transfer::BorderTiles(value - 3),
+            5 => Transfer::BorderMap,
+            _ => return Err(StateError::Invalid("SGB transfer")),
+        })
+    }
+}
+
+// Super Game Boy side of the hardware: receives command packets pulsed over P14/P15,
+// colours the screen with 4 palettes assigned per 8x8 cell and frames it with the border
+pub struct Sgb {
+    // The SGB only listens to cartridges whose header declares SGB support
+    enabled: bool,
+    packet: [u8; PACKET_BYTES],
+    // Bits received since the reset pulse, None outside of a packet
+    packet_bit: Option<usize>,
+    // Both lines went back high since the last bit
+    released: bool,
+    // Packets received so far of a command, the first one gives the count
+    command: Vec<u8>,
+    // RGB555 colours, colour 0 of palette 0 is shared by every palette and the border
+    palettes: [[u16; 4]; 4],
+    // 512 palettes of 4 colours sent with PAL_TRN and picked with PAL_SET
+    system_palettes: [u8; TRANSFER_BYTES],
+    // Palette number of every cell
+    attributes: [u8; CELLS_X * CELLS_Y],
+    attribute_files: [u8; ATTRIBUTE_FILES * ATTRIBUTE_FILE_BYTES],
+    // 256 SNES 4bpp tiles
+    border_tiles: [u8; 2 * TRANSFER_BYTES],
+    border: [u8; BORDER_BYTES],
+    transfer: Transfer,
+    mask: u8,
+    // Shades of the frame on the TV, held while the mask freezes it
+    screen: [u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+}
+
+impl Sgb {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            packet: [0; PACKET_BYTES],
+            packet_bit: None,
+            released: false,
+            command: Vec::new(),
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: [0; TRANSFER_BYTES],
+            attributes: [0; CELLS_X * CELLS_Y],
+            attribute_files: [0; ATTRIBUTE_FILES * ATTRIBUTE_FILE_BYTES],
+            border_tiles: [0; 2 * TRANSFER_BYTES],
+            border: [0; BORDER_BYTES],
+            transfer: Transfer::None,
+            mask: MASK_NONE,
+            screen: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    // Follows writes to P1. Pulling both lines low starts a packet, after which each pulse of P14 sends a 0
+    // and each pulse of P15 a 1, 128 bits LSB first and a 0 stop bit.
+    // Returns the new number of controllers when a MLT_REQ arrives
+    pub fn write_joypad(&mut self, data: u8) -> Option<u8> {
+        match data & 0x30 {
+            0x00 => {
+                self.packet = [0; PACKET_BYTES];
+                self.packet_bit = Some(0);
+                self.released = false;
+                None
+            }
+            0x30 => {
+                self.released = true;
+                None
+            }
+            select => {
+                let bit = self.packet_bit?;
+                if !self.released {
+                    return None;
+                }
+                self.released = false;
+                let one = select == 0x10;
+                if bit < PACKET_BITS {
+                    self.packet[bit / 8] |= (one as u8) << (bit % 8);
+                    self.packet_bit = Some(bit + 1);
+                    None
+                } else {
+                    self.packet_bit = None;
+                    if one { None } else { self.receive_packet() }
+                }
+            }
+        }
+    }
+
+    fn receive_packet(&mut self) -> Option<u8> {
+        if !self.enabled {
+            return None;
+        }
+        self.command.extend_from_slice(&self.packet);
+        let packets = (self.command[0] & 0b111).max(1) as usize;
+        if self.command.len() < packets * PACKET_BYTES {
+            return None;
+        }
+        let command = std::mem::take(&mut self.command);
+        self.run_command(&command)
+    }
+
+    fn run_command(&mut self, data: &[u8]) -> Option<u8> {
+        match data[0] >> 3 {
+            PAL01 => self.set_palette_pair(0, 1, data),
+            PAL23 => self.set_palette_pair(2, 3, data),
+            PAL03 => self.set_palette_pair(0, 3, data),
+            PAL12 => self.set_palette_pair(1, 2, data),
+            ATTR_BLK => self.attribute_blocks(data),
+            ATTR_LIN => self.attribute_lines(data),
+            ATTR_DIV => self.attribute_division(data),
+            ATTR_CHR => self.attribute_cells(data),
+            PAL_SET => self.set_system_palettes(data),
+            PAL_TRN => self.transfer = Transfer::SystemPalettes,
+            ATTR_TRN => self.transfer = Transfer::AttributeFiles,
+            CHR_TRN => self.transfer = Transfer::BorderTiles(data[1] & 1),
+            PCT_TRN => self.transfer = Transfer::BorderMap,
+            ATTR_SET => {
+                self.apply_attribute_file(data[1] & 0x3F);
+                if data[1] & 0x40 != 0 {
+                    self.mask = MASK_NONE;
+                }
+            }
+            MASK_EN => self.mask = data[1] & 0b11,
+            // 0, 1 or 3 for one, two or four players
+            MLT_REQ => return Some([1, 2, 1, 4][data[1] as usize & 0b11]),
+            // Sound, SNES code uploads and the remaining commands don't affect the picture
+            _ => {}
+        }
+        None
+    }
+
+    // PAL01, PAL23, PAL03 and PAL12: the shared colour 0 then colours 1-3 of two palettes
+    fn set_palette_pair(&mut self, first: usize, second: usize, data: &[u8]) {
+        let color = |i: usize| u16::from_le_bytes([data[1 + i * 2], data[2 + i * 2]]) & 0x7FFF;
+        self.palettes[0][0] = color(0);
+        for i in 1..4 {
+            self.palettes[first][i] = color(i);
+            self.palettes[second][i] = color(i + 3);
+        }
+    }
+
+    // Rectangles with separate palettes for the cells inside, on the edge and outside.
+    // Changing only the inside or only the outside also changes the edge
+    fn attribute_blocks(&mut self, data: &[u8]) {
+        for block in data[2..].chunks_exact(6).take(data[1] as usize) {
+            let control = block[0] & 0b111;
+            let inside = (control & 0b001 != 0).then_some(block[1] & 0b11);
+            let outside = (control & 0b100 != 0).then_some((block[1] >> 4) & 0b11);
+            let edge = match control {
+                0b001 => inside,
+                0b100 => outside,
+                _ => (control & 0b010 != 0).then_some((block[1] >> 2) & 0b11),
+            };
+            let [x1, y1, x2, y2] = [block[2], block[3], block[4], block[5]].map(|c| (c & 0x1F) as usize);
+            for y in 0..CELLS_Y {
+                for x in 0..CELLS_X {
+                    let palette = if x > x1 && x < x2 && y > y1 && y < y2 {
+                        inside
+                    } else if (x1..=x2).contains(&x) && (y1..=y2).contains(&y) {
+                        edge
+                    } else {
+                        outside
+                    };
+                    if let Some(palette) = palette {
+                        self.attributes[y * CELLS_X + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    // Whole rows (bit 7 set) or columns of cells, one per byte with the palette in bits 5-6
+    fn attribute_lines(&mut self, data: &[u8]) {
+        for &line in data[2..].iter().take(data[1] as usize) {
+            let palette = (line >> 5) & 0b11;
+            let index = (line & 0x1F) as usize;
+            if line & 0x80 != 0 {
+                if index < CELLS_Y {
+                    self.attributes[index * CELLS_X..(index + 1) * CELLS_X].fill(palette);
+                }
+            } else if index < CELLS_X {
+                for y in 0..CELLS_Y {
+                    self.attributes[y * CELLS_X + index] = palette;
+                }
+            }
+        }
+    }
+
+    // Splits the screen at a row (bit 6 set) or column, with palettes for either side and the line itself
+    fn attribute_division(&mut self, data: &[u8]) {
+        let after = data[1] & 0b11;
+        let before = (data[1] >> 2) & 0b11;
+        let on_line = (data[1] >> 4) & 0b11;
+        let horizontal = data[1] & 0x40 != 0;
+        let line = data[2] as usize;
+        for y in 0..CELLS_Y {
+            for x in 0..CELLS_X {
+                let position = if horizontal { y } else { x };
+                self.attributes[y * CELLS_X + x] = match position.cmp(&line) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on_line,
+                    std::cmp::Ordering::Greater => after,
+                };
+            }
+        }
+    }
+
+    // Palettes for a run of cells from a starting cell, 4 per byte, going right or down and wrapping
+    fn attribute_cells(&mut self, data: &[u8]) {
+        let (mut x, mut y) = (data[1] as usize, data[2] as usize);
+        let count = (u16::from_le_bytes([data[3], data[4]]) as usize).min(CELLS_X * CELLS_Y);
+        let vertical = data[5] & 1 != 0;
+        for i in 0..count {
+            let Some(&byte) = data.get(6 + i / 4) else { break };
+            if x < CELLS_X && y < CELLS_Y {
+                self.attributes[y * CELLS_X + x] = (byte >> (6 - (i % 4) * 2)) & 0b11;
+            }
+            if vertical {
+                y += 1;
+                if y == CELLS_Y {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x == CELLS_X {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    // PAL_SET: copies 4 of the transferred system palettes, optionally applying an attribute file and lifting the mask
+    fn set_system_palettes(&mut self, data: &[u8]) {
+        for (i, palette) in self.palettes.iter_mut().enumerate() {
+            let index = (u16::from_le_bytes([data[1 + i * 2], data[2 + i * 2]]) & 0x1FF) as usize;
+            for (color, value) in palette.iter_mut().enumerate() {
+                let offset = (index * 4 + color) * 2;
+                *value = u16::from_le_bytes([self.system_palettes[offset], self.system_palettes[offset + 1]]) & 0x7FFF;
+            }
+        }
+        let flags = data[9];
+        if flags & 0x80 != 0 {
+            self.apply_attribute_file(flags & 0x3F);
+        }
+        if flags & 0x40 != 0 {
+            self.mask = MASK_NONE;
+        }
+    }
+
+    fn apply_attribute_file(&mut self, file: u8) {
+        let file = file as usize;
+        if file >= ATTRIBUTE_FILES {
+            return;
+        }
+        let data = &self.attribute_files[file * ATTRIBUTE_FILE_BYTES..(file + 1) * ATTRIBUTE_FILE_BYTES];
+        for (cell, palette) in self.attributes.iter_mut().enumerate() {
+            *palette = (data[cell / 4] >> (6 - (cell % 4) * 2)) & 0b11;
+        }
+    }
+
+    // Called with the shades of every finished frame, completes a pending VRAM transfer
+    pub fn end_frame(&mut self, shades: &[u8]) {
+        if self.transfer != Transfer::None {
+            let data = transfer_data(shades);
+            match self.transfer {
+                Transfer::SystemPalettes => self.system_palettes = data,
+                Transfer::AttributeFiles => self.attribute_files.copy_from_slice(&data[..ATTRIBUTE_FILES * ATTRIBUTE_FILE_BYTES]),
+                Transfer::BorderTiles(bank) => {
+                    let start = bank as usize * TRANSFER_BYTES;
+                    self.border_tiles[start..start + TRANSFER_BYTES].copy_from_slice(&data);
+                }
+                Transfer::BorderMap => self.border.copy_from_slice(&data[..BORDER_BYTES]),
+                Transfer::None => {}
+            }
+            self.transfer = Transfer::None;
+        }
+        if self.mask != MASK_FREEZE {
+            self.screen.copy_from_slice(shades);
+        }
+    }
+
+    // The picture sent to the TV as packed RGB888, SGB_SCREEN_WIDTH x SGB_SCREEN_HEIGHT
+    pub fn rgb_frame(&self) -> Vec<u8> {
+        let mut colors = [self.palettes[0][0]; SGB_SCREEN_WIDTH * SGB_SCREEN_HEIGHT];
+        self.draw_border(&mut colors);
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                let shade = self.screen[y * SCREEN_WIDTH + x] as usize;
+                let palette = self.attributes[(y / 8) * CELLS_X + x / 8] as usize;
+                colors[(SCREEN_Y + y) * SGB_SCREEN_WIDTH + SCREEN_X + x] = match self.mask {
+                    MASK_BLACK => 0,
+                    MASK_COLOR0 => self.palettes[0][0],
+                    _ if shade == 0 => self.palettes[0][0],
+                    _ => self.palettes[palette][shade],
+                };
+            }
+        }
+        colors.iter().flat_map(|&color| rgb555_to_rgb888(color, false)).collect()
+    }
+
+    // Border tiles use colours 1-15 of palettes 4-7, colour 0 lets the backdrop through.
+    // Until a game sends its own the border is left blank rather than the SGB's built in picture
+    fn draw_border(&self, colors: &mut [u16]) {
+        for tile_y in 0..SGB_SCREEN_HEIGHT / 8 {
+            for tile_x in 0..SGB_SCREEN_WIDTH / 8 {
+                if BORDER_HOLE_X.contains(&tile_x) && BORDER_HOLE_Y.contains(&tile_y) {
+                    continue;
+                }
+                let entry_offset = (tile_y * 32 + tile_x) * 2;
+                let entry = u16::from_le_bytes([self.border[entry_offset], self.border[entry_offset + 1]]);
+                let tile = &self.border_tiles[(entry & 0xFF) as usize * 32..][..32];
+                let palette = ((entry >> 10) & 0b11) as usize;
+                for row in 0..8 {
+                    let tile_row = if entry & 0x8000 != 0 { 7 - row } else { row };
+                    for column in 0..8 {
+                        let bit = if entry & 0x4000 != 0 { column } else { 7 - column };
+                        let planes = [tile[tile_row * 2], tile[tile_row * 2 + 1], tile[16 + tile_row * 2], tile[17 + tile_row * 2]];
+                        let color = planes.iter().enumerate().fold(0, |color, (plane, &byte)| color | ((byte >> bit) & 1) << plane) as usize;
+                        if color != 0 {
+                            let offset = BORDER_MAP_BYTES + (palette * 16 + color) * 2;
+                            colors[(tile_y * 8 + row) * SGB_SCREEN_WIDTH + tile_x * 8 + column] =
+                                u16::from_le_bytes([self.border[offset], self.border[offset + 1]]) & 0x7FFF;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.bytes(&self.packet);
+        w.i32(self.packet_bit.map_or(-1, |bit| bit as i32));
+        w.bool(self.released);
+        w.vec(&self.command);
+        for palette in &self.palettes {
+            for &color in palette {
+                w.u16(color);
+            }
+        }
+        w.bytes(&self.system_palettes);
+        w.bytes(&self.attributes);
+        w.bytes(&self.attribute_files);
+        w.bytes(&self.border_tiles);
+        w.bytes(&self.border);
+        w.u8(self.transfer.to_u8());
+        w.u8(self.mask);
+        w.bytes(&self.screen);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.bool()?;
+        r.bytes(&mut self.packet)?;
+        self.packet_bit = match r.i32()? {
+            -1 => None,
+            bit @ 0..=128 => Some(bit as usize),
+            _ => return Err(StateError::Invalid("SGB packet bit")),
+        };
+        self.released = r.bool()?;
+        self.command = r.vec()?.to_vec();
+        if self.command.len() >= 7 * PACKET_BYTES {
+            return Err(StateError::Invalid("SGB command length"));
+        }
+        for palette in &mut self.palettes {
+            for color in palette.iter_mut() {
+                *color = r.u16()? & 0x7FFF;
+            }
+        }
+        r.bytes(&mut self.system_palettes)?;
+        r.bytes(&mut self.attributes)?;
+        self.attributes = self.attributes.map(|palette| palette & 0b11);
+        r.bytes(&mut self.attribute_files)?;
+        r.bytes(&mut self.border_tiles)?;
+        r.bytes(&mut self.border)?;
+        self.transfer = Transfer::from_u8(r.u8()?)?;
+        self.mask = r.u8()? & 0b11;
+        r.bytes(&mut self.screen)?;
+        self.screen = self.screen.map(|shade| shade & 0b11);
+        Ok(())
+    }
+}
+
+// Reads the screen the way the SGB receives VRAM transfers, as 2bpp tiles going across then down
+fn transfer_data(shades: &[u8]) -> [u8; TRANSFER_BYTES] {
+    let mut data = [0; TRANSFER_BYTES];
+    for (tile, bytes) in data.chunks_exact_mut(16).enumerate() {
+        let (tile_x, tile_y) = (tile % CELLS_X, tile / CELLS_X);
+        for row in 0..8 {
+            let start = (tile_y * 8 + row) * SCREEN_WIDTH + tile_x * 8;
+            for (i, &shade) in shades[start..start + 8].iter().enumerate() {
+                bytes[row * 2] |= (shade & 1) << (7 - i);
+                bytes[row * 2 + 1] |= ((shade >> 1) & 1) << (7 - i);
+            }
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Pulses every packet of a command over P1 and returns what the last stop bit produced
+    fn send(sgb: &mut Sgb, data: &[u8]) -> Option<u8> {
+        let mut result = None;
+        for packet in data.chunks_exact(PACKET_BYTES) {
+            sgb.write_joypad(0x00);
+            sgb.write_joypad(0x30);
+            for bit in 0..PACKET_BITS {
+                let one = packet[bit / 8] & (1 << (bit % 8)) != 0;
+                sgb.write_joypad(if one { 0x10 } else { 0x20 });
+                sgb.write_joypad(0x30);
+            }
+            result = sgb.write_joypad(0x20);
+            sgb.write_joypad(0x30);
+        }
+        result
+    }
+
+    fn command(code: u8, packets: u8, args: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; packets as usize * PACKET_BYTES];
+        data[0] = code << 3 | packets;
+        data[1..1 + args.len()].copy_from_slice(args);
+        data
+    }
+
+    fn enabled() -> Sgb {
+        let mut sgb = Sgb::new();
+        sgb.set_enabled(true);
+        sgb
+    }
+
+    #[test]
+    fn decodes_multiplayer_requests_from_enabled_games() {
+        let request = command(MLT_REQ, 1, &[0x01]);
+        assert_eq!(send(&mut Sgb::new(), &request), None);
+        let mut sgb = enabled();
+        assert_eq!(send(&mut sgb, &request), Some(2));
+        assert_eq!(send(&mut sgb, &command(MLT_REQ, 1, &[0x03])), Some(4));
+        assert_eq!(send(&mut sgb, &command(MLT_REQ, 1, &[0x00])), Some(1));
+    }
+
+    #[test]
+    fn drops_packets_with_a_high_stop_bit() {
+        let mut sgb = enabled();
+        sgb.write_joypad(0x00);
+        sgb.write_joypad(0x30);
+        for _ in 0..PACKET_BITS {
+            sgb.write_joypad(0x20);
+            sgb.write_joypad(0x30);
+        }
+        assert_eq!(sgb.write_joypad(0x10), None);
+        assert!(sgb.command.is_empty());
+        // Writes without a reset pulse first are ignored
+        assert_eq!(sgb.write_joypad(0x20), None);
+    }
+
+    #[test]
+    fn colours_the_screen_with_palette_pairs_and_attributes() {
+        let mut sgb = enabled();
+        let colors: Vec<u8> = [0x7FFF, 0x001F, 0x03E0, 0x7C00, 0x0011, 0x0022, 0x0033].iter().flat_map(|c: &u16| c.to_le_bytes()).collect();
+        send(&mut sgb, &command(PAL01, 1, &colors));
+        assert_eq!(sgb.palettes[0], [0x7FFF, 0x001F, 0x03E0, 0x7C00]);
+        assert_eq!(sgb.palettes[1], [DEFAULT_PALETTE[0], 0x0011, 0x0022, 0x0033]);
+
+        // Columns left of 10 use palette 1, column 10 palette 2 and the rest palette 3
+        send(&mut sgb, &command(ATTR_DIV, 1, &[0b10_01_11, 10]));
+        assert_eq!(sgb.attributes[CELLS_X + 9..CELLS_X + 12], [1, 2, 3]);
+
+        sgb.end_frame(&[1; SCREEN_WIDTH * SCREEN_HEIGHT]);
+        let frame = sgb.rgb_frame();
+        let pixel = |x: usize, y: usize| {
+            let offset = ((SCREEN_Y + y) * SGB_SCREEN_WIDTH + SCREEN_X + x) * 3;
+            [frame[offset], frame[offset + 1], frame[offset + 2]]
+        };
+        assert_eq!(pixel(0, 0), rgb555_to_rgb888(0x0011, false));
+        // The border area shows colour 0
+        assert_eq!(frame[..3], rgb555_to_rgb888(0x7FFF, false));
+    }
+
+    #[test]
+    fn collects_multi_packet_commands() {
+        let mut sgb = enabled();
+        // 60 cells from the top left going right, patterned 1, 2, 3, 0
+        let mut data = command(ATTR_CHR, 2, &[0, 0, 60, 0, 0]);
+        data[6..21].fill(0b01_10_11_00);
+        assert_eq!(send(&mut sgb, &data[..PACKET_BYTES]), None);
+        assert_eq!(sgb.attributes[..4], [0; 4]);
+        send(&mut sgb, &data[PACKET_BYTES..]);
+        assert_eq!(sgb.attributes[..4], [1, 2, 3, 0]);
+        assert_eq!(sgb.attributes[CELLS_X * 2 + 16..CELLS_X * 3], [1, 2, 3, 0]);
+        assert_eq!(sgb.attributes[CELLS_X * 3], 0);
+        assert!(sgb.command.is_empty());
+    }
+
+    #[test]
+    fn transfers_system_palettes_and_lifts_the_mask() {
+        let mut sgb = enabled();
+        send(&mut sgb, &command(MASK_EN, 1, &[MASK_FREEZE]));
+        send(&mut sgb, &command(PAL_TRN, 1, &[]));
+        sgb.end_frame(&[3; SCREEN_WIDTH * SCREEN_HEIGHT]);
+        assert!(sgb.system_palettes.iter().all(|&byte| byte == 0xFF));
+        // Frozen, the screen still shows the frame from before the mask
+        assert!(sgb.screen.iter().all(|&shade| shade == 0));
+
+        send(&mut sgb, &command(PAL_SET, 1, &[0, 0, 1, 0, 2, 0, 3, 0, 0x40]));
+        assert_eq!(sgb.palettes, [[0x7FFF; 4]; 4]);
+        assert_eq!(sgb.mask, MASK_NONE);
+    }
+
+    #[test]
+    fn states_round_trip() {
+        let mut sgb = enabled();
+        send(&mut sgb, &command(ATTR_DIV, 1, &[0b11_10_01, 3]));
+        send(&mut sgb, &command(ATTR_CHR, 2, &[0, 0, 60, 0, 0]));
+        sgb.write_joypad(0x00);
+        let mut w = StateWriter::new(None);
+        sgb.save_state(&mut w);
+        let state = w.finish();
+
+        let mut loaded = Sgb::new();
+        let mut r = StateReader::new(&state, None).unwrap();
+        loaded.load_state(&mut r).unwrap();
+        r.finish().unwrap();
+        let mut w = StateWriter::new(None);
+        loaded.save_state(&mut w);
+        assert!(w.finish() == state);
+    }
+}
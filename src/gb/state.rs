@@ -0,0 +1,181 @@
+use std::fmt;
+
+use crate::gb::cart::header::CartridgeHeader;
+
+const MAGIC: &[u8; 4] = b"GBSS";
+// Bumped whenever a field is added, removed or reordered
+pub const STATE_VERSION: u16 = 6;
+
+#[derive(Debug)]
+pub enum StateError {
+    Truncated,
+    NotAState,
+    UnsupportedVersion(u16),
+    RomMismatch { state: String, rom: String },
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::NotAState => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "save state version {} is not supported, expected {}", version, STATE_VERSION)
+            }
+            StateError::RomMismatch { state, rom } => write!(f, "save state is for {} but the loaded ROM is {}", state, rom),
+            StateError::Invalid(reason) => write!(f, "invalid save state: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+// Identifies the ROM a state belongs to, from the header's title and checksums
+fn rom_identity(header: Option<&CartridgeHeader>) -> (String, u8, u16) {
+    header.map_or((String::new(), 0, 0), |h| (h.title.clone(), h.header_checksum, h.global_checksum))
+}
+
+// Little endian serializer every component writes its fields into, in declaration order
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    // Starts a state with the magic, format version and ROM identity
+    pub fn new(header: Option<&CartridgeHeader>) -> Self {
+        let mut writer = Self { data: Vec::with_capacity(0x10000) };
+        writer.bytes(MAGIC);
+        writer.u16(STATE_VERSION);
+        let (title, header_checksum, global_checksum) = rom_identity(header);
+        writer.vec(title.as_bytes());
+        writer.u8(header_checksum);
+        writer.u16(global_checksum);
+        writer
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, value: i32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // Fixed size data, the reader must know the length
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data);
+    }
+
+    // Variable size data, prefixed with its length
+    pub fn vec(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        self.bytes(data);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    // Checks the magic, version and that the state was made with the loaded ROM
+    pub fn new(data: &'a [u8], header: Option<&CartridgeHeader>) -> Result<Self, StateError> {
+        let mut reader = Self { data, pos: 0 };
+        if reader.take(4).map_err(|_| StateError::NotAState)? != MAGIC {
+            return Err(StateError::NotAState);
+        }
+        let version = reader.u16()?;
+        if version != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        let title = String::from_utf8_lossy(reader.vec()?).into_owned();
+        let header_checksum = reader.u8()?;
+        let global_checksum = reader.u16()?;
+        let rom = rom_identity(header);
+        if (header_checksum, global_checksum) != (rom.1, rom.2) {
+            return Err(StateError::RomMismatch {
+                state: format!("{} ({:04X})", title, global_checksum),
+                rom: format!("{} ({:04X})", rom.0, rom.2),
+            });
+        }
+        Ok(reader)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or(StateError::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    // Fails if anything is left over, which means the state was written by a different layout
+    pub fn finish(self) -> Result<(), StateError> {
+        if self.pos == self.data.len() { Ok(()) } else { Err(StateError::Invalid("trailing data")) }
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn i32(&mut self) -> Result<i32, StateError> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+        out.copy_from_slice(self.take(out.len())?);
+        Ok(())
+    }
+
+    pub fn vec(&mut self) -> Result<&'a [u8], StateError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    // Length prefixed data that has to match the size of an existing buffer, like cartridge RAM
+    pub fn vec_into(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+        let data = self.vec()?;
+        if data.len() != out.len() {
+            return Err(StateError::Invalid("buffer size mismatch"));
+        }
+        out.copy_from_slice(data);
+        Ok(())
+    }
+}
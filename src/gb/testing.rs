@@ -0,0 +1,31 @@
+// Cartridge images shared by the unit tests
+
+// Cartridge with the given header codes that jumps from the entry point to code at 0x150. Every 16 KiB bank
+// starts with its own bank number, little endian, so tests can tell which one is mapped
+pub fn cartridge(cart_type: u8, rom_size_code: u8, ram_size_code: u8, code: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000 << rom_size_code];
+    for (bank, data) in rom.chunks_mut(0x4000).enumerate() {
+        data[..2].copy_from_slice(&(bank as u16).to_le_bytes());
+    }
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+    rom[0x147] = cart_type;
+    rom[0x148] = rom_size_code;
+    rom[0x149] = ram_size_code;
+    rom[0x150..0x150 + code.len()].copy_from_slice(code);
+    fix_checksums(&mut rom);
+    rom
+}
+
+// 32 KiB ROM only cartridge running code
+pub fn rom(code: &[u8]) -> Vec<u8> {
+    cartridge(0x00, 0x00, 0x00, code)
+}
+
+// Recomputes the header and global checksums after the header was edited
+pub fn fix_checksums(rom: &mut [u8]) {
+    rom[0x14D] = rom[0x134..0x14D].iter().fold(0u8, |sum, &b| sum.wrapping_sub(b).wrapping_sub(1));
+    let global = rom.iter().enumerate()
+        .filter(|&(i, _)| i != 0x14E && i != 0x14F)
+        .fold(0u16, |sum, (_, &b)| sum.wrapping_add(b as u16));
+    rom[0x14E..0x150].copy_from_slice(&global.to_be_bytes());
+}
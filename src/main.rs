@@ -1,22 +1,401 @@
-mod gb;
-use crate::gb::Gameboy;
+use gameboy_emulator::gb::{
+    Button, ButtonState, CartridgeHeader, Disconnected, Gameboy, LocalPeer, Model, Movie, MovieStart, NetworkLink, Printer, RenderMode, Rewind, DEFAULT_REWIND_BYTES, SerialDevice, StdoutCapture, DEFAULT_SAMPLE_RATE, MAX_SAMPLE_RATE,
+};
 
 use std::env;
-use std::fs::read;
+use std::fs::{read, write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::str::FromStr;
+
+const USAGE: &str = "Usage: cargo run path/to/rom [options]
+       cargo run info path/to/rom
+
+Options:
+    --frames N              Stop after N frames instead of running forever
+    --screenshot out.ppm    Save the last frame when stopping
+    --fifo                  Use the pixel FIFO renderer
+    --color-correction      Mimic the colours of the CGB LCD in CGB games
+    --rtc-host              Drive the cartridge clock from the host's wall-clock
+    --wav out.wav           Record audio to a WAV file
+    --sample-rate N         Audio sample rate, 48000 by default
+    --serial stdout|none    Device on the link port, stdout prints each byte sent,
+                            printer:DIR saves Game Boy Printer output as PNGs in DIR
+    --link listen:ADDR      Link cable to another emulator, also connect:ADDR,
+                            unix-listen:PATH and unix-connect:PATH
+    --link-local rom2.gb    Run a second Game Boy in-process with the cables connected
+    --play movie            Replay the input of a .gbmv, BizHawk .bk2 or VBA .vbm movie,
+                            stopping at its end unless --frames is given
+    --record out.gbmv       Record the input of every frame to a movie
+    --load-state N          Start from save state slot N (<rom>.ss<N>)
+    --save-state N          Save the state to slot N when stopping
+    --rewind N              Keep rewind history and step back N frames before stopping,
+                            then report the history's footprint
+    --model dmg             Hardware to emulate: dmg0, dmg, mgb, sgb or cgb,
+                            cgb for CGB cartridges and dmg otherwise by default,
+                            sgb screenshots include the 256x224 border
+    --boot-rom boot.bin     Run this boot ROM instead of starting at 0x0100
+    --palette up+a          Colours of a DMG game on a CGB, picked like holding a direction
+                            (up, down, left or right), optionally plus a or b, during boot";
+
+// Frames between flushes of modified save RAM, roughly 5 seconds
+const SAVE_FLUSH_FRAMES: u64 = 300;
 
 fn main() {
     let args: Vec<_> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: cargo run path/to/rom");
+    if args.len() < 2 {
+        println!("{}", USAGE);
         return;
     }
 
-    let mut gb = Gameboy::new();
+    if args[1] == "info" {
+        if args.len() != 3 {
+            println!("Usage: cargo run info path/to/rom");
+            return;
+        }
+        let buffer: Vec<u8> = read(&args[2]).expect("Unable to open file");
+        match CartridgeHeader::parse(&buffer) {
+            Ok(header) => println!("{}", header),
+            Err(e) => eprintln!("Invalid ROM: {}", e),
+        }
+        return;
+    }
+
+    let mut frames: Option<u64> = None;
+    let mut screenshot: Option<String> = None;
+    let mut render_mode = RenderMode::Scanline;
+    let mut color_correction = false;
+    let mut rtc_host = false;
+    let mut wav: Option<String> = None;
+    let mut sample_rate = DEFAULT_SAMPLE_RATE;
+    // Test ROMs report over serial, so bytes sent are printed unless told otherwise
+    let mut serial: Box<dyn SerialDevice> = Box::new(StdoutCapture);
+    let mut link_local: Option<String> = None;
+    let mut play: Option<String> = None;
+    let mut record: Option<String> = None;
+    let mut load_slot: Option<u32> = None;
+    let mut save_slot: Option<u32> = None;
+    let mut rewind_frames: Option<u64> = None;
+    let mut model: Option<Model> = None;
+    let mut boot_rom: Option<String> = None;
+    let mut palette: Option<ButtonState> = None;
+    let mut i = 2;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--frames" => {
+                frames = Some(parse_value(next_value(&args, &mut i), "Invalid frame count"));
+            }
+            "--screenshot" => {
+                screenshot = Some(next_value(&args, &mut i).to_string());
+            }
+            "--fifo" => {
+                render_mode = RenderMode::Fifo;
+            }
+            "--color-correction" => {
+                color_correction = true;
+            }
+            "--rtc-host" => {
+                rtc_host = true;
+            }
+            "--wav" => {
+                wav = Some(next_value(&args, &mut i).to_string());
+            }
+            "--sample-rate" => {
+                sample_rate = parse_value(next_value(&args, &mut i), "Invalid sample rate");
+                if sample_rate == 0 || sample_rate > MAX_SAMPLE_RATE {
+                    usage_error(&format!("Sample rate must be between 1 and {} Hz", MAX_SAMPLE_RATE));
+                }
+            }
+            "--serial" => {
+                serial = match next_value(&args, &mut i) {
+                    "stdout" => Box::new(StdoutCapture),
+                    "none" => Box::new(Disconnected),
+                    other if other.starts_with("printer:") => Box::new(Printer::new(&other["printer:".len()..])),
+                    other => usage_error(&format!("Unknown serial device: {}", other)),
+                };
+            }
+            "--link" => {
+                serial = Box::new(open_link(next_value(&args, &mut i)).expect("Unable to open link cable"));
+            }
+            "--link-local" => {
+                link_local = Some(next_value(&args, &mut i).to_string());
+            }
+            "--play" => {
+                play = Some(next_value(&args, &mut i).to_string());
+            }
+            "--record" => {
+                record = Some(next_value(&args, &mut i).to_string());
+            }
+            "--load-state" => {
+                load_slot = Some(parse_value(next_value(&args, &mut i), "Invalid save state slot"));
+            }
+            "--save-state" => {
+                save_slot = Some(parse_value(next_value(&args, &mut i), "Invalid save state slot"));
+            }
+            "--rewind" => {
+                rewind_frames = Some(parse_value(next_value(&args, &mut i), "Invalid rewind frame count"));
+            }
+            "--model" => {
+                model = Some(match next_value(&args, &mut i) {
+                    "dmg0" => Model::Dmg0,
+                    "dmg" => Model::Dmg,
+                    "mgb" => Model::Mgb,
+                    "sgb" => Model::Sgb,
+                    "cgb" => Model::Cgb,
+                    other => usage_error(&format!("Unknown model: {}", other)),
+                });
+            }
+            "--boot-rom" => {
+                boot_rom = Some(next_value(&args, &mut i).to_string());
+            }
+            "--palette" => {
+                palette = Some(parse_palette_buttons(next_value(&args, &mut i)));
+            }
+            other => usage_error(&format!("Unknown option: {}", other)),
+        }
+        i += 1;
+    }
 
     let buffer: Vec<u8> = read(&args[1]).expect("Unable to open file");
-    gb.load_rom(&buffer);
+    let model = model.unwrap_or_else(|| CartridgeHeader::parse(&buffer).map_or(Model::Dmg, |header| Model::for_header(&header)));
+    let mut gb = Gameboy::with_model(model);
+    if let Some(path) = boot_rom {
+        let data: Vec<u8> = read(&path).expect("Unable to open boot ROM");
+        if let Err(e) = gb.load_boot_rom(&data) {
+            eprintln!("Invalid boot ROM: {}", e);
+            return;
+        }
+    }
+    gb.set_render_mode(render_mode);
+    gb.set_color_correction(color_correction);
+    gb.set_sample_rate(sample_rate);
+    gb.connect_serial(serial);
+
+    if let Err(e) = gb.load_rom(&buffer) {
+        eprintln!("Invalid ROM: {}", e);
+        return;
+    }
+    gb.set_rtc_host_sync(rtc_host);
+    if let Some(held) = palette
+        && !gb.select_compat_palette(held)
+    {
+        eprintln!("--palette only applies to a DMG game on a CGB and takes one direction, optionally with a or b");
+    }
+
+    let playback = match play {
+        Some(path) => {
+            let data: Vec<u8> = read(&path).expect("Unable to open movie");
+            let header = gb.cartridge_header().expect("ROM has no header").clone();
+            match Movie::parse(&data, &header, &buffer).and_then(|movie| movie.apply_start(&mut gb).map(|_| movie)) {
+                Ok(movie) => Some(movie),
+                Err(e) => {
+                    eprintln!("Invalid movie: {}", e);
+                    return;
+                }
+            }
+        }
+        None => None,
+    };
+
+    // Battery backed RAM lives next to the ROM as <rom>.sav.
+    // Movies bring their own starting state, so the .sav is neither loaded nor overwritten during playback
+    let save_path = Path::new(&args[1]).with_extension("sav");
+    let use_save_file = gb.has_battery() && playback.is_none();
+    let mut start = MovieStart::PowerOn;
+    if use_save_file && let Ok(data) = read(&save_path) {
+        gb.load_save_data(&data);
+        start = MovieStart::SaveRam(data);
+    }
+    if let Some(slot) = load_slot {
+        if playback.is_some() {
+            eprintln!("A movie brings its own starting state, --load-state can't be used with --play");
+            return;
+        }
+        let data: Vec<u8> = read(state_path(&args[1], slot)).expect("Unable to open save state");
+        if let Err(e) = gb.load_state(&data) {
+            eprintln!("Invalid save state: {}", e);
+            return;
+        }
+        start = MovieStart::SaveState(data);
+    }
+    if let Some(movie) = &playback {
+        start = movie.start.clone();
+        frames = frames.or(Some(movie.len() as u64));
+    }
+    let mut recording = record.as_ref().map(|_| Movie::new(gb.cartridge_header().expect("ROM has no header"), start));
+
+    // The second machine only shares the render settings, its screen and audio are not output
+    let mut peer = link_local.map(|path| {
+        let mut second = Gameboy::with_model(model);
+        second.set_render_mode(render_mode);
+        let buffer: Vec<u8> = read(&path).expect("Unable to open file");
+        second.load_rom(&buffer).expect("Invalid ROM for linked Game Boy");
+        LocalPeer::new(&mut gb, second)
+    });
+
+    let mut rewind = rewind_frames.map(|_| Rewind::new(1, DEFAULT_REWIND_BYTES));
+    let mut samples: Vec<f32> = Vec::new();
+    let mut count = 0;
+    while frames.is_none_or(|n| count < n) {
+        // Input is applied once per frame, before it runs
+        let input = playback.as_ref().and_then(|movie| movie.input(count as usize)).unwrap_or_default();
+        gb.set_input(input);
+        if let Some(movie) = &mut recording {
+            movie.push(input);
+        }
+
+        match &mut peer {
+            Some(peer) => {
+                peer.run_frame(&mut gb);
+                peer.gameboy_mut().drain_samples(&mut Vec::new());
+            }
+            None => gb.run_frame(),
+        }
+        count += 1;
+        if let Some(rewind) = &mut rewind {
+            rewind.record(&gb);
+        }
+
+        // Without an audio device samples are only kept when exporting
+        if wav.is_some() {
+            gb.drain_samples(&mut samples);
+        } else {
+            gb.drain_samples(&mut Vec::new());
+        }
+
+        if count.is_multiple_of(SAVE_FLUSH_FRAMES) && use_save_file && gb.take_save_dirty() {
+            write(&save_path, gb.save_data()).expect("Unable to write save file");
+        }
+    }
+
+    if let (Some(rewind), Some(frames)) = (&mut rewind, rewind_frames) {
+        let rewound = match rewind.rewind(&mut gb, frames) {
+            Ok(rewound) => rewound,
+            Err(e) => {
+                eprintln!("Unable to rewind: {}", e);
+                0
+            }
+        };
+        let stats = rewind.stats();
+        println!(
+            "Rewound {} frames, {} snapshots covering {} frames in {} KiB ({} bytes per delta, last snapshot took {:?})",
+            rewound,
+            stats.snapshots,
+            stats.frames,
+            stats.bytes / 1024,
+            stats.average_delta_bytes,
+            stats.last_snapshot_time,
+        );
+    }
+
+    if use_save_file {
+        write(&save_path, gb.save_data()).expect("Unable to write save file");
+    }
+
+    if let Some(slot) = save_slot {
+        write(state_path(&args[1], slot), gb.save_state()).expect("Unable to write save state");
+    }
+
+    if let (Some(path), Some(movie)) = (record, recording) {
+        write(path, movie.to_bytes()).expect("Unable to write movie");
+    }
+
+    if let Some(path) = screenshot {
+        let (width, height) = gb.frame_size();
+        write_ppm(&path, &gb.rgb_frame(), width, height);
+    }
+
+    if let Some(path) = wav {
+        write_wav(&path, sample_rate, &samples);
+    }
+}
+
+// Save state slots live next to the ROM as <rom>.ss0, <rom>.ss1, ...
+fn state_path(rom: &str, slot: u32) -> PathBuf {
+    Path::new(rom).with_extension(format!("ss{}", slot))
+}
+
+// Opens a link cable from a listen:ADDR, connect:ADDR, unix-listen:PATH or unix-connect:PATH spec
+// Moves past an option and returns the value that follows it
+fn next_value<'a>(args: &'a [String], i: &mut usize) -> &'a str {
+    *i += 1;
+    match args.get(*i) {
+        Some(value) => value,
+        None => usage_error(&format!("{} needs a value", args[*i - 1])),
+    }
+}
+
+fn parse_value<T: FromStr>(value: &str, error: &str) -> T {
+    value.parse().unwrap_or_else(|_| usage_error(&format!("{}: {}", error, value)))
+}
+
+// Reports a bad command line and exits with a failure status
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    exit(1);
+}
+
+fn open_link(spec: &str) -> std::io::Result<NetworkLink> {
+    match spec.split_once(':') {
+        Some(("listen", addr)) => NetworkLink::listen_tcp(addr),
+        Some(("connect", addr)) => NetworkLink::connect_tcp(addr),
+        #[cfg(unix)]
+        Some(("unix-listen", path)) => NetworkLink::listen_unix(path),
+        #[cfg(unix)]
+        Some(("unix-connect", path)) => NetworkLink::connect_unix(path),
+        #[cfg(not(unix))]
+        Some(("unix-listen" | "unix-connect", _)) => {
+            Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Unix sockets are unsupported on this platform"))
+        }
+        _ => panic!("Unknown link cable: {}", spec),
+    }
+}
+
+// Writes interleaved stereo samples as a 16-bit PCM WAV file
+fn write_wav(path: &str, sample_rate: u32, samples: &[f32]) {
+    let data_len = (samples.len() * 2) as u32;
+    let mut data = Vec::with_capacity(44 + data_len as usize);
+    data.extend_from_slice(b"RIFF");
+    data.extend_from_slice(&(36 + data_len).to_le_bytes());
+    data.extend_from_slice(b"WAVEfmt ");
+    data.extend_from_slice(&16u32.to_le_bytes());
+    // PCM, 2 channels
+    data.extend_from_slice(&1u16.to_le_bytes());
+    data.extend_from_slice(&2u16.to_le_bytes());
+    data.extend_from_slice(&sample_rate.to_le_bytes());
+    data.extend_from_slice(&(sample_rate * 4).to_le_bytes());
+    data.extend_from_slice(&4u16.to_le_bytes());
+    data.extend_from_slice(&16u16.to_le_bytes());
+    data.extend_from_slice(b"data");
+    data.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    write(path, data).expect("Unable to write WAV file");
+}
+
+// Writes a binary PPM image of the screen
+fn write_ppm(path: &str, rgb: &[u8], width: usize, height: usize) {
+    let mut data = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    data.extend_from_slice(rgb);
+    write(path, data).expect("Unable to write screenshot");
+}
 
-    for _x in 1..12 {
-        gb.tick();
+// Buttons named in a --palette combination like "left+b"
+fn parse_palette_buttons(combination: &str) -> ButtonState {
+    let mut held = ButtonState::default();
+    for name in combination.split('+') {
+        let button = match name.to_ascii_lowercase().as_str() {
+            "up" => Button::Up,
+            "down" => Button::Down,
+            "left" => Button::Left,
+            "right" => Button::Right,
+            "a" => Button::A,
+            "b" => Button::B,
+            other => usage_error(&format!("Unknown palette button: {}", other)),
+        };
+        held.set(button, true);
     }
+    held
 }